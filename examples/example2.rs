@@ -1,7 +1,6 @@
-use log::trace;
 use std::fs::File;
 use std::io::Read;
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
 use std::ops::Add;
 use std::thread;
 use std::thread::sleep;
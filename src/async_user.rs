@@ -0,0 +1,197 @@
+use crate::client::{
+    delete_failure, delete_outcome, get_failure, get_outcome, put_failure, put_outcome, Client, Failure, Notifier,
+    Request,
+};
+use crate::common::UserMessage::{CompareAndPut, Delete, Get, Put};
+use crate::common::{File, ServerToUserMessage, UserMessage, Version};
+use crate::errors::{DeleteError, GetError, PutError};
+use crate::user::{User, UserBuilder};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, timeout_at};
+use tracing::{error, warn};
+
+/// Client of the ring for tokio applications, the async counterpart of [`User`](crate::user::User).
+///
+/// The operations are futures that wait for the answers without blocking the runtime, and the user can be shared
+/// between tasks, e.g. in an `Arc`. Dropping the future of an operation cancels it: its answer, if it still comes,
+/// is ignored. Deadlines, retries and bootstrap servers are configured with [`UserBuilder::build_async`].
+///
+/// # Example
+/// ```rust,no_run
+/// use crate::DHTchord::async_user::AsyncUser;
+/// use crate::DHTchord::common::File;
+///
+/// # async fn example() {
+/// let user = AsyncUser::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+/// let file = File{name: "greeting".to_string(), buffer: b"hello".to_vec()};
+///
+/// let key = user.put("127.0.0.1:7777", file).await.unwrap();
+/// match user.get("127.0.0.1:7777", key).await {
+///     Ok(file) => println!("File retrieved successfully: {:?}", file),
+///     Err(err) => println!("Failed to retrieve file: {:?}", err),
+/// }
+/// # }
+/// ```
+pub struct AsyncUser {
+    pub listening_addr: SocketAddr,
+    client: Client,
+}
+
+impl AsyncUser {
+    /// Listens on `ip_addr:port`, `0` picking a free port, and starts the thread receiving the answers. The
+    /// requests use the default deadline and retries, see [`UserBuilder`].
+    pub fn new(ip_addr: String, port: String) -> Result<Self, io::Error> {
+        Self::builder(ip_addr, port).build_async()
+    }
+
+    /// Starts the configuration of a user listening on `ip_addr:port`, finished by [`UserBuilder::build_async`].
+    pub fn builder(ip_addr: String, port: String) -> UserBuilder {
+        User::builder(ip_addr, port)
+    }
+
+    pub(crate) fn from_client(client: Client) -> Self {
+        Self {
+            listening_addr: client.listening_addr,
+            client,
+        }
+    }
+
+    /// Stores `file` under the SHA-256 of its name, see [`User::put`](crate::user::User::put).
+    pub async fn put(&self, server_address: &str, file: File) -> Result<String, PutError> {
+        let message = Put(file, None, self.listening_addr);
+        self.send_put(server_address, message).await.map(|(key, _)| key)
+    }
+
+    /// Like [`AsyncUser::put`], the file expiring `time_to_live` after the node receives it, see
+    /// [`User::put_with_ttl`](crate::user::User::put_with_ttl).
+    pub async fn put_with_ttl(
+        &self,
+        server_address: &str,
+        file: File,
+        time_to_live: Duration,
+    ) -> Result<String, PutError> {
+        let message = Put(file, Some(time_to_live), self.listening_addr);
+        self.send_put(server_address, message).await.map(|(key, _)| key)
+    }
+
+    /// Stores `file` only if the version currently stored under its key is `expected`, see
+    /// [`User::compare_and_put`](crate::user::User::compare_and_put).
+    pub async fn compare_and_put(
+        &self,
+        server_address: &str,
+        file: File,
+        expected: Option<Version>,
+    ) -> Result<(String, Version), PutError> {
+        let message = CompareAndPut(file, expected, self.listening_addr);
+        self.send_put(server_address, message).await
+    }
+
+    /// Stores `buffer` as an immutable, content-addressed blob, see [`User::put_blob`](crate::user::User::put_blob).
+    pub async fn put_blob(&self, server_address: &str, buffer: Vec<u8>) -> Result<String, PutError> {
+        self.put(server_address, File::blob(buffer)).await
+    }
+
+    async fn send_put(&self, server_address: &str, message: UserMessage) -> Result<(String, Version), PutError> {
+        self.request(server_address, message, put_failure, put_outcome).await
+    }
+
+    /// Retrieves the file stored under `key`, see [`User::get`](crate::user::User::get).
+    pub async fn get(&self, server_address: &str, key: String) -> Result<File, GetError> {
+        self.get_versioned(server_address, key).await.map(|(file, _)| file)
+    }
+
+    /// Like [`AsyncUser::get`], also returning the version of the file, to pass to
+    /// [`AsyncUser::compare_and_put`].
+    pub async fn get_versioned(&self, server_address: &str, key: String) -> Result<(File, Version), GetError> {
+        let message = Get(key, self.listening_addr);
+        self.request(server_address, message, get_failure, get_outcome).await
+    }
+
+    /// Deletes the file stored under `key` from the ring, see [`User::delete`](crate::user::User::delete).
+    pub async fn delete(&self, server_address: &str, key: String) -> Result<(), DeleteError> {
+        let message = Delete(key, self.listening_addr);
+        self.request(server_address, message, delete_failure, delete_outcome)
+            .await
+    }
+
+    /// Stops receiving answers and waits, off the executor, until the port of the user is released. Dropping the
+    /// user instead only stops the thread receiving the answers, without waiting for it so as not to block the
+    /// runtime: the port may then stay bound for a while.
+    pub async fn close(mut self) {
+        if let Some(listener_thread) = self.client.stop() {
+            let _ = task::spawn_blocking(move || listener_thread.join()).await;
+        }
+    }
+
+    /// Sends `message` to `server_address` under a new request id and passes the answers to `outcome` until it
+    /// returns the outcome, retrying on the next server like [`User`] when an attempt has no outcome at its deadline.
+    async fn request<T, E>(
+        &self,
+        server_address: &str,
+        message: UserMessage,
+        failed: fn(Failure) -> E,
+        mut outcome: impl FnMut(ServerToUserMessage) -> Option<Result<T, E>>,
+    ) -> Result<T, E> {
+        let (answers, mut receiver) = mpsc::unbounded_channel();
+        let mut request = self.client.start(message, Notifier::Async(answers));
+        let mut failure = Failure::Unreachable;
+        for (attempt, server) in self.client.attempts(server_address) {
+            time::sleep(self.client.backoff(attempt)).await;
+            let deadline = Instant::now() + self.client.policy.timeout;
+            failure = match send(&mut request, server, deadline).await {
+                Ok(()) => loop {
+                    match timeout_at(deadline.into(), receiver.recv()).await {
+                        Ok(Some(answer)) => {
+                            if let Some(outcome) = outcome(answer) {
+                                return outcome;
+                            }
+                        }
+                        Ok(None) => break Failure::Unreachable,
+                        Err(_) => break Failure::Timeout,
+                    }
+                },
+                Err(failure) => failure,
+            };
+            warn!(
+                "Attempt {} of request {} to {server}: {:?}",
+                attempt + 1,
+                request.id,
+                failure
+            );
+            request.abandon();
+        }
+        Err(failed(failure))
+    }
+}
+
+impl Drop for AsyncUser {
+    fn drop(&mut self) {
+        // Detached, joining it would block the runtime, see `AsyncUser::close`
+        drop(self.client.stop());
+    }
+}
+
+/// Sends `request` to `server` on an idle connection, or on a new one once established before `deadline`.
+async fn send(request: &mut Request<'_>, server: &str, deadline: Instant) -> Result<(), Failure> {
+    request.target(server);
+    if request.send_on_idle() {
+        return Ok(());
+    }
+    let established = request.connect().map_err(|e| {
+        error!("ERROR {:?} connecting to {server}", e);
+        Failure::Unreachable
+    })?;
+    match timeout_at(deadline.into(), established).await {
+        Ok(Ok(true)) => {}
+        Err(_) => return Err(Failure::Timeout),
+        Ok(_) => return Err(Failure::Unreachable),
+    }
+    request.send_on_new().map_err(|e| {
+        error!("ERROR {:?} sending request {}", e, request.id);
+        Failure::Unreachable
+    })
+}
@@ -0,0 +1,63 @@
+//! Splitting of large values into chunks, each stored under its own key, and the manifest listing them.
+//!
+//! A chunked value is stored as:
+//! - one content-addressed blob per chunk, see `File::blob`, so identical chunks are stored once,
+//! - a manifest under the name given by the user, listing the chunks in order.
+//!
+//! See `User::put_stream` and `User::get_stream`.
+
+use crate::common::ChordId;
+use serde::{Deserialize, Serialize};
+
+/// Size of the chunks written by `User::put_stream`, except for the last one.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of chunks transferred at the same time by `User::put_stream` and `User::get_stream`.
+pub const PARALLEL_TRANSFERS: usize = 4;
+
+/// Prefix of a serialized manifest, telling it apart from a regular value.
+const MANIFEST_MAGIC: &[u8] = b"DHTchord-manifest\0";
+
+/// A chunk of a value, in the order it appears in the value.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkRef {
+    /// Hex encoded key the chunk is stored under, the SHA-256 of its content.
+    pub key: String,
+    pub size: u64,
+}
+
+impl ChunkRef {
+    pub fn new(buffer: &[u8]) -> Self {
+        Self {
+            key: ChordId::digest(buffer).to_hex(),
+            size: buffer.len() as u64,
+        }
+    }
+
+    /// Checks that `buffer` is the content of this chunk.
+    pub fn matches(&self, buffer: &[u8]) -> bool {
+        buffer.len() as u64 == self.size && ChordId::digest(buffer).to_hex() == self.key
+    }
+}
+
+/// Object stored under the user key of a chunked value.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// Size of the whole value, in bytes.
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = MANIFEST_MAGIC.to_vec();
+        buffer.extend(bincode::serialize(self).unwrap());
+        buffer
+    }
+
+    /// Reads a manifest back, `None` if `buffer` is a regular value.
+    pub fn decode(buffer: &[u8]) -> Option<Self> {
+        let serialized = buffer.strip_prefix(MANIFEST_MAGIC)?;
+        bincode::deserialize(serialized).ok()
+    }
+}
@@ -0,0 +1,456 @@
+//! Connection to the ring shared by `User` and `AsyncUser`.
+//!
+//! A single thread receives the answers of the nodes and hands each of them to the request waiting for it, by
+//! request id. Connections to the nodes are pooled: a request takes an idle one for its duration, so that a large
+//! request being written never holds up the answers read on the same connection.
+//!
+//! Each attempt of a request waits for its answer until a deadline. A request without an answer is sent again under
+//! the same id, after a growing backoff, to the next of the servers: the one given to the operation, then the
+//! bootstrap servers. The node carrying out a put or a delete remembers its answer by user and request id, so that
+//! a retry of a request it already carried out gets the same answer.
+
+use crate::common::{File, Message, RequestId, ServerToUserMessage, UserMessage, Version};
+use crate::errors::{DeleteError, GetError, PutError};
+use message_io::network::{Endpoint, NetEvent, ResourceId, SendStatus, Transport};
+use message_io::node::{self, NodeHandler};
+use std::collections::HashMap;
+use std::io;
+use std::iter;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, trace};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_RETRIES: u32 = 2;
+
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Deadline, retries and servers of the requests of a client.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    /// How long an attempt waits for its connection and its answer.
+    pub(crate) timeout: Duration,
+    /// Number of attempts after the first one.
+    pub(crate) retries: u32,
+    /// Wait before the first retry, doubled for each following one.
+    pub(crate) backoff: Duration,
+    /// Servers tried in turn after the one given to the operation.
+    pub(crate) bootstrap_servers: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            bootstrap_servers: vec![],
+        }
+    }
+}
+
+/// Why an attempt of a request got no outcome.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Failure {
+    /// The connection to the server could not be established or the request not sent.
+    Unreachable,
+    /// No outcome before the deadline of the attempt.
+    Timeout,
+}
+
+/// First request id of a client: the current time in nanoseconds, so that a client restarted on the same address
+/// does not reuse the ids of the previous one, which the nodes still remember the answers of.
+fn first_request_id() -> RequestId {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as RequestId)
+}
+
+/// Where the listener thread hands the answers of a request: to a thread blocked in `User`, or to a task awaiting an
+/// `AsyncUser` operation.
+pub(crate) enum Notifier {
+    Blocking(mpsc::Sender<ServerToUserMessage>),
+    Async(tokio::sync::mpsc::UnboundedSender<ServerToUserMessage>),
+}
+
+impl Notifier {
+    fn notify(&self, answer: ServerToUserMessage) {
+        // The caller may have given up on the request in the meantime
+        let _ = match self {
+            Notifier::Blocking(sender) => sender.send(answer).map_err(drop),
+            Notifier::Async(sender) => sender.send(answer).map_err(drop),
+        };
+    }
+}
+
+/// Answers awaited by the requests in progress, by request id.
+type PendingRequests = Arc<Mutex<HashMap<RequestId, Notifier>>>;
+
+/// Connections being established, with the request waiting for each of them.
+type Connecting = Arc<Mutex<HashMap<ResourceId, oneshot::Sender<bool>>>>;
+
+/// Idle connections to the nodes, by address.
+type Connections = Arc<Mutex<HashMap<String, Vec<Endpoint>>>>;
+
+pub(crate) struct Client {
+    pub(crate) handler: NodeHandler<()>,
+    pub(crate) listening_addr: SocketAddr,
+    connections: Connections,
+    connecting: Connecting,
+    pending: PendingRequests,
+    next_request_id: AtomicU64,
+    pub(crate) policy: RetryPolicy,
+    /// Thread receiving the answers, stopped when the client is dropped.
+    listener_thread: Option<JoinHandle<()>>,
+}
+
+impl Client {
+    /// Listens on `ip_addr:port`, `0` picking a free port, and starts the thread receiving the answers.
+    pub(crate) fn new(ip_addr: String, port: String, policy: RetryPolicy) -> io::Result<Self> {
+        let (handler, listener) = node::split();
+        let (_, listening_addr) = handler.network().listen(Transport::Ws, ip_addr + ":" + &port)?;
+
+        let connections: Connections = Default::default();
+        let connecting: Connecting = Default::default();
+        let pending: PendingRequests = Default::default();
+        let listener_thread = {
+            let (connections, connecting, pending) = (connections.clone(), connecting.clone(), pending.clone());
+            thread::spawn(move || {
+                listener.for_each(move |event| match event.network() {
+                    NetEvent::Message(_, bytes) => dispatch(&pending, bytes),
+                    NetEvent::Connected(endpoint, established) => {
+                        if let Some(ready) = connecting.lock().unwrap().remove(&endpoint.resource_id()) {
+                            let _ = ready.send(established);
+                        }
+                        if !established {
+                            forget(&connections, endpoint);
+                        }
+                    }
+                    NetEvent::Disconnected(endpoint) => forget(&connections, endpoint),
+                    NetEvent::Accepted(..) => {}
+                })
+            })
+        };
+
+        Ok(Self {
+            handler,
+            listening_addr,
+            connections,
+            connecting,
+            pending,
+            next_request_id: AtomicU64::new(first_request_id()),
+            policy,
+            listener_thread: Some(listener_thread),
+        })
+    }
+
+    /// Registers a request of `message` under a new id, its answers going to `answers` until the returned request is
+    /// dropped. Nothing is sent yet.
+    pub(crate) fn start(&self, message: UserMessage, answers: Notifier) -> Request<'_> {
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(id, answers);
+        Request {
+            client: self,
+            id,
+            server_address: String::new(),
+            serialized: bincode::serialize(&Message::UserMessage(id, message)).unwrap(),
+            opening: None,
+            endpoint: None,
+        }
+    }
+
+    /// The attempts of a request first sent to `server_address`, numbered from 0, with the server each one goes to.
+    pub(crate) fn attempts<'a>(&'a self, server_address: &'a str) -> impl Iterator<Item = (u32, &'a str)> + 'a {
+        let bootstrap_servers = self
+            .policy
+            .bootstrap_servers
+            .iter()
+            .map(String::as_str)
+            .filter(move |server| *server != server_address);
+        let servers = iter::once(server_address).chain(bootstrap_servers);
+        (0..=self.policy.retries).zip(servers.cycle())
+    }
+
+    /// How long to wait before the given attempt: nothing before the first one, then the backoff doubled for each
+    /// retry.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        match attempt {
+            0 => Duration::ZERO,
+            retry => self.policy.backoff.saturating_mul(1 << (retry - 1).min(16)),
+        }
+    }
+
+    /// Stops the listener thread and hands it over, to be joined by the caller, or detached if it is dropped. The
+    /// port is released once the thread ends.
+    pub(crate) fn stop(&mut self) -> Option<JoinHandle<()>> {
+        self.handler.stop();
+        self.listener_thread.take()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(listener_thread) = self.stop() {
+            let _ = listener_thread.join();
+        }
+    }
+}
+
+/// A request in progress. Dropping it stops routing its answers, e.g. when the future of an `AsyncUser` operation is
+/// dropped, and gives its connection back to the pool.
+pub(crate) struct Request<'a> {
+    client: &'a Client,
+    pub(crate) id: RequestId,
+    server_address: String,
+    serialized: Vec<u8>,
+    /// Connection opened for the request and not established yet.
+    opening: Option<Endpoint>,
+    /// Connection the request was sent on.
+    endpoint: Option<Endpoint>,
+}
+
+impl Request<'_> {
+    /// Makes `server_address` the node the next attempt is sent to.
+    pub(crate) fn target(&mut self, server_address: &str) {
+        self.server_address = server_address.to_string();
+    }
+
+    /// Closes the connection of a failed attempt rather than giving it back to the pool, the node behind it being
+    /// down or stuck. Answers still coming on it are lost.
+    pub(crate) fn abandon(&mut self) {
+        if let Some(endpoint) = self.opening.take() {
+            self.client.connecting.lock().unwrap().remove(&endpoint.resource_id());
+            self.client.handler.network().remove(endpoint.resource_id());
+        }
+        if let Some(endpoint) = self.endpoint.take() {
+            self.client.handler.network().remove(endpoint.resource_id());
+        }
+    }
+
+    /// Sends the request on an idle connection to the node. Returns `false` if there is none, or if it was closed.
+    pub(crate) fn send_on_idle(&mut self) -> bool {
+        let network = self.client.handler.network();
+        loop {
+            let idle = self
+                .client
+                .connections
+                .lock()
+                .unwrap()
+                .get_mut(&self.server_address)
+                .and_then(Vec::pop);
+            let Some(endpoint) = idle else {
+                return false;
+            };
+            if network.send(endpoint, &self.serialized) == SendStatus::Sent {
+                self.endpoint = Some(endpoint);
+                return true;
+            }
+            trace!("Connection to {} closed", self.server_address);
+            network.remove(endpoint.resource_id());
+        }
+    }
+
+    /// Opens a new connection to the node. The returned receiver tells whether it was established.
+    pub(crate) fn connect(&mut self) -> io::Result<oneshot::Receiver<bool>> {
+        let (ready, established) = oneshot::channel();
+        // Registered before the listener thread can see the connection
+        let mut connecting = self.client.connecting.lock().unwrap();
+        let (endpoint, _) = self
+            .client
+            .handler
+            .network()
+            .connect(Transport::Ws, self.server_address.as_str())?;
+        connecting.insert(endpoint.resource_id(), ready);
+        self.opening = Some(endpoint);
+        Ok(established)
+    }
+
+    /// Sends the request on the connection opened by [`Request::connect`], once established.
+    pub(crate) fn send_on_new(&mut self) -> io::Result<()> {
+        let Some(endpoint) = self.opening.take() else {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "no connection opened"));
+        };
+        match self.client.handler.network().send(endpoint, &self.serialized) {
+            SendStatus::Sent => {
+                self.endpoint = Some(endpoint);
+                Ok(())
+            }
+            status => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("{}: {:?}", self.server_address, status),
+            )),
+        }
+    }
+}
+
+impl Drop for Request<'_> {
+    fn drop(&mut self) {
+        self.client.pending.lock().unwrap().remove(&self.id);
+        if let Some(endpoint) = self.opening.take() {
+            self.client.connecting.lock().unwrap().remove(&endpoint.resource_id());
+            self.client.handler.network().remove(endpoint.resource_id());
+        }
+        if let Some(endpoint) = self.endpoint.take() {
+            self.client
+                .connections
+                .lock()
+                .unwrap()
+                .entry(std::mem::take(&mut self.server_address))
+                .or_default()
+                .push(endpoint);
+        }
+    }
+}
+
+/// Hands an answer to the request waiting for it. Answers to requests nobody waits for any more are dropped.
+fn dispatch(pending: &PendingRequests, bytes: &[u8]) {
+    let (request_id, answer): (RequestId, ServerToUserMessage) = match bincode::deserialize(bytes) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            error!("ERROR {:?} decoding an answer", e);
+            return;
+        }
+    };
+    match pending.lock().unwrap().get(&request_id) {
+        Some(answers) => answers.notify(answer),
+        None => trace!("Dropping answer {:?} to request {request_id}", answer),
+    }
+}
+
+/// Removes a closed connection from the pool.
+fn forget(connections: &Connections, endpoint: Endpoint) {
+    trace!("Connection to {} closed", endpoint.addr());
+    for idle in connections.lock().unwrap().values_mut() {
+        idle.retain(|known| *known != endpoint);
+    }
+}
+
+/// Error of a put that got no outcome.
+pub(crate) fn put_failure(failure: Failure) -> PutError {
+    match failure {
+        Failure::Unreachable => PutError::ErrorStoringFile,
+        Failure::Timeout => PutError::Timeout,
+    }
+}
+
+/// Error of a get that got no outcome.
+pub(crate) fn get_failure(failure: Failure) -> GetError {
+    match failure {
+        Failure::Unreachable => GetError::ErrorRetrievingFile,
+        Failure::Timeout => GetError::Timeout,
+    }
+}
+
+/// Error of a delete that got no outcome.
+pub(crate) fn delete_failure(failure: Failure) -> DeleteError {
+    match failure {
+        Failure::Unreachable => DeleteError::ErrorDeletingFile,
+        Failure::Timeout => DeleteError::Timeout,
+    }
+}
+
+/// Outcome of a put given an answer of the nodes, `None` while the request is being forwarded.
+pub(crate) fn put_outcome(answer: ServerToUserMessage) -> Option<Result<(String, Version), PutError>> {
+    match answer {
+        ServerToUserMessage::SavedKey(key, version) => {
+            trace!("Ok response from server");
+            Some(Ok((key, version)))
+        }
+        ServerToUserMessage::VersionConflict(current) => {
+            trace!("Version conflict");
+            Some(Err(PutError::VersionConflict(current)))
+        }
+        ServerToUserMessage::ContentMismatch => {
+            trace!("Content does not match the key");
+            Some(Err(PutError::ContentMismatch))
+        }
+        ServerToUserMessage::QuotaExceeded => {
+            trace!("Quota exceeded");
+            Some(Err(PutError::QuotaExceeded))
+        }
+        ServerToUserMessage::ForwarderTo(_) => {
+            trace!("forwarder");
+            None
+        }
+        ServerToUserMessage::InternalServerError => {
+            trace!("Error returned from serve");
+            Some(Err(PutError::ErrorStoringFile))
+        }
+        other => {
+            error!("Unexpected answer from the server: {:?}", other);
+            Some(Err(PutError::UnexpectedAnswer))
+        }
+    }
+}
+
+/// Outcome of a get given an answer of the nodes, `None` while the request is being forwarded.
+pub(crate) fn get_outcome(answer: ServerToUserMessage) -> Option<Result<(File, Version), GetError>> {
+    match answer {
+        ServerToUserMessage::RequestedFile(file, _) if !file.verify() => {
+            error!("Content of {} does not match its key", file.name);
+            Some(Err(GetError::Corrupted))
+        }
+        ServerToUserMessage::RequestedFile(file, version) => {
+            trace!("File received");
+            Some(Ok((file, version)))
+        }
+        ServerToUserMessage::ForwarderTo(_) => {
+            trace!("Forwarded");
+            None
+        }
+        ServerToUserMessage::FileNotFound(_hex) => {
+            trace!("Not found");
+            Some(Err(GetError::NotFound))
+        }
+        ServerToUserMessage::HexConversionNotValid(_) => {
+            trace!("hex conversion error");
+            Some(Err(GetError::HexConversion))
+        }
+        ServerToUserMessage::CorruptedFile(_) => {
+            trace!("Corrupted file");
+            Some(Err(GetError::Corrupted))
+        }
+        ServerToUserMessage::InternalServerError => {
+            trace!("Internal error while saving file");
+            Some(Err(GetError::ErrorRetrievingFile))
+        }
+        other => {
+            error!("Unexpected answer from the server: {:?}", other);
+            Some(Err(GetError::UnexpectedAnswer))
+        }
+    }
+}
+
+/// Outcome of a delete given an answer of the nodes, `None` while the request is being forwarded.
+pub(crate) fn delete_outcome(answer: ServerToUserMessage) -> Option<Result<(), DeleteError>> {
+    match answer {
+        ServerToUserMessage::DeletedKey(_) => {
+            trace!("File deleted");
+            Some(Ok(()))
+        }
+        ServerToUserMessage::ForwarderTo(_) => {
+            trace!("Forwarded");
+            None
+        }
+        ServerToUserMessage::FileNotFound(_) => {
+            trace!("Not found");
+            Some(Err(DeleteError::NotFound))
+        }
+        ServerToUserMessage::HexConversionNotValid(_) => {
+            trace!("hex conversion error");
+            Some(Err(DeleteError::HexConversion))
+        }
+        ServerToUserMessage::InternalServerError => {
+            trace!("Internal error while deleting file");
+            Some(Err(DeleteError::ErrorDeletingFile))
+        }
+        other => {
+            error!("Unexpected answer from the server: {:?}", other);
+            Some(Err(DeleteError::UnexpectedAnswer))
+        }
+    }
+}
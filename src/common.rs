@@ -1,32 +1,183 @@
-use crate::node_state::NodeConfig;
+use crate::node_state::{NodeConfig, NodeStatus};
+use crate::storage::merkle::{MerkleTree, RangeEntry};
+use chrono::{DateTime, Utc};
 use digest::Digest;
-use message_io::network::{Endpoint, Transport};
+use message_io::network::Endpoint;
 use message_io::node::NodeHandler;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::net::SocketAddr;
+use std::time::Duration;
 
+/// Default data directory of the nodes, see `NodeBuilder::data_dir`.
 pub(crate) const SERVER_FOLDER: &str = "server/";
+pub(crate) const ID_BYTES: usize = 32;
+pub(crate) const ID_BITS: usize = ID_BYTES * 8;
+
+/// Identifier of a node or a key on the 2^256 Chord ring.
+///
+/// Ids are big-endian SHA-256 digests. All the arithmetic is done modulo 2^256 and the interval checks take
+/// wrap-around at the top of the ring into account.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChordId([u8; ID_BYTES]);
+
+impl ChordId {
+    pub fn from_bytes(bytes: [u8; ID_BYTES]) -> Self {
+        Self(bytes)
+    }
+
+    /// Hashes arbitrary data onto the ring.
+    pub fn digest(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// Id of the node listening on `addr`.
+    pub fn from_addr(addr: &SocketAddr) -> Self {
+        Self::digest(addr.to_string().as_bytes())
+    }
+
+    /// Parses a hex encoded key, as returned to the users after a put.
+    pub fn from_hex(key: &str) -> Option<Self> {
+        let bytes = hex::decode(key).ok()?;
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ID_BYTES] {
+        &self.0
+    }
+
+    /// Returns `self + 2^exponent mod 2^256`.
+    pub fn add_power_of_two(&self, exponent: usize) -> Self {
+        assert!(exponent < ID_BITS, "exponent {exponent} out of the identifier space");
+        let mut bytes = self.0;
+        let mut index = ID_BYTES - 1 - exponent / 8;
+        let mut carry = 1u16 << (exponent % 8);
+        loop {
+            let sum = bytes[index] as u16 + carry;
+            bytes[index] = sum as u8;
+            carry = sum >> 8;
+            if carry == 0 || index == 0 {
+                break;
+            }
+            index -= 1;
+        }
+        Self(bytes)
+    }
+
+    /// Clockwise distance from `self` to `other`, that is `other - self mod 2^256`.
+    pub fn distance(&self, other: &ChordId) -> ChordId {
+        let mut bytes = [0; ID_BYTES];
+        let mut borrow = 0i16;
+        for index in (0..ID_BYTES).rev() {
+            let mut difference = other.0[index] as i16 - self.0[index] as i16 - borrow;
+            borrow = 0;
+            if difference < 0 {
+                difference += 256;
+                borrow = 1;
+            }
+            bytes[index] = difference as u8;
+        }
+        Self(bytes)
+    }
+
+    /// Checks if `self` lies in the open interval `(start, end)` walking the ring clockwise.
+    ///
+    /// When `start == end` the interval covers the whole ring except `start`.
+    pub fn in_open_interval(&self, start: &ChordId, end: &ChordId) -> bool {
+        match start.cmp(end) {
+            Ordering::Less => start < self && self < end,
+            Ordering::Greater => self > start || self < end,
+            Ordering::Equal => self != start,
+        }
+    }
+
+    /// Checks if `self` lies in the half-open interval `(start, end]` walking the ring clockwise.
+    ///
+    /// When `start == end` the interval covers the whole ring.
+    pub fn in_half_open_interval(&self, start: &ChordId, end: &ChordId) -> bool {
+        self == end || self.in_open_interval(start, end)
+    }
+}
+
+impl Display for ChordId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl Debug for ChordId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ChordId({})", &self.to_hex()[..8])
+    }
+}
+
+/// Version of a stored object: a counter increased by every put of its key, and the id of the node that stored
+/// the put.
+///
+/// Versions are ordered by counter, the writer only breaks ties between puts stored by different nodes, e.g. on
+/// both sides of a failover.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub counter: u64,
+    pub writer: ChordId,
+}
+
+impl Version {
+    /// Version of the objects stored before versioning existed, lower than any version written since.
+    pub const UNVERSIONED: Version = Version {
+        counter: 0,
+        writer: ChordId([0; ID_BYTES]),
+    };
+
+    /// Version following `self`, written by `writer`.
+    pub fn next(&self, writer: ChordId) -> Version {
+        Version {
+            counter: self.counter + 1,
+            writer,
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.counter, &self.writer.to_hex()[..8])
+    }
+}
+
+/// Condition on the current version of a key for a put to be stored.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PutCondition {
+    Always,
+    /// The current version must be the given one, `None` meaning that the key must not be stored.
+    IfVersion(Option<Version>),
+}
 
 #[derive(Serialize, Deserialize)]
 pub(crate) enum Message {
     ChordMessage(ChordMessage),
-    UserMessage(UserMessage),
+    ///UserMessage(request_id, request), the id is echoed in the answer
+    UserMessage(RequestId, UserMessage),
 }
 
 #[derive(Serialize, Deserialize)]
 pub(crate) enum ChordMessage {
+    ///NotifySuccessor(possible_predecessor), the notify step of stabilization
     NotifySuccessor(SocketAddr),
 
-    NotifyPredecessor(SocketAddr),
-
-    NotifyPresence(SocketAddr),
-
     AddSuccessor(SocketAddr),
 
-    AddPredecessor(SocketAddr),
+    ///GetPredecessor(requesting_address)
+    GetPredecessor(SocketAddr),
+
+    ///Predecessor(sender_address, predecessor_of_the_sender)
+    Predecessor(SocketAddr, Option<SocketAddr>),
 
     Join(SocketAddr),
 
@@ -34,35 +185,121 @@ pub(crate) enum ChordMessage {
 
     ForwardJoin(SocketAddr),
 
-    ForwardedPut(SocketAddr, File),
+    ///ForwardedPut(user_address, request_id, file, condition, expires_at)
+    ForwardedPut(SocketAddr, RequestId, File, PutCondition, Option<DateTime<Utc>>),
+
+    ///ForwardedGet(user_address, request_id, key)
+    ForwardedGet(SocketAddr, RequestId, String),
+
+    ///ForwardedDelete(user_address, request_id, key)
+    ForwardedDelete(SocketAddr, RequestId, String),
+
+    ///MoveFile(file, version, expires_at)
+    MoveFile(File, Version, Option<DateTime<Utc>>),
+
+    ///MoveTombstones(deleted_keys_and_versions), handed over with the keys they cover
+    MoveTombstones(Vec<(ChordId, Version)>),
+
+    ///Replicate(file, version, expires_at), copy of a file stored by the node responsible for it
+    Replicate(File, Version, Option<DateTime<Utc>>),
+
+    ///ReplicateDelete(deleted_key, version_of_the_delete)
+    ReplicateDelete(ChordId, Version),
+
+    ///ReplicaGet(user_address, request_id, key), get answered by a replica while the node responsible for the key is
+    ///down
+    ReplicaGet(SocketAddr, RequestId, String),
+
+    ///MerkleSummary(owner_address, range_start, range_end, tree_of_the_range), sent to the replicas
+    MerkleSummary(SocketAddr, ChordId, ChordId, MerkleTree),
+
+    ///MerkleDiff(replica_address, range_start, range_end, differing_leaves, keys_of_the_replica_in_those_leaves)
+    MerkleDiff(SocketAddr, ChordId, ChordId, Vec<usize>, Vec<RangeEntry>),
+
+    ///RequestKeys(requesting_address, keys), answered with a MoveFile per key
+    RequestKeys(SocketAddr, Vec<ChordId>),
+
+    ///HandoffOffer(sender_address, keys_with_their_version), files a node hands over to its new predecessor
+    HandoffOffer(SocketAddr, Vec<(ChordId, Version)>),
+
+    ///HandoffAccept(receiver_address, wanted_keys)
+    HandoffAccept(SocketAddr, Vec<ChordId>),
+
+    ///HandoffTransfer(sender_address, files), a batch of the accepted files
+    HandoffTransfer(SocketAddr, Vec<HandedOverFile>),
+
+    ///HandoffAck(receiver_address, stored_keys), the sender deletes its copies once acknowledged
+    HandoffAck(SocketAddr, Vec<ChordId>),
+
+    ///HandoffNack(receiver_address, keys_that_failed_to_be_stored)
+    HandoffNack(SocketAddr, Vec<ChordId>),
 
-    ForwardedGet(SocketAddr, String),
+    HeartBeat(SocketAddr),
 
-    MoveFile(File),
+    ///Ping(pinging_address)
+    Ping(SocketAddr),
 
-    HeartBeat(SocketAddr, SocketAddr),
+    ///Pong(pinged_address)
+    Pong(SocketAddr),
 
-    Find(Vec<u8>, SocketAddr),
+    ///LeaveSuccessor(leaving_address, predecessor_of_the_leaving_node, its_tombstones), once its files are handed over
+    LeaveSuccessor(SocketAddr, Option<SocketAddr>, Vec<(ChordId, Version)>),
+
+    ///LeavePredecessor(leaving_address, successor_of_the_leaving_node)
+    LeavePredecessor(SocketAddr, SocketAddr),
+
+    ///LeaveAck(acknowledging_address)
+    LeaveAck(SocketAddr),
+
+    ///GetSuccessorList(requesting_address)
+    GetSuccessorList(SocketAddr),
+
+    ///SuccessorList(sender_address, successor_list_of_the_sender)
+    SuccessorList(SocketAddr, Vec<SocketAddr>),
+
+    Find(ChordId, SocketAddr),
+
+    ///FoundSuccessor(searched_id, successor_of_the_id)
+    FoundSuccessor(ChordId, SocketAddr),
 }
 
-#[derive(Serialize, Deserialize)]
+/// Id chosen by a user for each of its requests, echoed in the answers so that concurrent requests of the same user
+/// are told apart.
+pub(crate) type RequestId = u64;
+
+/// A file handed over to another node, with its version and its expiration.
+pub(crate) type HandedOverFile = (File, Version, Option<DateTime<Utc>>);
+
+#[derive(Serialize, Deserialize, Clone)]
 pub(crate) enum ServerToUserMessage {
-    RequestedFile(File),
-    SavedKey(String),
+    RequestedFile(File, Version),
+    SavedKey(String, Version),
+    ///VersionConflict(current_version), the condition of a put did not hold
+    VersionConflict(Option<Version>),
+    ContentMismatch,
+    QuotaExceeded,
+    DeletedKey(String),
     ForwarderTo(String),
     FileNotFound(String),
     HexConversionNotValid(String),
+    ///CorruptedFile(key), the stored content failed its checksum and was quarantined
+    CorruptedFile(String),
     InternalServerError,
 }
 
 impl Debug for ServerToUserMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::RequestedFile(_) => f.write_str("ServerToUserMessage(RequestedFile)"),
-            Self::SavedKey(_) => f.write_str("ServerToUserMessage(SavedKey)"),
+            Self::RequestedFile(..) => f.write_str("ServerToUserMessage(RequestedFile)"),
+            Self::SavedKey(..) => f.write_str("ServerToUserMessage(SavedKey)"),
+            Self::VersionConflict(_) => f.write_str("ServerToUserMessage(VersionConflict)"),
+            Self::ContentMismatch => f.write_str("ServerToUserMessage(ContentMismatch)"),
+            Self::QuotaExceeded => f.write_str("ServerToUserMessage(QuotaExceeded)"),
+            Self::DeletedKey(_) => f.write_str("ServerToUserMessage(DeletedKey)"),
             Self::ForwarderTo(_) => f.write_str("ServerToUserMessage(ForwarderTo)"),
             Self::FileNotFound(_) => f.write_str("ServerToUserMessage(FileNotFound)"),
             Self::HexConversionNotValid(_) => f.write_str("ServerToUserMessage(HexConversionNotValid)"),
+            Self::CorruptedFile(_) => f.write_str("ServerToUserMessage(CorruptedFile)"),
             Self::InternalServerError => f.write_str("ServerToUserMessage(InternalServerError)"),
         }
     }
@@ -70,17 +307,27 @@ impl Debug for ServerToUserMessage {
 
 pub(crate) enum ServerSignals {
     ForwardMessage(Endpoint, Message),
-    SendMessageToUser(Endpoint, ServerToUserMessage),
+    SendMessageToUser(Endpoint, RequestId, ServerToUserMessage),
     HeartBeat(),
+    CheckNeighbours(),
     Stabilization(),
+    Leave(),
+    LeaveTimeout(),
+    Scrub(),
+    ExpireKeys(),
+    Query(oneshot::Sender<NodeStatus>),
 }
 
 #[derive(Serialize, Deserialize)]
 pub(crate) enum UserMessage {
-    ///Put(file_to_save, self_address)
-    Put(File, SocketAddr),
+    ///Put(file_to_save, time_to_live, self_address), `None` for a file that never expires
+    Put(File, Option<Duration>, SocketAddr),
+    ///CompareAndPut(file_to_save, expected_version, self_address), `None` expecting the key not to be stored
+    CompareAndPut(File, Option<Version>, SocketAddr),
     ///Get(key, self_address)
     Get(String, SocketAddr),
+    ///Delete(key, self_address)
+    Delete(String, SocketAddr),
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct File {
@@ -88,52 +335,88 @@ pub struct File {
     pub buffer: Vec<u8>,
 }
 
-pub(crate) fn binary_search(config: &NodeConfig, digested_vector: &Vec<u8>) -> usize {
-    if config.finger_table.is_empty() {
-        return 0;
+/// Name prefix of the content-addressed files, followed by the hex encoded SHA-256 of their content.
+const BLOB_PREFIX: &str = "sha256:";
+
+impl File {
+    /// Content-addressed file holding `buffer`: its key is the SHA-256 of the content rather than of the name, so
+    /// identical contents share a key and the content can be checked against the key.
+    pub fn blob(buffer: Vec<u8>) -> Self {
+        Self {
+            name: format!("{BLOB_PREFIX}{}", ChordId::digest(&buffer).to_hex()),
+            buffer,
+        }
     }
-    let mut s = 0;
-    let mut e = config.finger_table.len();
-    while s < e {
-        let mid = (s + e) / 2;
-        let mid_id = Sha256::digest(config.finger_table[mid].to_string().as_bytes()).to_vec();
-        if mid_id > *digested_vector {
-            e = mid;
-        } else {
-            s = mid + 1;
+
+    /// Key of a content-addressed file, `None` for a file addressed by its name.
+    pub fn content_key(&self) -> Option<ChordId> {
+        ChordId::from_hex(self.name.strip_prefix(BLOB_PREFIX)?)
+    }
+
+    /// Key the file is stored under: the SHA-256 of its content for a content-addressed file, of its name otherwise.
+    pub fn key(&self) -> ChordId {
+        self.content_key()
+            .unwrap_or_else(|| ChordId::digest(self.name.as_bytes()))
+    }
+
+    /// Checks the content of a content-addressed file against its key. Files addressed by their name always pass.
+    pub fn verify(&self) -> bool {
+        self.content_key()
+            .is_none_or(|key| key == ChordId::digest(&self.buffer))
+    }
+}
+
+/// Returns the node the request for `id` has to be forwarded to, or `None` if this node is responsible for it.
+///
+/// A node is responsible for the ids in `(predecessor, self]`. Ids in `(self, successor]` belong to the successor,
+/// everything else is sent to the closest finger preceding `id`.
+pub(crate) fn next_hop(config: &NodeConfig, id: &ChordId) -> Option<SocketAddr> {
+    let successor = config.finger_table.successor()?;
+
+    if let Some(predecessor) = config.predecessor {
+        if id.in_half_open_interval(&ChordId::from_addr(&predecessor), &config.id) {
+            return None;
         }
     }
 
-    if e == config.finger_table.len() {
-        e -= 1;
+    if id.in_half_open_interval(&config.id, &ChordId::from_addr(&successor)) {
+        return Some(successor);
     }
 
-    e
+    Some(config.finger_table.closest_preceding_finger(id).unwrap_or(successor))
 }
 
-pub(crate) fn get_ws_endpoint(
+/// Endpoint used for the requests to `socket_addr`, connected with the transport of the node on first use.
+pub(crate) fn get_endpoint(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     socket_addr: SocketAddr,
 ) -> Endpoint {
-    match config.known_endpoints_ws.entry(socket_addr) {
+    match config.known_endpoints.entry(socket_addr) {
         Entry::Occupied(entry) => *entry.get(),
         Entry::Vacant(entry) => {
-            let (endpoint, _) = handler.network().connect(Transport::Ws, socket_addr).unwrap();
+            let (endpoint, _) = handler.network().connect(config.transport, socket_addr).unwrap();
             *entry.insert(endpoint)
         }
     }
 }
 
-pub(crate) fn get_udp_endpoint(
+/// Endpoint used for heartbeats and pings to `socket_addr`, connected with the liveness transport of the node.
+pub(crate) fn get_liveness_endpoint(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     socket_addr: SocketAddr,
 ) -> Endpoint {
-    match config.known_endpoints_udp.entry(socket_addr) {
+    if config.liveness_transport == config.transport {
+        return get_endpoint(handler, config, socket_addr);
+    }
+    match config.known_liveness_endpoints.entry(socket_addr) {
         Entry::Occupied(entry) => *entry.get(),
         Entry::Vacant(entry) => {
-            let (endpoint, _) = handler.network().connect(Transport::Udp, socket_addr).unwrap();
+            let (endpoint, _) = handler
+                .network()
+                .connect(config.liveness_transport, socket_addr)
+                .unwrap();
             *entry.insert(endpoint)
         }
     }
@@ -1,8 +1,23 @@
+use crate::common::Version;
+use std::io;
+
 #[non_exhaustive]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PutError {
     ForwardingRequest(String),
     ErrorStoringFile,
+    /// The expected version did not match the current one, given here, `None` if the key is not stored.
+    VersionConflict(Option<Version>),
+    /// Reading the value to stream failed.
+    ReadingInput(io::ErrorKind),
+    /// The content of a content-addressed file does not hash to its key.
+    ContentMismatch,
+    /// Storing the file would take the node responsible for it over its limit of bytes or of objects.
+    QuotaExceeded,
+    /// No server answered before the deadline, on any attempt.
+    Timeout,
+    /// The server answered with a message that is not an answer to this operation.
+    UnexpectedAnswer,
 }
 
 #[non_exhaustive]
@@ -12,4 +27,26 @@ pub enum GetError {
     ErrorRetrievingFile,
     NotFound,
     HexConversion,
+    /// Writing the streamed value failed.
+    WritingOutput(io::ErrorKind),
+    /// The stored content does not match its checksum, or its key for a content-addressed file. The node
+    /// quarantines its copy and fetches it again from another node, so a later get may succeed.
+    Corrupted,
+    /// No server answered before the deadline, on any attempt.
+    Timeout,
+    /// The server answered with a message that is not an answer to this operation.
+    UnexpectedAnswer,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeleteError {
+    ForwardingRequest(String),
+    ErrorDeletingFile,
+    NotFound,
+    HexConversion,
+    /// No server answered before the deadline, on any attempt.
+    Timeout,
+    /// The server answered with a message that is not an answer to this operation.
+    UnexpectedAnswer,
 }
@@ -1,4 +1,8 @@
+pub mod async_user;
+pub mod chunk;
+mod client;
 pub mod common;
 pub mod errors;
 pub mod node_state;
+pub mod storage;
 pub mod user;
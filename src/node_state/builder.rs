@@ -0,0 +1,316 @@
+use crate::common::{ChordId, SERVER_FOLDER};
+use crate::node_state::finger_table::FingerTable;
+use crate::node_state::handlers::server_message::handoff::{load_journal, HANDOFF_JOURNAL};
+use crate::node_state::handlers::server_message::replication::ReplicatedRanges;
+use crate::node_state::{NodeConfig, NodeState};
+use crate::storage::{FsStorage, StorageBackend};
+use chrono::{TimeDelta, Utc};
+use message_io::network::Transport;
+use message_io::node;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+const DEFAULT_GOSSIP_INTERVAL: Duration = Duration::from_secs(5);
+
+const DEFAULT_MAXIMUM_GOSSIP_INTERVAL: Duration = Duration::from_secs(320);
+
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+const DEFAULT_SUCCESSOR_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_PREDECESSOR_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_LEAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_SUCCESSOR_LIST_LENGTH: usize = 5;
+
+const DEFAULT_REPLICATION_FACTOR: usize = 3;
+
+const DEFAULT_TOMBSTONE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(10);
+
+const DEFAULT_EXPIRATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Builder for a [`NodeState`], covering addresses, storage, timers, successor list length, replication and
+/// transports.
+///
+/// Every option has a default, [`NodeState::new`] is a shortcut for `NodeBuilder::new(addr).build()`.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::net::SocketAddr;
+/// use std::time::Duration;
+/// use DHTchord::node_state::NodeBuilder;
+///
+/// let node = NodeBuilder::new("0.0.0.0:8080".parse().unwrap())
+///     .advertised_addr("192.168.1.2:8080".parse().unwrap())
+///     .data_dir("/var/lib/dht")
+///     .heartbeat_interval(Duration::from_secs(1))
+///     .successor_timeout(Duration::from_secs(3))
+///     .successor_list_length(8)
+///     .replication_factor(3)
+///     .max_bytes(10 * 1024 * 1024 * 1024)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct NodeBuilder {
+    bind_addr: SocketAddr,
+    advertised_addr: Option<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    storage: Option<Box<dyn StorageBackend>>,
+    gossip_interval: Duration,
+    maximum_gossip_interval: Duration,
+    heartbeat_interval: Duration,
+    successor_timeout: Duration,
+    predecessor_timeout: Duration,
+    leave_timeout: Duration,
+    successor_list_length: usize,
+    replication_factor: usize,
+    tombstone_lifetime: Duration,
+    scrub_interval: Duration,
+    expiration_interval: Duration,
+    max_bytes: Option<u64>,
+    max_objects: Option<u64>,
+    transport: Transport,
+    liveness_transport: Transport,
+}
+
+impl NodeBuilder {
+    /// Starts the configuration of a node listening on `bind_addr`.
+    pub fn new(bind_addr: SocketAddr) -> Self {
+        Self {
+            bind_addr,
+            advertised_addr: None,
+            data_dir: None,
+            storage: None,
+            gossip_interval: DEFAULT_GOSSIP_INTERVAL,
+            maximum_gossip_interval: DEFAULT_MAXIMUM_GOSSIP_INTERVAL,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            successor_timeout: DEFAULT_SUCCESSOR_TIMEOUT,
+            predecessor_timeout: DEFAULT_PREDECESSOR_TIMEOUT,
+            leave_timeout: DEFAULT_LEAVE_TIMEOUT,
+            successor_list_length: DEFAULT_SUCCESSOR_LIST_LENGTH,
+            replication_factor: DEFAULT_REPLICATION_FACTOR,
+            tombstone_lifetime: DEFAULT_TOMBSTONE_LIFETIME,
+            scrub_interval: DEFAULT_SCRUB_INTERVAL,
+            expiration_interval: DEFAULT_EXPIRATION_INTERVAL,
+            max_bytes: None,
+            max_objects: None,
+            transport: Transport::Ws,
+            liveness_transport: Transport::Udp,
+        }
+    }
+
+    /// Address the other nodes and the users reach this node at, and from which its id is computed. Defaults to
+    /// the bind address.
+    pub fn advertised_addr(mut self, advertised_addr: SocketAddr) -> Self {
+        self.advertised_addr = Some(advertised_addr);
+        self
+    }
+
+    /// Folder of the default [`FsStorage`], created if missing, which also records the unfinished handoff of keys to
+    /// a new predecessor. Defaults to `server/<ip>-<port>/`, relative to the working directory. With another storage
+    /// backend, only the handoff is recorded there.
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(data_dir.into());
+        self
+    }
+
+    /// Storage backend of the node, instead of an [`FsStorage`] in the data directory. The unfinished handoff of keys
+    /// is then only recorded if a data directory is also set: without one, handoffs are not crash-safe. A node
+    /// restarted during a handoff does not resume it, and only hands the keys over again once its predecessor
+    /// notifies it.
+    pub fn storage(mut self, storage: impl StorageBackend + 'static) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    /// Interval before the first stabilization round. It doubles after every round with a known successor, up to
+    /// the maximum gossip interval.
+    pub fn gossip_interval(mut self, gossip_interval: Duration) -> Self {
+        self.gossip_interval = gossip_interval;
+        self
+    }
+
+    pub fn maximum_gossip_interval(mut self, maximum_gossip_interval: Duration) -> Self {
+        self.maximum_gossip_interval = maximum_gossip_interval;
+        self
+    }
+
+    /// Interval between the heartbeats sent to the predecessor, and between the liveness checks of both neighbours.
+    pub fn heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// How long the successor can stay silent before the next entry of the successor list is promoted.
+    pub fn successor_timeout(mut self, successor_timeout: Duration) -> Self {
+        self.successor_timeout = successor_timeout;
+        self
+    }
+
+    /// How long the predecessor can leave a ping unanswered before the node forgets it.
+    pub fn predecessor_timeout(mut self, predecessor_timeout: Duration) -> Self {
+        self.predecessor_timeout = predecessor_timeout;
+        self
+    }
+
+    /// How long a leaving node waits without progress, from its successor storing its files or its neighbours
+    /// acknowledging, before stopping anyway.
+    pub fn leave_timeout(mut self, leave_timeout: Duration) -> Self {
+        self.leave_timeout = leave_timeout;
+        self
+    }
+
+    /// How many successors the node keeps track of to survive the failure of its immediate successor.
+    pub fn successor_list_length(mut self, successor_list_length: usize) -> Self {
+        self.successor_list_length = successor_list_length.max(1);
+        self
+    }
+
+    /// Number of nodes storing each key, the node responsible for it included. Replicas are taken from the
+    /// successor list, so at most `successor_list_length + 1` nodes store a key. `1` disables replication.
+    pub fn replication_factor(mut self, replication_factor: usize) -> Self {
+        self.replication_factor = replication_factor.max(1);
+        self
+    }
+
+    /// How long a deleted key is remembered, so that stale copies handed over by other nodes are not stored again.
+    pub fn tombstone_lifetime(mut self, tombstone_lifetime: Duration) -> Self {
+        self.tombstone_lifetime = tombstone_lifetime;
+        self
+    }
+
+    /// Time interval between two rounds of the scrubber, each checking a few stored files against their checksum.
+    pub fn scrub_interval(mut self, scrub_interval: Duration) -> Self {
+        self.scrub_interval = scrub_interval;
+        self
+    }
+
+    /// Time interval between two sweeps removing the files whose time-to-live ran out. Expired files are hidden
+    /// from gets as soon as they expire, the sweep only reclaims their space.
+    pub fn expiration_interval(mut self, expiration_interval: Duration) -> Self {
+        self.expiration_interval = expiration_interval;
+        self
+    }
+
+    /// Maximum total size of the files stored by the node, in bytes. Puts from users that would exceed it are
+    /// rejected with `PutError::QuotaExceeded`. Copies pushed or handed over by other nodes are still stored, so
+    /// that no data is lost, but count towards the limit. No limit by default.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Maximum number of files stored by the node, enforced like [`NodeBuilder::max_bytes`]. No limit by default.
+    pub fn max_objects(mut self, max_objects: u64) -> Self {
+        self.max_objects = Some(max_objects);
+        self
+    }
+
+    /// Transport used for the requests between nodes and from the users, `Ws` by default.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Transport used for heartbeats and pings, `Udp` by default.
+    pub fn liveness_transport(mut self, liveness_transport: Transport) -> Self {
+        self.liveness_transport = liveness_transport;
+        self
+    }
+
+    /// Opens the storage and binds the listeners.
+    ///
+    /// # Returns
+    /// - `Err(io::Error)`: if the storage cannot be opened, if the data directory cannot be created or its handoff
+    ///   journal read, or if the node fails to listen on the bind address. With the default [`FsStorage`], opening it checks that the data
+    ///   directory can be created and written, which other backends do not.
+    pub fn build(self) -> Result<NodeState, io::Error> {
+        let self_addr = self.advertised_addr.unwrap_or(self.bind_addr);
+        let id = ChordId::from_addr(&self_addr);
+
+        let (storage, data_dir) = match self.storage {
+            Some(storage) => {
+                if let Some(data_dir) = &self.data_dir {
+                    fs::create_dir_all(data_dir)?;
+                }
+                (storage, self.data_dir)
+            }
+            None => {
+                let data_dir = self.data_dir.unwrap_or_else(|| default_data_dir(&self_addr));
+                let storage: Box<dyn StorageBackend> = Box::new(FsStorage::open(&data_dir)?);
+                (storage, Some(data_dir))
+            }
+        };
+        let handoff_journal = data_dir.map(|data_dir| data_dir.join(HANDOFF_JOURNAL));
+        let tombstones = storage.tombstones()?.into_iter().collect();
+        let handoff = match &handoff_journal {
+            Some(path) => load_journal(path)?,
+            None => None,
+        };
+
+        let (handler, listener) = node::split();
+        handler.network().listen(self.transport, self.bind_addr)?;
+        if self.liveness_transport != self.transport {
+            handler.network().listen(self.liveness_transport, self.bind_addr)?;
+        }
+
+        let config = NodeConfig {
+            id,
+            self_addr,
+            storage,
+            tombstones,
+            tombstone_lifetime: to_time_delta(self.tombstone_lifetime),
+            answered: Default::default(),
+            scrub_interval: self.scrub_interval,
+            scrub_cursor: id,
+            expiration_interval: self.expiration_interval,
+            max_bytes: self.max_bytes,
+            max_objects: self.max_objects,
+            handoff,
+            handoff_journal,
+            finger_table: FingerTable::new(id),
+            successor_list: vec![],
+            successor_list_length: self.successor_list_length,
+            replication_factor: self.replication_factor,
+            replicas: vec![],
+            replicated_predecessor: None,
+            replicated_ranges: ReplicatedRanges::new(),
+            last_modified: Utc::now(),
+            successor_pings: Default::default(),
+            transport: self.transport,
+            liveness_transport: self.liveness_transport,
+            known_endpoints: Default::default(),
+            known_liveness_endpoints: Default::default(),
+            predecessor: None,
+            predecessor_ping: None,
+            predecessor_timeout: to_time_delta(self.predecessor_timeout),
+            successor_timeout: to_time_delta(self.successor_timeout),
+            heartbeat_interval: self.heartbeat_interval,
+            leave_timeout: self.leave_timeout,
+            leave: None,
+            gossip_interval: self.gossip_interval,
+            maximum_gossip_interval: self.maximum_gossip_interval,
+        };
+
+        Ok(NodeState {
+            handler,
+            listener,
+            config,
+        })
+    }
+}
+
+/// `server/<ip>-<port>/`, so that nodes sharing a port on different addresses do not collide.
+fn default_data_dir(self_addr: &SocketAddr) -> PathBuf {
+    let ip = self_addr.ip().to_string().replace(':', "_");
+    Path::new(SERVER_FOLDER).join(format!("{ip}-{}", self_addr.port()))
+}
+
+fn to_time_delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX)
+}
@@ -0,0 +1,103 @@
+use crate::common::{ChordId, ID_BITS};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+
+/// Entry `i` of the finger table of node `n`.
+struct Finger {
+    /// `n + 2^i mod 2^256`.
+    start: ChordId,
+    /// First node known to succeed `start`, `None` until it has been resolved.
+    node: Option<SocketAddr>,
+}
+
+/// Chord finger table: exactly [`ID_BITS`] entries, the `i`-th one pointing to the successor of `n + 2^i`.
+///
+/// The first entry is the successor of the node.
+pub(crate) struct FingerTable {
+    id: ChordId,
+    fingers: Vec<Finger>,
+}
+
+impl FingerTable {
+    pub(crate) fn new(id: ChordId) -> Self {
+        let fingers = (0..ID_BITS)
+            .map(|i| Finger {
+                start: id.add_power_of_two(i),
+                node: None,
+            })
+            .collect();
+        Self { id, fingers }
+    }
+
+    pub(crate) fn successor(&self) -> Option<SocketAddr> {
+        self.fingers[0].node
+    }
+
+    pub(crate) fn set_successor(&mut self, successor: SocketAddr) {
+        self.fingers[0].node = Some(successor);
+    }
+
+    /// True until the successor of the node is known, i.e. while the node is alone in the ring.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.successor().is_none()
+    }
+
+    pub(crate) fn node(&self, index: usize) -> Option<SocketAddr> {
+        self.fingers[index].node
+    }
+
+    pub(crate) fn start(&self, index: usize) -> ChordId {
+        self.fingers[index].start
+    }
+
+    /// Interval `[start_i, start_i+1)` covered by the `i`-th finger. The last one ends at the node itself.
+    pub(crate) fn interval(&self, index: usize) -> (ChordId, ChordId) {
+        let end = self.fingers.get(index + 1).map_or(self.id, |finger| finger.start);
+        (self.fingers[index].start, end)
+    }
+
+    pub(crate) fn update(&mut self, index: usize, node: SocketAddr) {
+        self.fingers[index].node = Some(node);
+    }
+
+    /// Sets the node of the finger whose start is `start`. Returns false if no finger starts there.
+    pub(crate) fn update_start(&mut self, start: &ChordId, node: SocketAddr) -> bool {
+        match self.fingers.iter_mut().find(|finger| finger.start == *start) {
+            Some(finger) => {
+                finger.node = Some(node);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets `node` in every finger pointing to it, e.g. after it failed.
+    pub(crate) fn remove_node(&mut self, node: &SocketAddr) {
+        for finger in self.fingers.iter_mut().filter(|finger| finger.node == Some(*node)) {
+            finger.node = None;
+        }
+    }
+
+    /// Distinct nodes referenced by the table, ordered by distance from this node.
+    pub(crate) fn nodes(&self) -> Vec<SocketAddr> {
+        let mut nodes: Vec<SocketAddr> = self.fingers.iter().filter_map(|finger| finger.node).collect();
+        nodes.sort_by_key(|node| self.id.distance(&ChordId::from_addr(node)));
+        nodes.dedup();
+        nodes
+    }
+
+    /// Scans the table from the farthest finger and returns the first node in `(n, id)`.
+    pub(crate) fn closest_preceding_finger(&self, id: &ChordId) -> Option<SocketAddr> {
+        self.fingers
+            .iter()
+            .rev()
+            .filter_map(|finger| finger.node)
+            .find(|node| ChordId::from_addr(node).in_open_interval(&self.id, id))
+    }
+}
+
+impl Debug for FingerTable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.nodes()).finish()
+    }
+}
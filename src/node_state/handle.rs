@@ -0,0 +1,99 @@
+use crate::common::{ChordId, ServerSignals};
+use crate::node_state::NodeConfig;
+use crate::storage::Usage;
+use message_io::node::NodeHandler;
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Snapshot of the state of a running node, returned by [`NodeHandle::query`].
+#[derive(Clone, Debug)]
+pub struct NodeStatus {
+    pub id: ChordId,
+    pub address: SocketAddr,
+    pub predecessor: Option<SocketAddr>,
+    pub successor: Option<SocketAddr>,
+    pub successor_list: Vec<SocketAddr>,
+    /// Distinct nodes of the finger table, ordered by distance from this node.
+    pub fingers: Vec<SocketAddr>,
+    /// Hex encoded keys stored by the node.
+    pub keys: Vec<String>,
+    /// Space taken by the stored files, see `NodeBuilder::max_bytes` and `NodeBuilder::max_objects` for the limits.
+    pub usage: Usage,
+}
+
+impl From<&NodeConfig> for NodeStatus {
+    fn from(config: &NodeConfig) -> Self {
+        let keys: Vec<String> = config
+            .storage
+            .keys()
+            .unwrap_or_default()
+            .iter()
+            .map(ChordId::to_hex)
+            .collect();
+        Self {
+            id: config.id,
+            address: config.self_addr,
+            predecessor: config.predecessor,
+            successor: config.finger_table.successor(),
+            successor_list: config.successor_list.clone(),
+            fingers: config.finger_table.nodes(),
+            keys,
+            usage: config.storage.usage().unwrap_or_default(),
+        }
+    }
+}
+
+/// Handle to a node running its event loop on a background thread, returned by `NodeState::spawn`.
+pub struct NodeHandle {
+    handler: NodeHandler<ServerSignals>,
+    thread: JoinHandle<()>,
+    address: SocketAddr,
+}
+
+impl NodeHandle {
+    pub(crate) fn new(handler: NodeHandler<ServerSignals>, thread: JoinHandle<()>, address: SocketAddr) -> Self {
+        Self {
+            handler,
+            thread,
+            address,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.handler.is_running() && !self.thread.is_finished()
+    }
+
+    /// Asks the running node for a snapshot of its state. Returns `None` if the node stopped or did not answer in
+    /// time.
+    pub fn query(&self) -> Option<NodeStatus> {
+        if !self.is_running() {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.handler.signals().send(ServerSignals::Query(sender));
+        receiver.recv_timeout(QUERY_TIMEOUT).ok()
+    }
+
+    /// Makes the node leave the ring gracefully, see `NodeState::leave_handle`. The event loop stops once the
+    /// neighbours acknowledged.
+    pub fn leave(&self) {
+        self.handler.signals().send(ServerSignals::Leave());
+    }
+
+    /// Stops the event loop right away, without telling the rest of the ring.
+    pub fn shutdown(&self) {
+        self.handler.stop();
+    }
+
+    /// Waits for the event loop to stop.
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
@@ -1,8 +1,11 @@
-use crate::common::{get_udp_endpoint, ChordMessage, Message, ServerSignals};
+use crate::common::{get_liveness_endpoint, ChordMessage, Message, ServerSignals};
 use crate::node_state::handlers::server_message::handle_server_message;
-use crate::node_state::handlers::server_message::stabilization::stabilization_protocol;
+use crate::node_state::handlers::server_message::leave::{handle_leave_timeout, start_leave};
+use crate::node_state::handlers::server_message::scrub::scrub;
+use crate::node_state::handlers::server_message::stabilization::{check_neighbours, stabilization_protocol};
+use crate::node_state::handlers::user_message::delete::expire_keys;
 use crate::node_state::handlers::user_message::handle_user_message;
-use crate::node_state::{NodeConfig, HEART_BEAT};
+use crate::node_state::{NodeConfig, NodeStatus};
 use message_io::network::{NetEvent, SendStatus};
 use message_io::node::NodeHandler;
 use tracing::trace;
@@ -19,41 +22,61 @@ pub fn handle_server_signal(handler: &NodeHandler<ServerSignals>, config: &mut N
                 handler.signals().send(ServerSignals::ForwardMessage(endpoint, message));
             }
         }
-        ServerSignals::SendMessageToUser(endpoint, message) => {
-            trace!("Forwarding message to user");
+        ServerSignals::SendMessageToUser(endpoint, request_id, message) => {
+            trace!("Answering request {request_id} of {}: {:?}", endpoint.addr(), message);
 
-            let output_data = bincode::serialize(&message).unwrap();
+            let output_data = bincode::serialize(&(request_id, &message)).unwrap();
 
             if handler.network().send(endpoint, &output_data) == SendStatus::ResourceNotAvailable {
                 trace!("Waiting for response {}", endpoint);
                 handler
                     .signals()
-                    .send(ServerSignals::SendMessageToUser(endpoint, message));
+                    .send(ServerSignals::SendMessageToUser(endpoint, request_id, message));
             }
         }
+        ServerSignals::CheckNeighbours() => {
+            handler
+                .signals()
+                .send_with_timer(ServerSignals::CheckNeighbours(), config.heartbeat_interval);
+            check_neighbours(handler, config);
+        }
         ServerSignals::Stabilization() => {
             trace!("Stabilization");
             stabilization_protocol(handler, config);
         }
+        ServerSignals::Leave() => {
+            trace!("Leaving the ring");
+            start_leave(handler, config);
+        }
+        ServerSignals::LeaveTimeout() => {
+            handle_leave_timeout(handler, config);
+        }
+        ServerSignals::Scrub() => {
+            trace!("Scrubbing");
+            scrub(handler, config);
+        }
+        ServerSignals::ExpireKeys() => {
+            handler
+                .signals()
+                .send_with_timer(ServerSignals::ExpireKeys(), config.expiration_interval);
+            expire_keys(config);
+        }
+        ServerSignals::Query(sender) => {
+            let _ = sender.send(NodeStatus::from(&*config));
+        }
         ServerSignals::HeartBeat() => {
             handler
                 .signals()
-                .send_with_timer(ServerSignals::HeartBeat(), HEART_BEAT);
+                .send_with_timer(ServerSignals::HeartBeat(), config.heartbeat_interval);
             if config.predecessor.is_none() {
                 return;
             }
 
-            let endpoint = get_udp_endpoint(handler, config, config.predecessor.unwrap());
-
-            let successor_address = if config.finger_table.is_empty() {
-                config.self_addr
-            } else {
-                config.finger_table[0]
-            };
+            let endpoint = get_liveness_endpoint(handler, config, config.predecessor.unwrap());
 
             handler.signals().send(ServerSignals::ForwardMessage(
                 endpoint,
-                Message::ChordMessage(ChordMessage::HeartBeat(config.self_addr, successor_address)),
+                Message::ChordMessage(ChordMessage::HeartBeat(config.self_addr)),
             ));
         }
     }
@@ -64,9 +87,9 @@ pub fn handle_net_event(handler: &NodeHandler<ServerSignals>, config: &mut NodeC
             let message = bincode::deserialize(serialized).unwrap();
 
             match message {
-                Message::UserMessage(user_message) => {
-                    trace!("Received user message");
-                    handle_user_message(handler, config, endpoint, user_message);
+                Message::UserMessage(request_id, user_message) => {
+                    trace!("Received user request {request_id}");
+                    handle_user_message(handler, config, endpoint, request_id, user_message);
                 }
                 Message::ChordMessage(server_message) => {
                     handle_server_message(handler, config, endpoint, server_message);
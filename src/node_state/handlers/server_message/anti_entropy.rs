@@ -0,0 +1,203 @@
+use crate::common::{get_endpoint, ChordId, ChordMessage, Message, ServerSignals, Version};
+use crate::node_state::handlers::server_message::replication::replica_set;
+use crate::node_state::handlers::server_message::scrub::get_or_quarantine;
+use crate::node_state::NodeConfig;
+use crate::storage::merkle::{leaf_of, range_entries, MerkleTree, ObjectDigest, RangeEntry};
+use message_io::node::NodeHandler;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tracing::{error, trace};
+
+/// Number of copies a repair pushes to a replica at once. The replica compares its copies again before the next
+/// batch, see [`handle_merkle_diff`].
+pub(crate) const REPAIR_BATCH: usize = 16;
+
+/// Sends the Merkle tree of the keys this node is responsible for, `(predecessor, self]`, to its replicas. Each
+/// replica answers with the keys of the leaves that differ, see [`handle_merkle_summary`].
+pub(crate) fn anti_entropy(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let replicas = replica_set(config);
+    send_summary(handler, config, &replicas);
+}
+
+/// Sends the Merkle tree of `(predecessor, self]` to `replicas`, nothing while the predecessor is unknown.
+pub(crate) fn send_summary(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig, replicas: &[SocketAddr]) {
+    let Some(predecessor) = config.predecessor else {
+        return;
+    };
+    if replicas.is_empty() {
+        return;
+    }
+
+    let start = ChordId::from_addr(&predecessor);
+    let tree = match range_entries(config.storage.as_ref(), &start, &config.id) {
+        Ok(entries) => MerkleTree::new(&entries),
+        Err(e) => {
+            error!("ERROR {:?} summarizing the keys for anti-entropy", e);
+            return;
+        }
+    };
+    for &replica in replicas {
+        let endpoint = get_endpoint(handler, config, replica);
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::MerkleSummary(
+                config.self_addr,
+                start,
+                config.id,
+                tree.clone(),
+            )),
+        ));
+    }
+}
+
+/// Compares the Merkle tree of `(start, end]` sent by the node responsible for the range with the local copy, and
+/// sends back the leaves that differ with the keys this node stores in them. The range is recorded as replicated
+/// by this node, see [`collect_replicas`](super::replication::collect_replicas).
+pub(crate) fn handle_merkle_summary(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    owner: SocketAddr,
+    start: ChordId,
+    end: ChordId,
+    tree: MerkleTree,
+) {
+    config.replicated_ranges.record(owner, start, end);
+    let entries = match range_entries(config.storage.as_ref(), &start, &end) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("ERROR {:?} summarizing the replicated keys", e);
+            return;
+        }
+    };
+    let local = MerkleTree::new(&entries);
+    if local.root() == tree.root() {
+        trace!("Replica of {owner} in sync");
+        return;
+    }
+
+    let leaves = local.differing_leaves(&tree);
+    let differing = entries
+        .into_iter()
+        .filter(|(key, _, _)| leaves.contains(&leaf_of(key)))
+        .collect();
+    trace!("Replica of {owner} differs in {} leaves", leaves.len());
+    let endpoint = get_endpoint(handler, config, owner);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::MerkleDiff(
+            config.self_addr,
+            start,
+            end,
+            leaves,
+            differing,
+        )),
+    ));
+}
+
+/// Repairs a replica from the keys it stores in the leaves that differ, by version: keys missing on the replica or
+/// older there are pushed to it, keys deleted here by a more recent delete are deleted there, and keys only the
+/// replica has, or has in a more recent version, are requested from it.
+///
+/// At most [`REPAIR_BATCH`] copies are pushed, followed by a new summary when more differ: the answer of the
+/// replica to it acknowledges the batch and lists the keys still to repair.
+pub(crate) fn handle_merkle_diff(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    replica: SocketAddr,
+    start: ChordId,
+    end: ChordId,
+    leaves: Vec<usize>,
+    replica_entries: Vec<RangeEntry>,
+) {
+    if end != config.id || config.predecessor.map(|predecessor| ChordId::from_addr(&predecessor)) != Some(start) {
+        trace!("Ignoring the anti-entropy answer of {replica} for a stale range");
+        return;
+    }
+    let entries = match range_entries(config.storage.as_ref(), &start, &end) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("ERROR {:?} listing the keys to repair", e);
+            return;
+        }
+    };
+
+    let mut replica_entries: HashMap<ChordId, (Version, ObjectDigest)> = replica_entries
+        .into_iter()
+        .map(|(key, version, digest)| (key, (version, digest)))
+        .collect();
+    let endpoint = get_endpoint(handler, config, replica);
+    let mut missing = vec![];
+    let mut pushed = 0;
+    let mut unfinished = false;
+    for (key, version, digest) in entries.into_iter().filter(|(key, _, _)| leaves.contains(&leaf_of(key))) {
+        match replica_entries.remove(&key) {
+            Some((_, replica_digest)) if replica_digest == digest => continue,
+            Some((replica_version, _)) if replica_version > version => {
+                missing.push(key);
+                continue;
+            }
+            _ => {}
+        }
+        if pushed == REPAIR_BATCH {
+            unfinished = true;
+            continue;
+        }
+        match get_or_quarantine(handler, config, &key) {
+            Ok(Some((file, metadata))) => {
+                pushed += 1;
+                handler.signals().send(ServerSignals::ForwardMessage(
+                    endpoint,
+                    Message::ChordMessage(ChordMessage::Replicate(file, metadata.version, metadata.expires_at)),
+                ))
+            }
+            Ok(None) => {}
+            Err(e) => error!("ERROR {:?} reading {key} to repair {replica}", e),
+        }
+    }
+
+    for (key, (version, _)) in replica_entries {
+        if let Some(tombstone) = config
+            .tombstones
+            .get(&key)
+            .filter(|tombstone| tombstone.version >= version)
+        {
+            handler.signals().send(ServerSignals::ForwardMessage(
+                endpoint,
+                Message::ChordMessage(ChordMessage::ReplicateDelete(key, tombstone.version)),
+            ));
+        } else {
+            missing.push(key);
+        }
+    }
+    if !missing.is_empty() {
+        trace!("Requesting {} keys missing or older here from {replica}", missing.len());
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::RequestKeys(config.self_addr, missing)),
+        ));
+    }
+    if unfinished {
+        trace!("Pushed a batch of {pushed} copies to {replica}, comparing again");
+        send_summary(handler, config, &[replica]);
+    }
+}
+
+/// Sends back the requested keys, stored by the requesting node like handed over files.
+pub(crate) fn handle_request_keys(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    requesting: SocketAddr,
+    keys: Vec<ChordId>,
+) {
+    let endpoint = get_endpoint(handler, config, requesting);
+    for key in keys {
+        match get_or_quarantine(handler, config, &key) {
+            Ok(Some((file, metadata))) => handler.signals().send(ServerSignals::ForwardMessage(
+                endpoint,
+                Message::ChordMessage(ChordMessage::MoveFile(file, metadata.version, metadata.expires_at)),
+            )),
+            Ok(None) => {}
+            Err(e) => error!("ERROR {:?} reading requested key {key}", e),
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use crate::common::{get_endpoint, ChordId, ChordMessage, HandedOverFile, Message, ServerSignals, Version};
+use crate::node_state::handlers::server_message::leave::abort_leave;
+use crate::node_state::handlers::server_message::scrub::get_or_quarantine;
+use crate::node_state::handlers::user_message::put::save_handed_over;
+use crate::node_state::NodeConfig;
+use crate::storage::sync_dir;
+use chrono::{DateTime, Utc};
+use message_io::node::NodeHandler;
+use std::collections::{BTreeSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use tracing::{error, trace, warn};
+
+/// Number of files sent by each transfer of a handoff, the next batch waits for the acknowledgement of this one.
+pub(crate) const HANDOFF_BATCH: usize = 16;
+
+/// Name of the file, in the data directory, recording the keys of an unfinished handoff.
+pub(crate) const HANDOFF_JOURNAL: &str = "handoff";
+
+/// Files being handed over to a new predecessor, or to the successor of a leaving node: offered, sent in batches of
+/// the accepted keys and deleted locally once acknowledged.
+pub(crate) struct Handoff {
+    /// Node the files are handed over to.
+    pub(crate) target: SocketAddr,
+    /// Keys not acknowledged by `target` yet.
+    pub(crate) pending: BTreeSet<ChordId>,
+    /// Keys accepted by `target` and not sent yet.
+    queued: VecDeque<ChordId>,
+    /// When `target` last answered, the offer is sent again once it stays silent for longer than the successor
+    /// timeout.
+    pub(crate) last_progress: DateTime<Utc>,
+}
+
+impl Handoff {
+    fn new(target: SocketAddr, pending: BTreeSet<ChordId>) -> Self {
+        Handoff {
+            target,
+            pending,
+            queued: VecDeque::new(),
+            last_progress: Utc::now(),
+        }
+    }
+}
+
+/// Loads the handoff interrupted by a restart of the node, if any. It is offered again on the first stabilization
+/// round.
+pub(crate) fn load_journal(path: &Path) -> io::Result<Option<Handoff>> {
+    let buffer = match fs::read(path) {
+        Ok(buffer) => buffer,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (target, pending): (SocketAddr, BTreeSet<ChordId>) =
+        bincode::deserialize(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut handoff = Handoff::new(target, pending);
+    handoff.last_progress = DateTime::UNIX_EPOCH;
+    Ok(Some(handoff))
+}
+
+/// Writes the keys still to hand over to the journal, or removes it once the handoff is over.
+fn save_journal(config: &NodeConfig) {
+    let Some(path) = &config.handoff_journal else {
+        return;
+    };
+    let saved = match &config.handoff {
+        Some(handoff) => write_journal(path, handoff),
+        None => fs::remove_file(path).or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        }),
+    };
+    let saved = saved.and_then(|_| match path.parent() {
+        Some(dir) => sync_dir(dir),
+        None => Ok(()),
+    });
+    if let Err(e) = saved {
+        error!("ERROR {:?} saving the handoff journal {}", e, path.display());
+    }
+}
+
+/// Replaces the journal at `path` through a synced temporary file, so that a crash leaves either journal whole.
+fn write_journal(path: &Path, handoff: &Handoff) -> io::Result<()> {
+    let buffer = bincode::serialize(&(handoff.target, &handoff.pending))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let temporary = path.with_extension("tmp");
+    let mut journal = fs::File::create(&temporary)?;
+    journal.write_all(&buffer)?;
+    journal.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// Starts handing over `keys` to `target`, replacing the handoff in progress: its keys are not in the range of this
+/// node either and are part of `keys`.
+pub(crate) fn start_handoff(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    target: SocketAddr,
+    keys: Vec<ChordId>,
+) {
+    config.handoff = (!keys.is_empty()).then(|| Handoff::new(target, keys.into_iter().collect()));
+    save_journal(config);
+    offer(handler, config);
+}
+
+/// Offers the pending keys of the handoff with their version, so that the target only asks for the files it lacks.
+/// Keys no longer stored are dropped from the handoff.
+fn offer(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let Some(handoff) = &config.handoff else {
+        return;
+    };
+    let (target, keys) = (handoff.target, handoff.pending.clone());
+
+    let mut offered = Vec::with_capacity(keys.len());
+    let mut gone = Vec::new();
+    let now = Utc::now();
+    for key in keys {
+        match config.storage.metadata(&key) {
+            Ok(Some(metadata)) if !metadata.is_expired(now) => offered.push((key, metadata.version)),
+            Ok(_) => gone.push(key),
+            Err(e) => error!("ERROR {:?} reading {key} to offer it", e),
+        }
+    }
+    finish(config, gone);
+    if offered.is_empty() {
+        return;
+    }
+
+    trace!("Offering {} files to {target}", offered.len());
+    let endpoint = get_endpoint(handler, config, target);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::HandoffOffer(config.self_addr, offered)),
+    ));
+}
+
+/// Answers an offer with the keys this node wants: the ones neither deleted here nor stored in a version at least
+/// as recent.
+pub(crate) fn handle_handoff_offer(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    sender: SocketAddr,
+    offered: Vec<(ChordId, Version)>,
+) {
+    let wanted: Vec<ChordId> = offered
+        .into_iter()
+        .filter(|(key, version)| {
+            config
+                .tombstones
+                .get(key)
+                .is_none_or(|tombstone| tombstone.version < *version)
+        })
+        .filter(|(key, version)| match config.storage.metadata(key) {
+            Ok(stored) => stored.is_none_or(|metadata| metadata.version < *version),
+            Err(e) => {
+                error!("ERROR {:?} reading offered key {key}", e);
+                true
+            }
+        })
+        .map(|(key, _)| key)
+        .collect();
+
+    trace!("Accepting {} files from {sender}", wanted.len());
+    let endpoint = get_endpoint(handler, config, sender);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::HandoffAccept(config.self_addr, wanted)),
+    ));
+}
+
+/// Queues the keys accepted by the target and sends the first batch. The keys not accepted are already stored by
+/// the target and are done.
+pub(crate) fn handle_handoff_accept(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    target: SocketAddr,
+    wanted: Vec<ChordId>,
+) {
+    let Some(handoff) = config.handoff.as_mut().filter(|handoff| handoff.target == target) else {
+        return;
+    };
+    handoff.last_progress = Utc::now();
+    let wanted: BTreeSet<ChordId> = wanted.into_iter().filter(|key| handoff.pending.contains(key)).collect();
+    let declined: Vec<ChordId> = handoff.pending.difference(&wanted).copied().collect();
+    handoff.queued = wanted.into_iter().collect();
+
+    finish(config, declined);
+    send_batch(handler, config);
+}
+
+/// Stores the files of a transfer and acknowledges the ones stored, or dropped because this node holds a more
+/// recent version. The ones that failed to be stored are acknowledged negatively.
+pub(crate) fn handle_handoff_transfer(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    sender: SocketAddr,
+    files: Vec<HandedOverFile>,
+) {
+    let mut stored = Vec::with_capacity(files.len());
+    let mut failed = Vec::new();
+    for (file, version, expires_at) in files {
+        let key = file.key();
+        match save_handed_over(file, version, expires_at, config) {
+            Ok(()) => stored.push(key),
+            Err(e) => {
+                error!("ERROR {:?} storing handed over file {key}", e);
+                failed.push(key);
+            }
+        }
+    }
+
+    let endpoint = get_endpoint(handler, config, sender);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::HandoffAck(config.self_addr, stored)),
+    ));
+    if !failed.is_empty() {
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::HandoffNack(config.self_addr, failed)),
+        ));
+    }
+}
+
+/// Completes the acknowledged keys and sends the next batch.
+pub(crate) fn handle_handoff_ack(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    target: SocketAddr,
+    keys: Vec<ChordId>,
+) {
+    let Some(handoff) = config.handoff.as_mut().filter(|handoff| handoff.target == target) else {
+        return;
+    };
+    handoff.last_progress = Utc::now();
+    trace!("{target} acknowledged {} handed over files", keys.len());
+    finish(config, keys);
+    send_batch(handler, config);
+}
+
+/// The target failed to store `keys`: they stay pending and are offered again when the handoff resumes. A leaving
+/// node gives up the handoff and its leave instead, it still holds every file.
+pub(crate) fn handle_handoff_nack(config: &mut NodeConfig, target: SocketAddr, keys: Vec<ChordId>) {
+    let Some(handoff) = config.handoff.as_mut().filter(|handoff| handoff.target == target) else {
+        return;
+    };
+    handoff.last_progress = Utc::now();
+    warn!("{target} failed to store {} handed over files", keys.len());
+    if config.leave.is_some() {
+        config.handoff = None;
+        save_journal(config);
+        abort_leave(config);
+    }
+}
+
+/// Sends the next queued files to the target. Files no longer stored are done, the ones that cannot be read stay
+/// pending.
+fn send_batch(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let mut files = Vec::with_capacity(HANDOFF_BATCH);
+    let mut gone = Vec::new();
+    let now = Utc::now();
+    while files.len() < HANDOFF_BATCH {
+        let Some(key) = config.handoff.as_mut().and_then(|handoff| handoff.queued.pop_front()) else {
+            break;
+        };
+        match get_or_quarantine(handler, config, &key) {
+            Ok(Some((file, metadata))) if !metadata.is_expired(now) => {
+                files.push((file, metadata.version, metadata.expires_at))
+            }
+            Ok(_) => gone.push(key),
+            Err(e) => error!("ERROR {:?} reading {key} to hand it over", e),
+        }
+    }
+    finish(config, gone);
+
+    let Some(handoff) = &config.handoff else {
+        return;
+    };
+    if files.is_empty() {
+        return;
+    }
+    let target = handoff.target;
+    let endpoint = get_endpoint(handler, config, target);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::HandoffTransfer(config.self_addr, files)),
+    ));
+}
+
+/// Removes `keys` from the handoff, deleting the files this node is no longer responsible for unless it keeps them
+/// as a replica of the target, and records the progress.
+fn finish(config: &mut NodeConfig, keys: Vec<ChordId>) {
+    let Some(handoff) = config.handoff.as_mut() else {
+        return;
+    };
+    if keys.is_empty() {
+        return;
+    }
+    let target_id = ChordId::from_addr(&handoff.target);
+    for key in &keys {
+        handoff.pending.remove(key);
+    }
+    if handoff.pending.is_empty() {
+        trace!("Handoff to {} done", handoff.target);
+        config.handoff = None;
+    }
+
+    if config.replication_factor == 1 {
+        for key in keys
+            .iter()
+            .filter(|key| key.in_half_open_interval(&config.id, &target_id))
+        {
+            if let Err(e) = config.storage.delete(key) {
+                error!("ERROR {:?} removing handed over file {key}", e);
+            }
+        }
+    }
+    save_journal(config);
+}
+
+/// Offers the pending keys again when the target stayed silent for longer than the successor timeout, e.g. after a
+/// restart or a lost message. A handoff to a node that is no longer the predecessor goes to the current one, for
+/// the keys still outside the range of this node, except while leaving.
+pub(crate) fn resume_handoff(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let now = Utc::now();
+    let predecessor = config.predecessor;
+    let self_id = config.id;
+    let Some(handoff) = config.handoff.as_mut() else {
+        return;
+    };
+    if now.signed_duration_since(handoff.last_progress) <= config.successor_timeout {
+        return;
+    }
+
+    let retarget = predecessor.filter(|predecessor| *predecessor != handoff.target && config.leave.is_none());
+    if let Some(predecessor) = retarget {
+        warn!("Handing over to {predecessor} instead of {}", handoff.target);
+        let predecessor_id = ChordId::from_addr(&predecessor);
+        handoff
+            .pending
+            .retain(|key| key.in_half_open_interval(&self_id, &predecessor_id));
+        handoff.target = predecessor;
+        if handoff.pending.is_empty() {
+            config.handoff = None;
+            save_journal(config);
+            return;
+        }
+        save_journal(config);
+    }
+
+    if let Some(handoff) = config.handoff.as_mut() {
+        trace!(
+            "Resuming the handoff of {} files to {}",
+            handoff.pending.len(),
+            handoff.target
+        );
+        handoff.queued.clear();
+        handoff.last_progress = now;
+    }
+    offer(handler, config);
+}
@@ -0,0 +1,200 @@
+use crate::common::{get_endpoint, ChordId, ChordMessage, Message, ServerSignals, Version};
+use crate::node_state::handlers::server_message::handoff::start_handoff;
+use crate::node_state::handlers::user_message::put::save_handed_over_tombstones;
+use crate::node_state::NodeConfig;
+use chrono::{DateTime, Utc};
+use message_io::network::{Endpoint, SendStatus};
+use message_io::node::NodeHandler;
+use std::net::SocketAddr;
+use tracing::{error, trace};
+
+/// Progress of the leave of a node, see [`start_leave`].
+pub(crate) enum Leave {
+    /// Handing the stored files over to the successor.
+    HandingOver(SocketAddr),
+    /// Announced to the neighbours at the given time, waiting for the acknowledgements of the listed ones.
+    Announced(DateTime<Utc>, Vec<SocketAddr>),
+}
+
+/// Starts leaving the ring: every stored file is handed over to the successor in acknowledged batches, see
+/// [`handoff`](super::handoff), then the successor is told about the predecessor it has to adopt and the predecessor
+/// about its new successor. The node stops once both acknowledged, or once the leave made no progress for the leave
+/// timeout of the node. It stays in the ring if the successor fails to store a file.
+pub fn start_leave(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    if config.leave.is_some() {
+        return;
+    }
+
+    let Some(successor) = config
+        .finger_table
+        .successor()
+        .filter(|successor| *successor != config.self_addr)
+    else {
+        trace!("Alone in the ring, leaving");
+        handler.stop();
+        return;
+    };
+
+    let keys = match config.storage.keys() {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("ERROR {:?} listing the files to hand over, not leaving", e);
+            return;
+        }
+    };
+
+    trace!("Leaving, handing {} files over to {successor}", keys.len());
+    config.leave = Some(Leave::HandingOver(successor));
+    start_handoff(handler, config, successor, keys);
+    continue_leave(handler, config);
+    handler
+        .signals()
+        .send_with_timer(ServerSignals::LeaveTimeout(), config.leave_timeout);
+}
+
+/// Announces the leave once the files are handed over: the successor gets the tombstones and the predecessor it has
+/// to adopt, the predecessor its new successor.
+pub(crate) fn continue_leave(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let Some(Leave::HandingOver(successor)) = config.leave else {
+        return;
+    };
+    if config.handoff.is_some() {
+        return;
+    }
+
+    let tombstones = config
+        .tombstones
+        .iter()
+        .map(|(key, tombstone)| (*key, tombstone.version))
+        .collect();
+
+    let mut pending_leave_acks = vec![successor];
+    let successor_endpoint = get_endpoint(handler, config, successor);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        successor_endpoint,
+        Message::ChordMessage(ChordMessage::LeaveSuccessor(
+            config.self_addr,
+            config.predecessor,
+            tombstones,
+        )),
+    ));
+
+    if let Some(predecessor) = config.predecessor {
+        pending_leave_acks.push(predecessor);
+        let predecessor_endpoint = get_endpoint(handler, config, predecessor);
+        handler.signals().send(ServerSignals::ForwardMessage(
+            predecessor_endpoint,
+            Message::ChordMessage(ChordMessage::LeavePredecessor(config.self_addr, successor)),
+        ));
+    }
+
+    trace!("Files handed over, waiting for {:?}", pending_leave_acks);
+    config.leave = Some(Leave::Announced(Utc::now(), pending_leave_acks));
+}
+
+/// Gives up leaving after a failure of the successor to store the handed over files: the node keeps serving them.
+pub(crate) fn abort_leave(config: &mut NodeConfig) {
+    if let Some(Leave::HandingOver(successor)) = config.leave {
+        error!("{successor} failed to store the handed over files, not leaving");
+        config.leave = None;
+    }
+}
+
+/// Stops the node once its leave made no progress for the leave timeout, whether handing the files over or waiting
+/// for the acknowledgements, and waits again otherwise.
+pub(crate) fn handle_leave_timeout(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let now = Utc::now();
+    let last_progress = match &config.leave {
+        None => return,
+        Some(Leave::HandingOver(_)) => config.handoff.as_ref().map_or(now, |handoff| handoff.last_progress),
+        Some(Leave::Announced(announced_at, _)) => *announced_at,
+    };
+    let waited = now.signed_duration_since(last_progress).to_std().unwrap_or_default();
+    if let Some(remaining) = config
+        .leave_timeout
+        .checked_sub(waited)
+        .filter(|remaining| !remaining.is_zero())
+    {
+        handler
+            .signals()
+            .send_with_timer(ServerSignals::LeaveTimeout(), remaining);
+        return;
+    }
+    trace!("Leave not acknowledged in time, stopping anyway");
+    handler.stop();
+}
+
+/// The predecessor of this node is leaving, after handing its files over: stores its tombstones and adopts its
+/// predecessor.
+pub fn handle_leave_successor(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    endpoint: Endpoint,
+    leaving: SocketAddr,
+    new_predecessor: Option<SocketAddr>,
+    tombstones: Vec<(ChordId, Version)>,
+) {
+    save_handed_over_tombstones(tombstones, config);
+    if config.predecessor == Some(leaving) {
+        config.predecessor = None;
+        config.predecessor_ping = None;
+        if let Some(new_predecessor) = new_predecessor.filter(|predecessor| *predecessor != config.self_addr) {
+            config.set_predecessor(new_predecessor);
+        }
+    }
+    forget_node(config, &leaving);
+
+    send_leave_ack(handler, config, endpoint);
+}
+
+/// The successor of this node is leaving: adopts the successor of the leaving node.
+pub fn handle_leave_predecessor(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    endpoint: Endpoint,
+    leaving: SocketAddr,
+    new_successor: SocketAddr,
+) {
+    let was_successor = config.finger_table.successor() == Some(leaving);
+    forget_node(config, &leaving);
+    if was_successor && new_successor != config.self_addr {
+        config.set_successor(new_successor);
+    }
+
+    send_leave_ack(handler, config, endpoint);
+}
+
+pub fn handle_leave_ack(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig, acknowledging: SocketAddr) {
+    let Some(Leave::Announced(_, pending_leave_acks)) = config.leave.as_mut() else {
+        return;
+    };
+    if let Some(position) = pending_leave_acks.iter().position(|node| *node == acknowledging) {
+        pending_leave_acks.remove(position);
+    }
+    if !pending_leave_acks.is_empty() {
+        return;
+    }
+
+    // The successor now owns the files, they can go
+    for key in config.storage.keys().unwrap_or_default() {
+        if let Err(e) = config.storage.delete(&key) {
+            error!("ERROR {:?} removing handed over file {key}", e);
+        }
+    }
+
+    trace!("Left the ring");
+    handler.stop();
+}
+
+fn forget_node(config: &mut NodeConfig, node: &SocketAddr) {
+    config.finger_table.remove_node(node);
+    config.successor_list.retain(|successor| successor != node);
+}
+
+fn send_leave_ack(handler: &NodeHandler<ServerSignals>, config: &NodeConfig, endpoint: Endpoint) {
+    let message = Message::ChordMessage(ChordMessage::LeaveAck(config.self_addr));
+    let serialized = bincode::serialize(&message).unwrap();
+    while handler.network().send(endpoint, &serialized) == SendStatus::ResourceNotAvailable {
+        trace!("Waiting for response...");
+    }
+}
@@ -1,21 +1,37 @@
-use crate::common;
-use crate::common::{binary_search, get_ws_endpoint, ChordMessage, Message, ServerSignals, SERVER_FOLDER};
+use crate::common::{get_endpoint, get_liveness_endpoint, ChordId, ChordMessage, Message, ServerSignals, Version};
+use crate::node_state::handlers::server_message::anti_entropy::{
+    handle_merkle_diff, handle_merkle_summary, handle_request_keys,
+};
 use crate::node_state::handlers::server_message::find::handle_lookup;
-use crate::node_state::handlers::user_message::get::{get_file_bytes, handle_forwarded_get};
-use crate::node_state::handlers::user_message::put::{handle_forwarded_put, save_in_server};
+use crate::node_state::handlers::server_message::handoff::{
+    handle_handoff_accept, handle_handoff_ack, handle_handoff_nack, handle_handoff_offer, handle_handoff_transfer,
+    start_handoff,
+};
+use crate::node_state::handlers::server_message::leave::{
+    continue_leave, handle_leave_ack, handle_leave_predecessor, handle_leave_successor,
+};
+use crate::node_state::handlers::server_message::replication::{handle_replica_get, refresh_replicas};
+use crate::node_state::handlers::server_message::stabilization::handle_stabilize_response;
+use crate::node_state::handlers::user_message::delete::{handle_forwarded_delete, save_tombstone};
+use crate::node_state::handlers::user_message::get::handle_forwarded_get;
+use crate::node_state::handlers::user_message::put::{
+    handle_forwarded_put, save_handed_over, save_handed_over_tombstones, save_replica,
+};
 use crate::node_state::NodeConfig;
 use chrono::Utc;
-use digest::Digest;
 use join::handle_join;
 use message_io::network::Endpoint;
 use message_io::node::NodeHandler;
-use sha2::Sha256;
-use std::fs;
 use std::net::SocketAddr;
-use tracing::trace;
+use tracing::{error, trace};
 
+pub mod anti_entropy;
 mod find;
+pub mod handoff;
 pub mod join;
+pub mod leave;
+pub mod replication;
+pub mod scrub;
 pub mod stabilization;
 
 pub fn handle_server_message(
@@ -25,18 +41,6 @@ pub fn handle_server_message(
     message: ChordMessage,
 ) {
     match message {
-        ChordMessage::AddPredecessor(predecessor) => {
-            config.predecessor = Some(predecessor);
-
-            let forwarding_endpoint = get_ws_endpoint(handler, config, predecessor);
-
-            if forwarding_endpoint.addr() != endpoint.addr() {
-                handler.signals().send(ServerSignals::ForwardMessage(
-                    forwarding_endpoint,
-                    Message::ChordMessage(ChordMessage::NotifyPredecessor(config.self_addr)),
-                ));
-            }
-        }
         ChordMessage::Join(addr) => {
             trace!("request from endpoint: {endpoint} ip: {addr}",);
             handle_join(handler, config, endpoint, addr);
@@ -46,123 +50,205 @@ pub fn handle_server_message(
             trace!("Message received from other peer: {message}");
         }
         ChordMessage::AddSuccessor(successor) => {
-            //trace!("Add successor {endpoint}, {mex} {}", self.config.self_addr);
-            config.finger_table.insert(0, successor);
-            move_files(handler, config, successor, &endpoint);
-
-            let forwarding_endpoint = get_ws_endpoint(handler, config, successor);
+            trace!("Joined the ring, successor {successor}");
+            config.set_successor(successor);
             config.last_modified = Utc::now();
 
-            if forwarding_endpoint.addr() == endpoint.addr() {
-                return;
-            }
-
+            let forwarding_endpoint = get_endpoint(handler, config, successor);
             handler.signals().send(ServerSignals::ForwardMessage(
                 forwarding_endpoint,
                 Message::ChordMessage(ChordMessage::NotifySuccessor(config.self_addr)),
             ));
-
-            trace!("{}, {:?}", config.self_addr, config.finger_table);
         }
         ChordMessage::ForwardJoin(addr) => {
             trace!("Forwarded join, joining {addr}");
 
-            let new_endpoint = get_ws_endpoint(handler, config, addr);
+            let new_endpoint = get_endpoint(handler, config, addr);
             let message = Message::ChordMessage(ChordMessage::Join(config.self_addr));
             handler
                 .signals()
                 .send(ServerSignals::ForwardMessage(new_endpoint, message));
         }
-        ChordMessage::ForwardedPut(addr, file) => {
-            trace!("Forwarded put");
-            handle_forwarded_put(handler, config, addr, file);
+        ChordMessage::ForwardedPut(addr, request_id, file, condition, expires_at) => {
+            trace!("Forwarded put, request {request_id} of {addr}");
+            handle_forwarded_put(handler, config, addr, request_id, file, condition, expires_at);
         }
-        ChordMessage::ForwardedGet(addr, key) => {
-            trace!("Forwarded get");
-            handle_forwarded_get(handler, config, addr, key);
+        ChordMessage::ForwardedGet(addr, request_id, key) => {
+            trace!("Forwarded get, request {request_id} of {addr}");
+            handle_forwarded_get(handler, config, addr, request_id, key);
         }
-        ChordMessage::MoveFile(file) => {
-            let _ = save_in_server(file, config.self_addr.port(), config);
+        ChordMessage::ForwardedDelete(addr, request_id, key) => {
+            trace!("Forwarded delete, request {request_id} of {addr}");
+            handle_forwarded_delete(handler, config, addr, request_id, key);
         }
-
-        ChordMessage::NotifySuccessor(predecessor) => {
-            if config.predecessor.is_some() && config.predecessor.unwrap() == predecessor {
-                return;
+        ChordMessage::MoveFile(file, version, expires_at) => {
+            if let Err(e) = save_handed_over(file, version, expires_at, config) {
+                error!("ERROR {:?} storing a moved file", e);
             }
-            config.predecessor = Some(predecessor);
         }
-        ChordMessage::NotifyPredecessor(successor) => {
-            config.last_modified = Utc::now();
+        ChordMessage::MoveTombstones(tombstones) => {
+            save_handed_over_tombstones(tombstones, config);
+        }
+        ChordMessage::Replicate(file, version, expires_at) => {
+            if let Err(e) = save_replica(file, version, expires_at, config) {
+                error!("ERROR {:?} storing a replica", e);
+            }
+        }
+        ChordMessage::ReplicateDelete(key, version) => {
+            if let Err(e) = save_tombstone(&key, version, config) {
+                error!("ERROR {:?} deleting replica {key}", e);
+            }
+        }
+        ChordMessage::ReplicaGet(addr, request_id, key) => {
+            trace!("Get of {key} answered by a replica, request {request_id} of {addr}");
+            handle_replica_get(handler, config, addr, request_id, key);
+        }
+        ChordMessage::MerkleSummary(owner, start, end, tree) => {
+            handle_merkle_summary(handler, config, owner, start, end, tree);
+        }
+        ChordMessage::MerkleDiff(replica, start, end, leaves, entries) => {
+            handle_merkle_diff(handler, config, replica, start, end, leaves, entries);
+        }
+        ChordMessage::RequestKeys(requesting, keys) => {
+            handle_request_keys(handler, config, requesting, keys);
+        }
+        ChordMessage::HandoffOffer(sender, offered) => {
+            handle_handoff_offer(handler, config, sender, offered);
+        }
+        ChordMessage::HandoffAccept(target, wanted) => {
+            handle_handoff_accept(handler, config, target, wanted);
+            continue_leave(handler, config);
+        }
+        ChordMessage::HandoffTransfer(sender, files) => {
+            trace!("Handoff of {} files from {sender}", files.len());
+            handle_handoff_transfer(handler, config, sender, files);
+        }
+        ChordMessage::HandoffAck(target, keys) => {
+            handle_handoff_ack(handler, config, target, keys);
+            continue_leave(handler, config);
+        }
+        ChordMessage::HandoffNack(target, keys) => {
+            handle_handoff_nack(config, target, keys);
+        }
 
-            if config.finger_table[0] == successor {
+        ChordMessage::NotifySuccessor(predecessor) => {
+            let is_closer = match config.predecessor {
+                None => true,
+                Some(current) => {
+                    ChordId::from_addr(&predecessor).in_open_interval(&ChordId::from_addr(&current), &config.id)
+                }
+            };
+            if predecessor == config.self_addr || !is_closer {
                 return;
             }
-            config.finger_table.insert(0, successor);
+            config.set_predecessor(predecessor);
 
-            move_files(handler, config, successor, &endpoint);
-            //todo remove the first one if it's not n+2^i id
+            move_files(handler, config, predecessor);
+            refresh_replicas(handler, config);
+        }
+        ChordMessage::GetPredecessor(requesting_address) => {
+            let requesting_endpoint = get_endpoint(handler, config, requesting_address);
+            handler.signals().send(ServerSignals::ForwardMessage(
+                requesting_endpoint,
+                Message::ChordMessage(ChordMessage::Predecessor(config.self_addr, config.predecessor)),
+            ));
+        }
+        ChordMessage::Predecessor(successor, successors_predecessor) => {
+            handle_stabilize_response(handler, config, successor, successors_predecessor);
         }
         ChordMessage::Find(wanted_id, searching_address) => {
             handle_lookup(handler, config, wanted_id, searching_address);
         }
-        ChordMessage::NotifyPresence(addr) => {
-            if !config.finger_table.is_empty() && addr == config.finger_table[0] {
-                config.last_modified = Utc::now();
-                return;
+        ChordMessage::FoundSuccessor(start, addr) => {
+            if config.finger_table.update_start(&start, addr) {
+                trace!("Finger starting at {:?} resolved to {addr}", start);
             }
-
-            let digested_address = Sha256::digest(addr.to_string().as_bytes()).to_vec();
-            let index = binary_search(config, &digested_address);
-            trace!("{:?}\n {:?}", digested_address, config.id);
-            if config.finger_table[index] == addr {
-                return;
+        }
+        ChordMessage::HeartBeat(successor_address) => {
+            if config.finger_table.successor() == Some(successor_address) {
+                config.last_modified = Utc::now();
             }
-            config.finger_table.insert(index, addr);
-            trace!("Node added to finger table ");
-        }
-        ChordMessage::HeartBeat(successor_address, successors_successor_address) => {
-            if (!config.finger_table.is_empty() && successor_address != config.finger_table[0])
-                || config.finger_table.is_empty()
-            {
-                config.finger_table.insert(0, successor_address);
+        }
+        ChordMessage::Ping(addr) => {
+            let pinging_endpoint = get_liveness_endpoint(handler, config, addr);
+            handler.signals().send(ServerSignals::ForwardMessage(
+                pinging_endpoint,
+                Message::ChordMessage(ChordMessage::Pong(config.self_addr)),
+            ));
+        }
+        ChordMessage::Pong(addr) => {
+            if config.predecessor == Some(addr) {
+                config.predecessor_ping = None;
             }
-
-            if (!config.successors_cache.is_empty() && successors_successor_address != config.successors_cache[0])
-                || config.successors_cache.is_empty()
-            {
-                config.successors_cache.insert(0, successors_successor_address);
+            config.successor_pings.remove(&addr);
+        }
+        ChordMessage::LeaveSuccessor(leaving, new_predecessor, tombstones) => {
+            trace!("Successor {leaving} leaving");
+            handle_leave_successor(handler, config, endpoint, leaving, new_predecessor, tombstones);
+        }
+        ChordMessage::LeavePredecessor(leaving, new_successor) => {
+            trace!("Predecessor {leaving} leaving");
+            handle_leave_predecessor(handler, config, endpoint, leaving, new_successor);
+        }
+        ChordMessage::LeaveAck(acknowledging) => {
+            handle_leave_ack(handler, config, acknowledging);
+        }
+        ChordMessage::GetSuccessorList(requesting_address) => {
+            let requesting_endpoint = get_endpoint(handler, config, requesting_address);
+            handler.signals().send(ServerSignals::ForwardMessage(
+                requesting_endpoint,
+                Message::ChordMessage(ChordMessage::SuccessorList(
+                    config.self_addr,
+                    config.successor_list.clone(),
+                )),
+            ));
+        }
+        ChordMessage::SuccessorList(successor, successor_list) => {
+            if config.finger_table.successor() != Some(successor) {
+                return;
             }
-
-            config.last_modified = Utc::now();
+            config.update_successor_list(successor, successor_list);
+            trace!("Successor list {:?}", config.successor_list);
+            refresh_replicas(handler, config);
         }
     }
 }
 
-fn move_files(
-    handler: &NodeHandler<ServerSignals>,
-    config: &mut NodeConfig,
-    new_node_addr: SocketAddr,
-    endpoint: &Endpoint,
-) {
-    let digested_addr = Sha256::digest(new_node_addr.to_string().as_bytes()).to_vec();
-
-    let forward_endpoint = get_ws_endpoint(handler, config, new_node_addr);
-
-    trace!("{} {}", endpoint.addr(), forward_endpoint.addr());
+/// Hands over to `new_predecessor` the files this node is no longer responsible for, i.e. the ones whose key is
+/// not in `(new_predecessor, self]`, together with the tombstones of that range.
+///
+/// The files are offered first and only deleted once `new_predecessor` acknowledged them, see
+/// [`handoff`](handoff). With replication they are kept: this node is now the first replica of `new_predecessor`.
+/// A leaving node hands all its files over to its successor instead, which passes them on.
+pub(crate) fn move_files(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig, new_predecessor: SocketAddr) {
+    if config.leave.is_some() {
+        return;
+    }
+    let predecessor_id = ChordId::from_addr(&new_predecessor);
+
+    let forward_endpoint = get_endpoint(handler, config, new_predecessor);
+
+    trace!("moving files to {}", forward_endpoint.addr());
+
+    let tombstones: Vec<(ChordId, Version)> = config
+        .tombstones
+        .iter()
+        .filter(|(key, _)| key.in_half_open_interval(&config.id, &predecessor_id))
+        .map(|(key, tombstone)| (*key, tombstone.version))
+        .collect();
+    if !tombstones.is_empty() {
+        handler.signals().send(ServerSignals::ForwardMessage(
+            forward_endpoint,
+            Message::ChordMessage(ChordMessage::MoveTombstones(tombstones)),
+        ));
+    }
 
-    for (key, file_name) in &config.saved_files {
-        let digested_key = hex::decode(key).unwrap();
-        if digested_key > digested_addr {
-            let file_path = SERVER_FOLDER.to_string() + config.self_addr.port().to_string().as_str() + "/" + key;
-            let buffer = get_file_bytes(file_path.clone());
-            handler.signals().send(ServerSignals::ForwardMessage(
-                forward_endpoint,
-                Message::ChordMessage(ChordMessage::MoveFile(common::File {
-                    name: file_name.to_string(),
-                    buffer,
-                })),
-            ));
-            fs::remove_file(file_path).unwrap()
+    let keys = match config.storage.list_range(&config.id, &predecessor_id) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("ERROR {:?} listing the files to move", e);
+            return;
         }
-    }
+    };
+    start_handoff(handler, config, new_predecessor, keys);
 }
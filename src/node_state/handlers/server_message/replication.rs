@@ -0,0 +1,227 @@
+use crate::common::{
+    self, get_endpoint, ChordId, ChordMessage, Message, RequestId, ServerSignals, ServerToUserMessage, Version,
+};
+use crate::node_state::handlers::server_message::anti_entropy::send_summary;
+use crate::node_state::handlers::server_message::scrub::{get_or_quarantine, quarantine};
+use crate::node_state::NodeConfig;
+use crate::storage::is_corrupted;
+use chrono::{DateTime, Utc};
+use message_io::node::NodeHandler;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tracing::{error, trace};
+
+/// Number of maximum gossip intervals a replicated range stays valid without a new summary from its owner.
+const REPLICA_LEASE_ROUNDS: u32 = 3;
+
+/// Ranges of keys this node holds copies of for other nodes, learnt from the Merkle summaries their owners send
+/// every stabilization round, see [`collect_replicas`].
+pub(crate) struct ReplicatedRanges {
+    /// Range `(start, end]` of each owner, with when its last summary arrived.
+    ranges: HashMap<SocketAddr, (ChordId, ChordId, DateTime<Utc>)>,
+    /// When the node started listening to the summaries.
+    since: DateTime<Utc>,
+}
+
+impl ReplicatedRanges {
+    pub(crate) fn new() -> Self {
+        ReplicatedRanges {
+            ranges: HashMap::new(),
+            since: Utc::now(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, owner: SocketAddr, start: ChordId, end: ChordId) {
+        self.ranges.insert(owner, (start, end, Utc::now()));
+    }
+
+    fn covers(&self, key: &ChordId) -> bool {
+        self.ranges
+            .values()
+            .any(|(start, end, _)| key.in_half_open_interval(start, end))
+    }
+}
+
+/// Successors holding a copy of the keys this node is responsible for: the first `replication_factor - 1` entries
+/// of the successor list.
+pub(crate) fn replica_set(config: &NodeConfig) -> Vec<SocketAddr> {
+    config
+        .successor_list
+        .iter()
+        .copied()
+        .filter(|node| *node != config.self_addr)
+        .take(config.replication_factor.saturating_sub(1))
+        .collect()
+}
+
+/// Pushes a copy of a file just stored by this node, as the node responsible for it, to its replicas.
+pub(crate) fn replicate(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    file: &common::File,
+    version: Version,
+    expires_at: Option<DateTime<Utc>>,
+) {
+    for replica in replica_set(config) {
+        let endpoint = get_endpoint(handler, config, replica);
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::Replicate(file.clone(), version, expires_at)),
+        ));
+    }
+}
+
+/// Tells the replicas that a key this node is responsible for has been deleted, with `version`.
+pub(crate) fn replicate_delete(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    key: ChordId,
+    version: Version,
+) {
+    for replica in replica_set(config) {
+        let endpoint = get_endpoint(handler, config, replica);
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::ReplicateDelete(key, version)),
+        ));
+    }
+}
+
+/// Brings the replicas up to date after the replica set or the range of keys this node is responsible for changed,
+/// e.g. after a successor failed or the predecessor did and this node took over its keys. The replicas new to the
+/// set, or all of them once the range changed, get the Merkle tree of the range: only the keys they lack are then
+/// pushed, in batches, see [`anti_entropy`](super::anti_entropy).
+pub(crate) fn refresh_replicas(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let replicas = replica_set(config);
+    if replicas == config.replicas && config.predecessor == config.replicated_predecessor {
+        return;
+    }
+    let outdated: Vec<SocketAddr> = if config.predecessor == config.replicated_predecessor {
+        replicas
+            .iter()
+            .filter(|replica| !config.replicas.contains(replica))
+            .copied()
+            .collect()
+    } else {
+        replicas.clone()
+    };
+    config.replicas = replicas;
+    config.replicated_predecessor = config.predecessor;
+
+    trace!("Refreshing the replicas {:?}", outdated);
+    send_summary(handler, config, &outdated);
+}
+
+/// Deletes the copies this node no longer holds for anyone: the keys outside its own range and outside the ranges
+/// its owners summarized to it lately, see [`ReplicatedRanges`]. Keys being handed over are kept. Nothing is
+/// collected without replication, while the node leaves or its range is unknown, nor before it has been up long
+/// enough to hear from every owner.
+pub(crate) fn collect_replicas(config: &mut NodeConfig) {
+    if config.replication_factor <= 1 || config.leave.is_some() {
+        return;
+    }
+    let Some(predecessor) = config.predecessor else {
+        return;
+    };
+    let now = Utc::now();
+    let lease = config.maximum_gossip_interval * REPLICA_LEASE_ROUNDS;
+    let outlived = |since: &DateTime<Utc>| now.signed_duration_since(*since).to_std().is_ok_and(|age| age > lease);
+    let replicated = &mut config.replicated_ranges;
+    replicated
+        .ranges
+        .retain(|_, (_, _, received_at)| !outlived(received_at));
+    if !outlived(&replicated.since) {
+        return;
+    }
+
+    let keys = match config.storage.keys() {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("ERROR {:?} listing the keys to collect", e);
+            return;
+        }
+    };
+    let start = ChordId::from_addr(&predecessor);
+    for key in keys {
+        if key.in_half_open_interval(&start, &config.id)
+            || config.replicated_ranges.covers(&key)
+            || config
+                .handoff
+                .as_ref()
+                .is_some_and(|handoff| handoff.pending.contains(&key))
+        {
+            continue;
+        }
+        trace!("No longer a replica of {key}, deleting it");
+        if let Err(e) = config.storage.delete(&key) {
+            error!("ERROR {:?} deleting the stale replica {key}", e);
+        }
+    }
+}
+
+/// Replica to read from instead of the successor, when the successor is the node responsible for a key but has
+/// not sent a heartbeat for a while.
+pub(crate) fn replica_of_failed_successor(config: &NodeConfig) -> Option<SocketAddr> {
+    let silent_for = Utc::now().signed_duration_since(config.last_modified);
+    if silent_for.to_std().ok()? <= config.heartbeat_interval * 3 {
+        return None;
+    }
+    config
+        .successor_list
+        .iter()
+        .copied()
+        .skip(1)
+        .find(|node| *node != config.self_addr)
+}
+
+/// Whether the predecessor left a ping unanswered for longer than three heartbeats. This node then answers the gets
+/// of the keys it holds a copy of, instead of forwarding them to the predecessor.
+pub(crate) fn predecessor_looks_down(config: &NodeConfig) -> bool {
+    config.predecessor_ping.is_some_and(|sent| {
+        Utc::now()
+            .signed_duration_since(sent)
+            .to_std()
+            .is_ok_and(|silent_for| silent_for > config.heartbeat_interval * 3)
+    })
+}
+
+/// Answers a get sent straight to this node because the node responsible for the key looks down.
+pub(crate) fn handle_replica_get(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    user_address: SocketAddr,
+    request_id: RequestId,
+    key: String,
+) {
+    let message = match ChordId::from_hex(&key) {
+        Some(digested_key) => read_replica(handler, config, digested_key, key),
+        None => ServerToUserMessage::HexConversionNotValid(key),
+    };
+    let endpoint = get_endpoint(handler, config, user_address);
+    handler
+        .signals()
+        .send(ServerSignals::SendMessageToUser(endpoint, request_id, message));
+}
+
+fn read_replica(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    digested_key: ChordId,
+    key: String,
+) -> ServerToUserMessage {
+    match get_or_quarantine(handler, config, &digested_key) {
+        Ok(Some((_, metadata))) if metadata.is_expired(Utc::now()) => ServerToUserMessage::FileNotFound(key),
+        Ok(Some((file, metadata))) if file.verify() => ServerToUserMessage::RequestedFile(file, metadata.version),
+        Ok(Some((file, _))) => {
+            error!("Content of replica {} does not match its key", file.name);
+            quarantine(handler, config, digested_key);
+            ServerToUserMessage::CorruptedFile(key)
+        }
+        Ok(None) => ServerToUserMessage::FileNotFound(key),
+        Err(e) if is_corrupted(&e) => ServerToUserMessage::CorruptedFile(key),
+        Err(e) => {
+            error!("ERROR {:?} reading replica {key}", e);
+            ServerToUserMessage::InternalServerError
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::common::{self, get_endpoint, ChordId, ChordMessage, Message, ServerSignals};
+use crate::node_state::handlers::server_message::replication::replica_set;
+use crate::node_state::NodeConfig;
+use crate::storage::{is_corrupted, Metadata};
+use message_io::node::NodeHandler;
+use std::io;
+use tracing::{error, trace};
+
+/// Number of files checked by each round of the scrubber.
+const SCRUB_BATCH: usize = 16;
+
+/// Reads `key` like `StorageBackend::get_with_metadata`, quarantining the file if it failed its checksum.
+pub(crate) fn get_or_quarantine(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    key: &ChordId,
+) -> io::Result<Option<(common::File, Metadata)>> {
+    let stored = config.storage.get_with_metadata(key);
+    if let Err(e) = &stored {
+        if is_corrupted(e) {
+            quarantine(handler, config, *key);
+        }
+    }
+    stored
+}
+
+/// Moves a corrupted file out of the storage and asks the nodes that may hold a copy of it to send it back: the
+/// predecessor, which is the node responsible for the key or a replica closer to it, and the replicas.
+pub(crate) fn quarantine(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig, key: ChordId) {
+    error!("{key} failed its checksum, quarantining it");
+    if let Err(e) = config.storage.quarantine(&key) {
+        error!("ERROR {:?} quarantining {key}", e);
+        return;
+    }
+
+    let mut holders = replica_set(config);
+    holders.extend(config.predecessor);
+    holders.sort_unstable();
+    holders.dedup();
+    holders.retain(|holder| *holder != config.self_addr);
+    for holder in holders {
+        trace!("Requesting a copy of {key} from {holder}");
+        let endpoint = get_endpoint(handler, config, holder);
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::RequestKeys(config.self_addr, vec![key])),
+        ));
+    }
+}
+
+/// Checks the next few stored files against their checksum, walking the keys in ring order across rounds, and
+/// schedules the next round. Corrupted files are quarantined.
+pub(crate) fn scrub(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    handler
+        .signals()
+        .send_with_timer(ServerSignals::Scrub(), config.scrub_interval);
+
+    let mut keys = match config.storage.list_range(&config.scrub_cursor, &config.scrub_cursor) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("ERROR {:?} listing the keys to scrub", e);
+            return;
+        }
+    };
+    // The cursor, checked last round, comes first
+    if keys.first() == Some(&config.scrub_cursor) {
+        keys.rotate_left(1);
+    }
+
+    for key in keys.into_iter().take(SCRUB_BATCH) {
+        match config.storage.get(&key) {
+            Err(e) if is_corrupted(&e) => quarantine(handler, config, key),
+            Err(e) => error!("ERROR {:?} scrubbing {key}", e),
+            Ok(_) => {}
+        }
+        config.scrub_cursor = key;
+    }
+}
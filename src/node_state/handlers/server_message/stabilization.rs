@@ -1,73 +1,166 @@
-use crate::common::{binary_search, get_udp_endpoint, get_ws_endpoint, ChordMessage, Message, ServerSignals};
-use crate::node_state::{NodeConfig, FINGER_TABLE_SIZE, HEARTBEAT_TIMEOUT, ID_BYTES, MAXIMUM_DURATION};
+use crate::common::{
+    get_endpoint, get_liveness_endpoint, next_hop, ChordId, ChordMessage, Message, ServerSignals, ID_BITS,
+};
+use crate::node_state::handlers::server_message::anti_entropy::anti_entropy;
+use crate::node_state::handlers::server_message::handoff::resume_handoff;
+use crate::node_state::handlers::server_message::replication::{collect_replicas, refresh_replicas};
+use crate::node_state::handlers::user_message::delete::expire_tombstones;
+use crate::node_state::NodeConfig;
 use chrono::Utc;
 use message_io::node::NodeHandler;
+use std::net::SocketAddr;
 use std::ops::Mul;
 use tracing::trace;
 
-fn binary_add(mut vec: Vec<u8>, index: usize, bytes: usize) -> Result<Vec<u8>, ()> {
-    let mut byte = bytes - 1;
-    let power = (index % 8) as u8;
-    if (byte as isize - (index / 8) as isize) < 0 {
-        return Err(());
+pub fn stabilization_protocol(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    stabilize(handler, config);
+    refresh_successor_list(handler, config);
+    heart_beat(handler, config);
+    fix_fingers(handler, config);
+    refresh_replicas(handler, config);
+    anti_entropy(handler, config);
+    collect_replicas(config);
+    resume_handoff(handler, config);
+    expire_tombstones(config);
+    config.answered.expire();
+}
+
+/// Liveness checks of both neighbours. They run every heartbeat interval rather than every stabilization round,
+/// whose interval grows far beyond the successor and predecessor timeouts.
+pub(crate) fn check_neighbours(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    check_successor(handler, config);
+    check_predecessor(handler, config);
+}
+
+/// If the successor stopped answering, promotes the first live entry of the successor list and notifies it. The
+/// other entries are pinged meanwhile, so that the ones already dead are skipped.
+fn check_successor(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    if config.finger_table.is_empty() {
+        return;
     }
+    let now = Utc::now();
+    ping_successor_list(handler, config);
+    if now.signed_duration_since(config.last_modified) > config.successor_timeout {
+        let Some(successor) = config.promote_next_successor() else {
+            return;
+        };
+        trace!("Successor failed, promoting {successor}");
+        config.last_modified = now;
 
-    byte -= index / 8;
-    let mut specific_byte = vec[byte] as u16;
+        notify(handler, config, successor);
+    }
+}
 
-    let mut shifted_increment = 1;
-    shifted_increment <<= power;
+/// Pings the entries of the successor list after the successor, which watches over itself through its heartbeats.
+fn ping_successor_list(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let backups = config.successor_list.iter().skip(1).copied().collect::<Vec<_>>();
+    config.successor_pings.retain(|node, _| backups.contains(node));
 
-    specific_byte += shifted_increment;
-    let mut carry;
-    if specific_byte > FINGER_TABLE_SIZE as u16 {
-        while specific_byte > FINGER_TABLE_SIZE as u16 {
-            carry = specific_byte / (FINGER_TABLE_SIZE as u16 + 1);
+    let now = Utc::now();
+    for backup in backups {
+        config.successor_pings.entry(backup).or_insert(now);
+        let endpoint = get_liveness_endpoint(handler, config, backup);
+        handler.signals().send(ServerSignals::ForwardMessage(
+            endpoint,
+            Message::ChordMessage(ChordMessage::Ping(config.self_addr)),
+        ));
+    }
+}
 
-            vec[byte] = (specific_byte % FINGER_TABLE_SIZE as u16) as u8;
+/// Pings the predecessor and forgets it once a ping stays unanswered for longer than the predecessor timeout, so
+/// that a live node can take its place through `NotifySuccessor`.
+pub(crate) fn check_predecessor(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let Some(predecessor) = config.predecessor else {
+        return;
+    };
 
-            if byte as isize - 1 < 0 {
-                break;
-            }
-            byte -= 1;
-            specific_byte = carry + vec[byte] as u16;
+    let now = Utc::now();
+    match config.predecessor_ping {
+        Some(sent) if now.signed_duration_since(sent) > config.predecessor_timeout => {
+            trace!("Predecessor {predecessor} failed");
+            config.predecessor = None;
+            config.predecessor_ping = None;
+            return;
         }
-    } else {
-        vec[byte] = specific_byte as u8;
+        Some(_) => {}
+        None => config.predecessor_ping = Some(now),
     }
-    Ok(vec)
+
+    let endpoint = get_liveness_endpoint(handler, config, predecessor);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::Ping(config.self_addr)),
+    ));
 }
 
-pub fn stabilization_protocol(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
-    check_successor(handler, config);
-    heart_beat(handler, config);
-    update_finger_table(handler, config);
+/// First half of the stabilize round: asks the successor for its predecessor.
+///
+/// A node that is still alone but has been notified by another one takes its predecessor as successor.
+fn stabilize(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let successor = match (config.finger_table.successor(), config.predecessor) {
+        (Some(successor), _) => successor,
+        (None, Some(predecessor)) => {
+            config.set_successor(predecessor);
+            config.last_modified = Utc::now();
+            notify(handler, config, predecessor);
+            return;
+        }
+        (None, None) => return,
+    };
+
+    let endpoint = get_endpoint(handler, config, successor);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::GetPredecessor(config.self_addr)),
+    ));
 }
 
-fn check_successor(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
-    if config.finger_table.is_empty() {
+/// Second half of the stabilize round: adopts the predecessor of the successor if it lies between this node and
+/// the successor, then notifies the (possibly new) successor.
+pub(crate) fn handle_stabilize_response(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    successor: SocketAddr,
+    successors_predecessor: Option<SocketAddr>,
+) {
+    if config.finger_table.successor() != Some(successor) {
         return;
     }
-    let now = Utc::now();
-    if now.signed_duration_since(config.last_modified) > HEARTBEAT_TIMEOUT {
-        if config.successors_cache.is_empty() {
-            return;
+    config.last_modified = Utc::now();
+
+    let mut new_successor = successor;
+    if let Some(candidate) = successors_predecessor {
+        if candidate != config.self_addr
+            && ChordId::from_addr(&candidate).in_open_interval(&config.id, &ChordId::from_addr(&successor))
+        {
+            trace!("Adopting {candidate} as successor");
+            config.set_successor(candidate);
+            new_successor = candidate;
         }
-        while !config.successors_cache.is_empty() {
-            if config.finger_table[0] != config.successors_cache[0] {
-                config.finger_table[0] = config.successors_cache[0];
-                if config.finger_table.len() > 1 && config.finger_table[0] == config.finger_table[1] {
-                    config.finger_table.remove(1);
-                }
-            }
-            config.successors_cache.remove(0);
-        }
-        let endpoint = get_ws_endpoint(handler, config, config.finger_table[0]);
-        handler.signals().send(ServerSignals::ForwardMessage(
-            endpoint,
-            Message::ChordMessage(ChordMessage::NotifySuccessor(config.self_addr)),
-        ));
     }
+
+    notify(handler, config, new_successor);
+}
+
+fn notify(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig, successor: SocketAddr) {
+    let endpoint = get_endpoint(handler, config, successor);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::NotifySuccessor(config.self_addr)),
+    ));
+}
+
+/// Asks the successor for its successor list, the answer refreshes ours.
+fn refresh_successor_list(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+    let Some(successor) = config.finger_table.successor() else {
+        return;
+    };
+
+    let endpoint = get_endpoint(handler, config, successor);
+    handler.signals().send(ServerSignals::ForwardMessage(
+        endpoint,
+        Message::ChordMessage(ChordMessage::GetSuccessorList(config.self_addr)),
+    ));
 }
 
 fn heart_beat(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
@@ -75,38 +168,35 @@ fn heart_beat(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
         return;
     }
 
-    let endpoint = get_udp_endpoint(handler, config, config.predecessor.unwrap());
-
-    let successor = if config.finger_table.is_empty() {
-        config.self_addr
-    } else {
-        config.finger_table[0]
-    };
+    let endpoint = get_liveness_endpoint(handler, config, config.predecessor.unwrap());
 
-    let message = Message::ChordMessage(ChordMessage::HeartBeat(config.self_addr, successor));
+    let message = Message::ChordMessage(ChordMessage::HeartBeat(config.self_addr));
 
     handler.signals().send(ServerSignals::ForwardMessage(endpoint, message));
 }
 
-fn update_finger_table(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
+/// Refreshes every finger in place. A finger is resolved locally when the node of the previous finger also
+/// succeeds its start, otherwise a `Find` for its start is sent towards the ring.
+fn fix_fingers(handler: &NodeHandler<ServerSignals>, config: &mut NodeConfig) {
     if !config.finger_table.is_empty() {
-        let searching = binary_add(config.id.clone(), 0, ID_BYTES).unwrap();
-        let mut forwarding_index = binary_search(config, &searching);
-        let mut socket_address = config.finger_table[forwarding_index];
-        let mut endpoint = get_ws_endpoint(handler, config, socket_address);
-
-        handler.signals().send(ServerSignals::ForwardMessage(
-            endpoint,
-            Message::ChordMessage(ChordMessage::Find(searching, config.self_addr)),
-        ));
-
-        for i in 0..FINGER_TABLE_SIZE {
-            let searching = binary_add(config.id.clone(), i, ID_BYTES).unwrap();
-            forwarding_index = binary_search(config, &searching);
+        // The first finger is the successor, kept up to date by the rest of the protocol
+        for i in 1..ID_BITS {
+            if let Some(previous) = config.finger_table.node(i - 1) {
+                let (start, end) = config.finger_table.interval(i - 1);
+                let previous_id = ChordId::from_addr(&previous);
+                if previous_id != start && !previous_id.in_open_interval(&start, &end) {
+                    config.finger_table.update(i, previous);
+                    continue;
+                }
+            }
 
-            socket_address = config.finger_table[forwarding_index];
+            let searching = config.finger_table.start(i);
+            let Some(socket_address) = next_hop(config, &searching) else {
+                config.finger_table.update(i, config.self_addr);
+                continue;
+            };
 
-            endpoint = get_ws_endpoint(handler, config, socket_address);
+            let endpoint = get_endpoint(handler, config, socket_address);
 
             handler.signals().send(ServerSignals::ForwardMessage(
                 endpoint,
@@ -114,7 +204,7 @@ fn update_finger_table(handler: &NodeHandler<ServerSignals>, config: &mut NodeCo
             ));
         }
 
-        if config.gossip_interval.lt(&MAXIMUM_DURATION) {
+        if config.gossip_interval.lt(&config.maximum_gossip_interval) {
             let new_interval = config.gossip_interval.mul(2);
             config.gossip_interval = new_interval;
         }
@@ -0,0 +1,44 @@
+use crate::common::{RequestId, ServerToUserMessage};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tracing::trace;
+
+/// How long a node remembers its answer to a put or a delete, longer than a user keeps retrying a request.
+const ANSWER_LIFETIME: TimeDelta = TimeDelta::minutes(2);
+
+/// Answers to the puts and deletes this node carried out, by user and request id, so that a retry of a request
+/// reaching the node again, directly or forwarded, gets the same answer instead of being carried out twice.
+#[derive(Default)]
+pub(crate) struct AnsweredRequests {
+    answers: HashMap<(SocketAddr, RequestId), (DateTime<Utc>, ServerToUserMessage)>,
+}
+
+impl AnsweredRequests {
+    /// The answer already given to request `request_id` of `user_addr`, if any.
+    pub(crate) fn recall(&self, user_addr: SocketAddr, request_id: RequestId) -> Option<ServerToUserMessage> {
+        let (_, answer) = self.answers.get(&(user_addr, request_id))?;
+        trace!("Request {request_id} of {user_addr} already answered: {:?}", answer);
+        Some(answer.clone())
+    }
+
+    /// Records the answer to request `request_id` of `user_addr`. Answers that do not settle the request, a forward
+    /// or an internal error, are not recorded: a retry carries the request out again.
+    pub(crate) fn remember(&mut self, user_addr: SocketAddr, request_id: RequestId, answer: &ServerToUserMessage) {
+        if matches!(
+            answer,
+            ServerToUserMessage::ForwarderTo(_) | ServerToUserMessage::InternalServerError
+        ) {
+            return;
+        }
+        self.answers
+            .insert((user_addr, request_id), (Utc::now(), answer.clone()));
+    }
+
+    /// Forgets the answers older than their lifetime.
+    pub(crate) fn expire(&mut self) {
+        let now = Utc::now();
+        self.answers
+            .retain(|_, (answered_at, _)| now.signed_duration_since(*answered_at) <= ANSWER_LIFETIME);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::common::{
+    get_endpoint, next_hop, ChordId, ChordMessage, Message, RequestId, ServerSignals, ServerToUserMessage, Version,
+};
+use crate::errors::DeleteError;
+use crate::node_state::handlers::server_message::replication::replicate_delete;
+use crate::node_state::NodeConfig;
+use crate::storage::Tombstone;
+use chrono::Utc;
+use message_io::node::NodeHandler;
+use std::io;
+use std::net::SocketAddr;
+use tracing::{error, trace};
+
+pub fn handle_forwarded_delete(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    addr: SocketAddr,
+    request_id: RequestId,
+    key: String,
+) {
+    let endpoint = get_endpoint(handler, config, addr);
+    let response = delete_from_key(handler, config, addr, request_id, key);
+    let message = ServerSignals::SendMessageToUser(endpoint, request_id, response);
+    handler.signals().send(message);
+}
+
+pub fn delete_from_key(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    addr: SocketAddr,
+    request_id: RequestId,
+    key: String,
+) -> ServerToUserMessage {
+    if let Some(answer) = config.answered.recall(addr, request_id) {
+        return answer;
+    }
+    let answer = match handle_user_delete(handler, config, key.clone(), addr, request_id) {
+        Ok(()) => ServerToUserMessage::DeletedKey(key),
+        Err(e) => match e {
+            DeleteError::ForwardingRequest(addr) => ServerToUserMessage::ForwarderTo(addr),
+            DeleteError::ErrorDeletingFile | DeleteError::Timeout | DeleteError::UnexpectedAnswer => {
+                ServerToUserMessage::InternalServerError
+            }
+            DeleteError::NotFound => ServerToUserMessage::FileNotFound(key),
+            DeleteError::HexConversion => ServerToUserMessage::HexConversionNotValid(key),
+        },
+    };
+    config.answered.remember(addr, request_id, &answer);
+    answer
+}
+
+fn handle_user_delete(
+    handler: &NodeHandler<ServerSignals>,
+    config: &mut NodeConfig,
+    key: String,
+    addr: SocketAddr,
+    request_id: RequestId,
+) -> Result<(), DeleteError> {
+    trace!("Handling user delete, request {request_id} of {addr}");
+
+    let Some(digested_key) = ChordId::from_hex(&key) else {
+        return Err(DeleteError::HexConversion);
+    };
+
+    if let Some(forwarding_address) = next_hop(config, &digested_key) {
+        let forwarding_endpoint = get_endpoint(handler, config, forwarding_address);
+
+        handler.signals().send(ServerSignals::ForwardMessage(
+            forwarding_endpoint,
+            Message::ChordMessage(ChordMessage::ForwardedDelete(addr, request_id, key)),
+        ));
+
+        return Err(DeleteError::ForwardingRequest(forwarding_address.to_string()));
+    }
+
+    let deleted = delete_in_server(&digested_key, config);
+    if let (Ok(_), Some(tombstone)) = (&deleted, config.tombstones.get(&digested_key)) {
+        replicate_delete(handler, config, digested_key, tombstone.version);
+    }
+    match deleted {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(DeleteError::NotFound),
+        Err(e) => {
+            error!("ERROR {:?} deleting {key}", e);
+            Err(DeleteError::ErrorDeletingFile)
+        }
+    }
+}
+
+/// Removes the file stored under `key` and leaves in its place a tombstone with the version following the last one
+/// of the key. Returns false if there was no file. A key this node knows nothing of, neither stored nor deleted, is
+/// left alone: a tombstone would shadow a copy of it still on its way from another node.
+pub(crate) fn delete_in_server(key: &ChordId, config: &mut NodeConfig) -> io::Result<bool> {
+    let stored = config.storage.metadata(key)?.map(|metadata| metadata.version);
+    let deleted = config.tombstones.get(key).map(|tombstone| tombstone.version);
+    if stored.is_none() && deleted.is_none() {
+        return Ok(false);
+    }
+    let version = stored.max(deleted).unwrap_or(Version::UNVERSIONED).next(config.id);
+    save_tombstone(key, version, config)
+}
+
+/// Removes the file stored under `key` and records a tombstone with `version` in its place, unless the key is
+/// already known in a version at least as recent. Returns false if no file was removed.
+pub(crate) fn save_tombstone(key: &ChordId, version: Version, config: &mut NodeConfig) -> io::Result<bool> {
+    let stored = config.storage.metadata(key)?.map(|metadata| metadata.version);
+    let deleted = config.tombstones.get(key).map(|tombstone| tombstone.version);
+    if stored.max(deleted).is_some_and(|latest| latest >= version) {
+        trace!("Ignoring the delete of {key} with version {version}, the key is more recent");
+        return Ok(false);
+    }
+    let tombstone = Tombstone {
+        version,
+        deleted_at: Utc::now(),
+    };
+    let removed = config.storage.delete_with_tombstone(key, tombstone)?;
+    config.tombstones.insert(*key, tombstone);
+    Ok(removed)
+}
+
+/// Keeps a tombstone only while it is younger than the tombstone lifetime of the node.
+pub(crate) fn expire_tombstones(config: &mut NodeConfig) {
+    let now = Utc::now();
+    let lifetime = config.tombstone_lifetime;
+    let expired: Vec<ChordId> = config
+        .tombstones
+        .iter()
+        .filter(|(_, tombstone)| now.signed_duration_since(tombstone.deleted_at) > lifetime)
+        .map(|(key, _)| *key)
+        .collect();
+    for key in expired {
+        match config.storage.remove_tombstone(&key) {
+            Ok(()) => {
+                config.tombstones.remove(&key);
+            }
+            Err(e) => error!("ERROR {:?} removing the tombstone of {key}", e),
+        }
+    }
+}
+
+/// Removes the stored files whose time-to-live ran out. Every node sweeps its own copies, replicas included, and
+/// copies handed over after expiring are dropped, so no tombstone is needed.
+pub(crate) fn expire_keys(config: &mut NodeConfig) {
+    let now = Utc::now();
+    let keys = match config.storage.keys() {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("ERROR {:?} listing the keys to expire", e);
+            return;
+        }
+    };
+    for key in keys {
+        match config.storage.metadata(&key) {
+            Ok(Some(metadata)) if metadata.is_expired(now) => {
+                trace!("{key} expired, removing it");
+                if let Err(e) = config.storage.delete(&key) {
+                    error!("ERROR {:?} removing expired file {key}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("ERROR {:?} reading the metadata of {key}", e),
+        }
+    }
+}
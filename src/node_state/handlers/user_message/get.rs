@@ -1,26 +1,27 @@
 use crate::common;
 use crate::common::{
-    binary_search, get_ws_endpoint, ChordMessage, Message, ServerSignals, ServerToUserMessage, SERVER_FOLDER,
+    get_endpoint, next_hop, ChordId, ChordMessage, Message, RequestId, ServerSignals, ServerToUserMessage, Version,
 };
 use crate::errors::GetError;
+use crate::node_state::handlers::server_message::replication::{predecessor_looks_down, replica_of_failed_successor};
+use crate::node_state::handlers::server_message::scrub::{get_or_quarantine, quarantine};
 use crate::node_state::NodeConfig;
-use digest::Digest;
+use crate::storage::is_corrupted;
+use chrono::Utc;
 use message_io::node::NodeHandler;
-use sha2::Sha256;
-use std::fs::File;
-use std::io::Read;
 use std::net::SocketAddr;
-use std::ops::Add;
-use tracing::trace;
+use tracing::{error, trace};
 
 pub fn handle_forwarded_get(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     addr: SocketAddr,
+    request_id: RequestId,
     key: String,
 ) {
-    let endpoint = get_ws_endpoint(handler, config, addr);
-    let message = ServerSignals::SendMessageToUser(endpoint, get_from_key(handler, config, addr, key));
+    let endpoint = get_endpoint(handler, config, addr);
+    let response = get_from_key(handler, config, addr, request_id, key);
+    let message = ServerSignals::SendMessageToUser(endpoint, request_id, response);
     handler.signals().send(message);
 }
 
@@ -28,15 +29,20 @@ pub fn get_from_key(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     addr: SocketAddr,
+    request_id: RequestId,
     key: String,
 ) -> ServerToUserMessage {
-    match handle_user_get(handler, config, key.clone(), addr) {
-        Ok(file) => ServerToUserMessage::RequestedFile(file),
+    match handle_user_get(handler, config, key.clone(), addr, request_id) {
+        Ok((file, version)) => ServerToUserMessage::RequestedFile(file, version),
         Err(e) => match e {
             GetError::ForwardingRequest(addr) => ServerToUserMessage::ForwarderTo(addr),
-            GetError::ErrorRetrievingFile => ServerToUserMessage::InternalServerError,
+            GetError::ErrorRetrievingFile
+            | GetError::WritingOutput(_)
+            | GetError::Timeout
+            | GetError::UnexpectedAnswer => ServerToUserMessage::InternalServerError,
             GetError::NotFound => ServerToUserMessage::FileNotFound(key),
             GetError::HexConversion => ServerToUserMessage::HexConversionNotValid(key),
+            GetError::Corrupted => ServerToUserMessage::CorruptedFile(key),
         },
     }
 }
@@ -46,69 +52,65 @@ fn handle_user_get(
     config: &mut NodeConfig,
     key: String,
     addr: SocketAddr,
-) -> Result<common::File, GetError> {
-    trace!("Handling user get");
+    request_id: RequestId,
+) -> Result<(common::File, Version), GetError> {
+    trace!("Handling user get, request {request_id} of {addr}");
 
-    if hex::decode(key.clone()).is_err() {
+    let Some(digested_file_name) = ChordId::from_hex(&key) else {
         return Err(GetError::HexConversion);
-    }
-
-    let digested_file_name = hex::decode(key.clone()).unwrap();
-    if !config.finger_table.is_empty() {
-        let successor = Sha256::digest(config.finger_table[0].to_string().as_bytes()).to_vec();
-
-        if !(digested_file_name > config.id && (digested_file_name < successor || config.id > successor))
-            && !(config.id > successor && digested_file_name < successor)
-            && !config.finger_table.is_empty()
-        {
-            let forwarding_index = binary_search(config, &digested_file_name); // todo check code duplication with put
-            let forwarding_address = config.finger_table[forwarding_index];
-
-            let forwarding_endpoint = get_ws_endpoint(handler, config, forwarding_address);
+    };
 
+    // A copy of a key of a silent predecessor is served here rather than forwarded to it
+    let replica_of_silent_predecessor =
+        predecessor_looks_down(config) && matches!(config.storage.metadata(&digested_file_name), Ok(Some(_)));
+    if replica_of_silent_predecessor {
+        trace!("Predecessor looks down, reading {key} from the local copy");
+    }
+    if let Some(forwarding_address) = next_hop(config, &digested_file_name).filter(|_| !replica_of_silent_predecessor) {
+        let owned_by_successor =
+            digested_file_name.in_half_open_interval(&config.id, &ChordId::from_addr(&forwarding_address));
+        if let Some(replica) = replica_of_failed_successor(config).filter(|_| owned_by_successor) {
+            trace!("Successor {forwarding_address} looks down, reading {key} from {replica}");
+            let replica_endpoint = get_endpoint(handler, config, replica);
             handler.signals().send(ServerSignals::ForwardMessage(
-                forwarding_endpoint,
-                Message::ChordMessage(ChordMessage::ForwardedGet(addr, key)),
-            ));
-
-            return Err(GetError::ForwardingRequest(
-                config.finger_table[forwarding_index].to_string(),
+                replica_endpoint,
+                Message::ChordMessage(ChordMessage::ReplicaGet(addr, request_id, key)),
             ));
+            return Err(GetError::ForwardingRequest(replica.to_string()));
         }
-    }
-
-    if !config.saved_files.contains_key(&key) {
-        trace!("No such a file");
-        return Err(GetError::NotFound);
-    }
-
-    let file_name = config.saved_files.get(&key).unwrap();
-
-    let file_path = SERVER_FOLDER
-        .to_string()
-        .add(&config.self_addr.port().to_string())
-        .add("/")
-        .add(&key);
 
-    let buffer = get_file_bytes(file_path);
+        let forwarding_endpoint = get_endpoint(handler, config, forwarding_address);
 
-    let file = common::File {
-        name: file_name.to_string(),
-        buffer,
-    };
-
-    trace!("returning {}", file_name);
-    Ok(file)
-}
-
-pub fn get_file_bytes(file_path: String) -> Vec<u8> {
-    trace!("{file_path}");
+        handler.signals().send(ServerSignals::ForwardMessage(
+            forwarding_endpoint,
+            Message::ChordMessage(ChordMessage::ForwardedGet(addr, request_id, key)),
+        ));
 
-    let file = File::open(file_path);
-
-    let mut buffer = Vec::new();
-
-    let _ = file.unwrap().read_to_end(&mut buffer); //todo check that works fine
+        return Err(GetError::ForwardingRequest(forwarding_address.to_string()));
+    }
 
-    buffer
+    match get_or_quarantine(handler, config, &digested_file_name) {
+        Ok(Some((_, metadata))) if metadata.is_expired(Utc::now()) => {
+            trace!("{key} expired");
+            Err(GetError::NotFound)
+        }
+        Ok(Some((file, _))) if !file.verify() => {
+            error!("Content of {} does not match its key", file.name);
+            quarantine(handler, config, digested_file_name);
+            Err(GetError::Corrupted)
+        }
+        Ok(Some((file, metadata))) => {
+            trace!("returning {} at version {}", file.name, metadata.version);
+            Ok((file, metadata.version))
+        }
+        Ok(None) => {
+            trace!("No such a file");
+            Err(GetError::NotFound)
+        }
+        Err(e) if is_corrupted(&e) => Err(GetError::Corrupted),
+        Err(e) => {
+            error!("ERROR {:?} reading {key}", e);
+            Err(GetError::ErrorRetrievingFile)
+        }
+    }
 }
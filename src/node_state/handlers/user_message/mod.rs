@@ -1,9 +1,12 @@
+pub mod answered;
+pub mod delete;
 pub mod get;
 pub mod put;
 
-use crate::common::{ServerSignals, UserMessage};
+use crate::common::{PutCondition, RequestId, ServerSignals, UserMessage};
+use crate::node_state::handlers::user_message::delete::delete_from_key;
 use crate::node_state::handlers::user_message::get::get_from_key;
-use crate::node_state::handlers::user_message::put::put_user_file;
+use crate::node_state::handlers::user_message::put::{expiration, put_user_file};
 use crate::node_state::NodeConfig;
 use message_io::network::Endpoint;
 use message_io::node::NodeHandler;
@@ -12,12 +15,34 @@ pub fn handle_user_message(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     endpoint: Endpoint,
+    request_id: RequestId,
     message: UserMessage,
 ) {
     let message_to_send = match message {
-        UserMessage::Put(file, user_addr) => put_user_file(handler, config, file, user_addr),
-        UserMessage::Get(key, user_addr) => get_from_key(handler, config, user_addr, key),
+        UserMessage::Put(file, time_to_live, user_addr) => {
+            let expires_at = expiration(time_to_live);
+            put_user_file(
+                handler,
+                config,
+                file,
+                PutCondition::Always,
+                expires_at,
+                user_addr,
+                request_id,
+            )
+        }
+        UserMessage::CompareAndPut(file, expected, user_addr) => put_user_file(
+            handler,
+            config,
+            file,
+            PutCondition::IfVersion(expected),
+            None,
+            user_addr,
+            request_id,
+        ),
+        UserMessage::Get(key, user_addr) => get_from_key(handler, config, user_addr, request_id, key),
+        UserMessage::Delete(key, user_addr) => delete_from_key(handler, config, user_addr, request_id, key),
     };
-    let serialized = bincode::serialize(&message_to_send).unwrap();
+    let serialized = bincode::serialize(&(request_id, message_to_send)).unwrap();
     handler.network().send(endpoint, &serialized);
 }
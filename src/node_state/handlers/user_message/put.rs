@@ -1,111 +1,271 @@
 use crate::common;
 use crate::common::{
-    binary_search, get_ws_endpoint, ChordMessage, Message, ServerSignals, ServerToUserMessage, SERVER_FOLDER,
+    get_endpoint, next_hop, ChordId, ChordMessage, Message, PutCondition, RequestId, ServerSignals,
+    ServerToUserMessage, Version,
 };
 use crate::errors::PutError;
-use crate::node_state::{NodeConfig, SAVED_FILES};
-use digest::Digest;
+use crate::node_state::handlers::server_message::replication::replicate;
+use crate::node_state::handlers::user_message::delete::save_tombstone;
+use crate::node_state::NodeConfig;
+use chrono::{DateTime, TimeDelta, Utc};
 use message_io::node::NodeHandler;
-use sha2::Sha256;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io;
 use std::net::SocketAddr;
-use std::ops::Add;
-use std::path::Path;
-use std::{fs, io};
-use tracing::trace;
+use std::time::Duration;
+use tracing::{error, trace};
 
 pub fn handle_forwarded_put(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     addr: SocketAddr,
+    request_id: RequestId,
     file: common::File,
+    condition: PutCondition,
+    expires_at: Option<DateTime<Utc>>,
 ) {
-    let endpoint = get_ws_endpoint(handler, config, addr);
-    let message = ServerSignals::SendMessageToUser(endpoint, put_user_file(handler, config, file, addr));
-    handler.signals().send(message);
+    let endpoint = get_endpoint(handler, config, addr);
+    let response = put_user_file(handler, config, file, condition, expires_at, addr, request_id);
+    handler
+        .signals()
+        .send(ServerSignals::SendMessageToUser(endpoint, request_id, response));
+}
+
+/// When a file put now with `time_to_live` expires. The node receiving the put from the user fixes it, so that
+/// forwarding the put does not extend it.
+pub(crate) fn expiration(time_to_live: Option<Duration>) -> Option<DateTime<Utc>> {
+    let time_to_live = TimeDelta::from_std(time_to_live?).ok()?;
+    Utc::now().checked_add_signed(time_to_live)
 }
 
 pub fn put_user_file(
     handler: &NodeHandler<ServerSignals>,
     config: &mut NodeConfig,
     file: common::File,
+    condition: PutCondition,
+    expires_at: Option<DateTime<Utc>>,
     user_addr: SocketAddr,
+    request_id: RequestId,
 ) -> ServerToUserMessage {
-    trace!("Received file");
-    match handle_user_put(handler, file, config, user_addr) {
-        Ok(saved_key) => ServerToUserMessage::SavedKey(saved_key),
+    trace!("Received file, request {request_id} of {user_addr}");
+    if let Some(answer) = config.answered.recall(user_addr, request_id) {
+        return answer;
+    }
+    let answer = match handle_user_put(handler, file, condition, expires_at, config, user_addr, request_id) {
+        Ok((saved_key, version)) => ServerToUserMessage::SavedKey(saved_key, version),
         Err(error) => match error {
             PutError::ForwardingRequest(address) => ServerToUserMessage::ForwarderTo(address),
-            PutError::ErrorStoringFile => ServerToUserMessage::InternalServerError,
+            PutError::ErrorStoringFile | PutError::ReadingInput(_) | PutError::Timeout | PutError::UnexpectedAnswer => {
+                ServerToUserMessage::InternalServerError
+            }
+            PutError::VersionConflict(current) => ServerToUserMessage::VersionConflict(current),
+            PutError::ContentMismatch => ServerToUserMessage::ContentMismatch,
+            PutError::QuotaExceeded => ServerToUserMessage::QuotaExceeded,
         },
-    }
+    };
+    config.answered.remember(user_addr, request_id, &answer);
+    answer
 }
 
 fn handle_user_put(
     handler: &NodeHandler<ServerSignals>,
     file: common::File,
+    condition: PutCondition,
+    expires_at: Option<DateTime<Utc>>,
     config: &mut NodeConfig,
     addr: SocketAddr,
-) -> Result<String, PutError> {
-    let digested_file_name = Sha256::digest(file.name.as_bytes()).to_vec();
-    println!("{:?}", digested_file_name);
-    if !config.finger_table.is_empty() {
-        let successor = Sha256::digest(config.finger_table[0].to_string().as_bytes()).to_vec();
-        if !(digested_file_name > config.id && (digested_file_name < successor || config.id > successor))
-            && !(config.id > successor && digested_file_name < successor)
-            && !config.finger_table.is_empty()
-        {
-            let forwarding_index = binary_search(config, &digested_file_name);
-
-            let forwarding_endpoint = get_ws_endpoint(handler, config, addr);
-
-            handler.signals().send(ServerSignals::ForwardMessage(
-                forwarding_endpoint,
-                Message::ChordMessage(ChordMessage::ForwardedPut(addr, file)),
-            ));
-
-            return Err(PutError::ForwardingRequest(
-                config.finger_table[forwarding_index].to_string(),
-            ));
-        }
+    request_id: RequestId,
+) -> Result<(String, Version), PutError> {
+    if !file.verify() {
+        trace!("Rejecting {}, its content does not match", file.name);
+        return Err(PutError::ContentMismatch);
+    }
+    let digested_file_name = file.key();
+
+    if let Some(forwarding_address) = next_hop(config, &digested_file_name) {
+        let forwarding_endpoint = get_endpoint(handler, config, forwarding_address);
+
+        handler.signals().send(ServerSignals::ForwardMessage(
+            forwarding_endpoint,
+            Message::ChordMessage(ChordMessage::ForwardedPut(
+                addr, request_id, file, condition, expires_at,
+            )),
+        ));
+
+        return Err(PutError::ForwardingRequest(forwarding_address.to_string()));
     }
-    save_in_server(file, config.self_addr.port(), config).map_or(Err(PutError::ErrorStoringFile), Ok)
+    // A compare-and-put replaces the content of the file, not its expiration
+    let expires_at = match condition {
+        PutCondition::IfVersion(Some(_)) => {
+            let stored = config.storage.metadata(&digested_file_name).map_err(|e| {
+                error!("ERROR {:?} storing {digested_file_name}", e);
+                PutError::ErrorStoringFile
+            })?;
+            stored.and_then(|metadata| metadata.expires_at)
+        }
+        _ => expires_at,
+    };
+    let (saved_key, version) = save_in_server(file.clone(), condition, expires_at, config)?;
+    replicate(handler, config, &file, version, expires_at);
+    Ok((saved_key, version))
 }
 
-pub fn save_in_server(file: common::File, port: u16, config: &mut NodeConfig) -> io::Result<String> {
-    let common::File { name, buffer: data } = file;
+/// Stores `file` under its key with the next version of the key, bringing it back if it had been deleted or had
+/// expired, until `expires_at` if any. Returns the hex encoded key and the new version.
+///
+/// A content-addressed file already stored, and not expiring before `expires_at`, is not written again: the version
+/// stored is returned.
+///
+/// # Returns
+/// - `Err(PutError::VersionConflict)`: if `condition` does not hold for the current version of the key, an expired
+///   file counting as no file.
+/// - `Err(PutError::QuotaExceeded)`: if storing the file would take the node over its limits.
+/// - `Err(PutError::ErrorStoringFile)`: if the storage fails.
+pub fn save_in_server(
+    file: common::File,
+    condition: PutCondition,
+    expires_at: Option<DateTime<Utc>>,
+    config: &mut NodeConfig,
+) -> Result<(String, Version), PutError> {
+    let key = file.key();
+    let storing_error = |e: io::Error| {
+        error!("ERROR {:?} storing {key}", e);
+        PutError::ErrorStoringFile
+    };
 
-    let digested_hex_file_name = hex::encode(Sha256::digest(name.as_bytes()));
+    let stored = config.storage.metadata(&key).map_err(storing_error)?;
+    let live = stored.as_ref().filter(|metadata| !metadata.is_expired(Utc::now()));
+    let current = live.map(|metadata| metadata.version);
+    if let PutCondition::IfVersion(expected) = condition {
+        if expected != current {
+            trace!(
+                "Version conflict on {key}: expected {:?}, current {:?}",
+                expected,
+                current
+            );
+            return Err(PutError::VersionConflict(current));
+        }
+    }
 
-    let destination = &(SERVER_FOLDER
-        .to_string()
-        .add(&port.to_string())
-        .add("/")
-        .add(&digested_hex_file_name));
-    let path = Path::new(destination);
+    if let Some(live) = live.filter(|live| file.content_key().is_some() && outlives(live.expires_at, expires_at)) {
+        trace!("{key} already stored, deduplicated");
+        return Ok((key.to_hex(), live.version));
+    }
 
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+    let usage = config.storage.usage().map_err(storing_error)?;
+    let replaced = stored.as_ref().map_or(0, |metadata| metadata.size);
+    let bytes = usage.bytes.saturating_sub(replaced) + file.buffer.len() as u64;
+    let objects = usage.objects + u64::from(stored.is_none());
+    if config.max_bytes.is_some_and(|max| bytes > max) || config.max_objects.is_some_and(|max| objects > max) {
+        trace!("Rejecting {key}, the node would store {bytes} bytes in {objects} objects");
+        return Err(PutError::QuotaExceeded);
     }
 
-    let mut file = File::create(destination)?;
-    file.write_all(&data)?;
-    file.flush()?;
+    // Versions go on from an expired or deleted file, which other nodes may still hold, so that the new file
+    // replaces it there
+    let deleted = config.tombstones.get(&key).map(|tombstone| tombstone.version);
+    let version = stored
+        .map(|metadata| metadata.version)
+        .max(deleted)
+        .unwrap_or(Version::UNVERSIONED)
+        .next(config.id);
+    config
+        .storage
+        .put(key, file, version, expires_at)
+        .map_err(storing_error)?;
+    config.tombstones.remove(&key);
+    Ok((key.to_hex(), version))
+}
 
-    if !config.saved_files.contains_key(&digested_hex_file_name) {
-        append_in_saved_files_file(port, digested_hex_file_name.clone(), name.clone())?;
+/// Whether a file expiring at `stored` lasts at least as long as one expiring at `requested`.
+fn outlives(stored: Option<DateTime<Utc>>, requested: Option<DateTime<Utc>>) -> bool {
+    match (stored, requested) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(stored), Some(requested)) => stored >= requested,
     }
-    config.saved_files.insert(digested_hex_file_name.clone(), name);
-    trace!("File stored successfully");
-    Ok(digested_hex_file_name)
 }
 
-fn append_in_saved_files_file(port: u16, digested_hex_file: String, file_name: String) -> io::Result<()> {
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(SERVER_FOLDER.to_string() + port.to_string().as_str() + "/" + SAVED_FILES)?;
+/// Stores a copy pushed by the node responsible for the file, unless the local copy or a delete of the key is more
+/// recent. A copy of the same version replaces the local one, the node responsible for the file holding the
+/// reference content.
+pub(crate) fn save_replica(
+    file: common::File,
+    version: Version,
+    expires_at: Option<DateTime<Utc>>,
+    config: &mut NodeConfig,
+) -> io::Result<()> {
+    let key = checked_key(&file)?;
+    if config
+        .tombstones
+        .get(&key)
+        .is_some_and(|tombstone| tombstone.version >= version)
+    {
+        trace!("Dropping replica of {key}, it was deleted");
+        return Ok(());
+    }
+    if config
+        .storage
+        .metadata(&key)?
+        .is_some_and(|metadata| metadata.version > version)
+    {
+        trace!("Dropping replica of {key}, version {version} is older than the stored one");
+        return Ok(());
+    }
+    config.storage.put(key, file, version, expires_at)?;
+    config.tombstones.remove(&key);
+    Ok(())
+}
 
-    writeln!(file, "{}", digested_hex_file + ":" + file_name.as_str())?;
+/// Stores a file handed over by another node, unless it was deleted here by a more recent delete, it expired, or the
+/// local copy is at least as recent.
+pub(crate) fn save_handed_over(
+    file: common::File,
+    version: Version,
+    expires_at: Option<DateTime<Utc>>,
+    config: &mut NodeConfig,
+) -> io::Result<()> {
+    let key = checked_key(&file)?;
+    if config
+        .tombstones
+        .get(&key)
+        .is_some_and(|tombstone| tombstone.version >= version)
+    {
+        trace!("Dropping handed over file {key}, it was deleted");
+        return Ok(());
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        trace!("Dropping handed over file {key}, it expired");
+        return Ok(());
+    }
+    if config
+        .storage
+        .metadata(&key)?
+        .is_some_and(|metadata| metadata.version >= version)
+    {
+        trace!("Dropping handed over file {key}, version {version} is not newer");
+        return Ok(());
+    }
+    config.storage.put(key, file, version, expires_at)?;
+    config.tombstones.remove(&key);
     Ok(())
 }
+
+/// Key of a file received from another node, after checking the content of a content-addressed one.
+fn checked_key(file: &common::File) -> io::Result<ChordId> {
+    if !file.verify() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("content of {} does not match its key", file.name),
+        ));
+    }
+    Ok(file.key())
+}
+
+/// Records the tombstones handed over by another node, except for the keys stored again since.
+pub(crate) fn save_handed_over_tombstones(tombstones: Vec<(ChordId, Version)>, config: &mut NodeConfig) {
+    for (key, version) in tombstones {
+        if let Err(e) = save_tombstone(&key, version, config) {
+            error!("ERROR {:?} recording the tombstone of {key}", e);
+        }
+    }
+}
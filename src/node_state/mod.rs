@@ -1,69 +1,133 @@
+mod builder;
+mod finger_table;
+mod handle;
 mod handlers;
 mod test;
 
+pub use builder::NodeBuilder;
+pub use handle::{NodeHandle, NodeStatus};
+
 use crate::common::ChordMessage::{self};
-use crate::common::{Message, ServerSignals, SERVER_FOLDER};
+use crate::common::{ChordId, Message, ServerSignals};
+use crate::node_state::finger_table::FingerTable;
 use crate::node_state::handlers::event::{handle_net_event, handle_server_signal};
+use crate::node_state::handlers::server_message::handoff::Handoff;
+use crate::node_state::handlers::server_message::leave::Leave;
+use crate::node_state::handlers::server_message::replication::ReplicatedRanges;
+use crate::node_state::handlers::user_message::answered::AnsweredRequests;
+use crate::storage::{StorageBackend, Tombstone};
 use chrono::{DateTime, TimeDelta, Utc};
 use message_io::network::{Endpoint, SendStatus, Transport};
-use message_io::node::{self, NodeEvent, NodeHandler, NodeListener};
-use sha2::{Digest, Sha256};
+use message_io::node::{NodeEvent, NodeHandler, NodeListener};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
 use std::net::{IpAddr, SocketAddr};
-use std::path::Path;
+use std::path::PathBuf;
 use std::time::Duration;
-use std::{fs, io};
-use tracing::{error, info, trace};
-
-const SAVED_FILES: &str = "saved_files.txt";
-const ID_BYTES: usize = 32;
-const FINGER_TABLE_SIZE: usize = 255;
-
-const MAXIMUM_DURATION: Duration = Duration::from_secs(320);
+use std::{io, thread};
+use tracing::{info, trace};
 
-const HEARTBEAT_TIMEOUT: TimeDelta = TimeDelta::seconds(30);
-
-const HEART_BEAT: Duration = Duration::from_secs(5);
 pub struct NodeState {
     handler: NodeHandler<ServerSignals>,
     listener: NodeListener<ServerSignals>,
     config: NodeConfig,
 }
 
+/// Handle to ask a running node to leave the ring gracefully.
+///
+/// Once the successor stored the files of the node and both neighbours acknowledged, the `run` loop of the node
+/// returns. If the successor fails to store a file, the node stays in the ring.
+#[derive(Clone)]
+pub struct LeaveHandle {
+    handler: NodeHandler<ServerSignals>,
+}
+
+impl LeaveHandle {
+    pub fn leave(&self) {
+        self.handler.signals().send(ServerSignals::Leave());
+    }
+}
+
 pub struct NodeConfig {
-    pub(crate) id: Vec<u8>,
+    pub(crate) id: ChordId,
     /// The node's own address.
     pub(crate) self_addr: SocketAddr,
-    /// Maps hashed key to file name.
-    pub(crate) saved_files: HashMap<String, String>,
-    ///List of successors node
-    pub(crate) finger_table: Vec<SocketAddr>,
-
-    pub(crate) successors_cache: Vec<SocketAddr>,
+    /// Files the node is responsible for.
+    pub(crate) storage: Box<dyn StorageBackend>,
+    /// Keys deleted by the users, so that handed over copies do not bring them back. Kept in the storage as well.
+    pub(crate) tombstones: HashMap<ChordId, Tombstone>,
+    /// How long a tombstone is kept.
+    pub(crate) tombstone_lifetime: TimeDelta,
+    /// Recent answers to the puts and deletes of the users, replayed to their retries.
+    pub(crate) answered: AnsweredRequests,
+    /// Time interval between two rounds of checking stored files against their checksum.
+    pub(crate) scrub_interval: Duration,
+    /// Last key checked by the scrubber, the next round continues from there.
+    pub(crate) scrub_cursor: ChordId,
+    /// Time interval between two sweeps of the expired files.
+    pub(crate) expiration_interval: Duration,
+    /// Total size of the stored files above which puts from users are rejected, `None` for no limit.
+    pub(crate) max_bytes: Option<u64>,
+    /// Number of stored files above which puts from users are rejected, `None` for no limit.
+    pub(crate) max_objects: Option<u64>,
+    /// Files being handed over to the predecessor, `None` when there are none.
+    pub(crate) handoff: Option<Handoff>,
+    /// Where the keys of an unfinished handoff are recorded, so that it resumes after a restart. `None` when the
+    /// node does not store its files in a data directory.
+    pub(crate) handoff_journal: Option<PathBuf>,
+    /// Successors of `id + 2^i`, the first finger is the successor of the node
+    pub(crate) finger_table: FingerTable,
+
+    /// The first `successor_list_length` successors of the node, starting from the immediate one.
+    pub(crate) successor_list: Vec<SocketAddr>,
+
+    pub(crate) successor_list_length: usize,
+    /// Number of nodes storing each key: the node responsible for it and its first `replication_factor - 1`
+    /// successors.
+    pub(crate) replication_factor: usize,
+    /// Successors that received a copy of the keys of the node.
+    pub(crate) replicas: Vec<SocketAddr>,
+    /// Predecessor of the node when its keys were last copied to `replicas`.
+    pub(crate) replicated_predecessor: Option<SocketAddr>,
+    /// Ranges of keys the node holds copies of for other nodes.
+    pub(crate) replicated_ranges: ReplicatedRanges,
 
     pub(crate) last_modified: DateTime<Utc>,
+    /// When the oldest unanswered ping was sent to each backup entry of the successor list.
+    pub(crate) successor_pings: HashMap<SocketAddr, DateTime<Utc>>,
+    /// How long the successor can stay silent before being considered failed.
+    pub(crate) successor_timeout: TimeDelta,
+    /// Transport of the requests between nodes and from the users.
+    pub(crate) transport: Transport,
+    /// Transport of heartbeats and pings.
+    pub(crate) liveness_transport: Transport,
 
-    pub(crate) known_endpoints_ws: HashMap<SocketAddr, Endpoint>,
+    pub(crate) known_endpoints: HashMap<SocketAddr, Endpoint>,
 
-    pub(crate) known_endpoints_udp: HashMap<SocketAddr, Endpoint>,
+    pub(crate) known_liveness_endpoints: HashMap<SocketAddr, Endpoint>,
 
     pub(crate) predecessor: Option<SocketAddr>,
+    /// When the oldest unanswered ping was sent to the predecessor.
+    pub(crate) predecessor_ping: Option<DateTime<Utc>>,
+    /// How long the predecessor can leave a ping unanswered before being considered failed.
+    pub(crate) predecessor_timeout: TimeDelta,
+    /// Progress of the leave of this node, `None` while the node is not leaving.
+    pub(crate) leave: Option<Leave>,
+    /// How long a leaving node waits for its successor to store its files, or for the acknowledgements of its
+    /// neighbours.
+    pub(crate) leave_timeout: Duration,
+    /// Time interval between heartbeats.
+    pub(crate) heartbeat_interval: Duration,
     /// Time interval between gossip rounds.
     gossip_interval: Duration,
+    /// Upper bound of `gossip_interval`.
+    maximum_gossip_interval: Duration,
 }
 
 impl NodeState {
-    /// Creates a new instance of the struct, initializing the network handler, listener, and node configuration.
+    /// Creates a node listening on `ip:port` with the default configuration, see [`NodeBuilder`] to change it.
     ///
-    /// This function performs the following operations:
-    /// 1. Initializes a network handler and a listener by calling `node::split()`.
-    /// 2. Configures the handler to listen for WebSocket (`Ws`) and UDP (`Udp`) connections on the specified address and port.
-    /// 3. Calculates a unique identifier for the node based on the `SocketAddr` (IP and port) using a SHA-256 hash.
-    /// 4. Ensures the folder structure for storing saved files exists, creating it if necessary.
-    /// 5. Loads previously saved files from the storage folder, or initializes an empty list if no saved files are found.
-    /// 6. Constructs the node configuration, including the finger table and other parameters, and initializes the struct.
+    /// The node listens for WebSocket (`Ws`) and UDP (`Udp`) connections, and loads the files previously stored in
+    /// `server/<ip>-<port>/`.
     ///
     /// # Parameters
     /// - `ip`: An `IpAddr` (e.g., `Ipv4Addr` or `Ipv6Addr`) representing the IP address where the node will listen for incoming connections.
@@ -73,62 +137,47 @@ impl NodeState {
     /// - `Ok(Self)`: Returns an instance of the struct if the initialization is successful.
     /// - `Err(io::Error)`: Returns an I/O error if the handler fails to bind to the specified address and port.
     ///
-    ///
-    ///
     /// # Example
-    /// ```rust
+    /// ```rust,no_run
     /// use std::net::{IpAddr, Ipv4Addr};
     /// use std::io;
     ///
-    ///     use DHTchord::node_state::{NodeConfig, NodeState};
+    /// use DHTchord::node_state::NodeState;
+    ///
     /// let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-    ///     let port = 8080;
+    /// let port = 8080;
     ///
-    ///     let node = NodeState::new(ip, port)?;
+    /// let node = NodeState::new(ip, port)?;
+    /// # Ok::<(), io::Error>(())
     /// ```
-    ///
-    ///
-    /// # Key Components in the Configuration
-    /// - `id`: A unique identifier for the node, calculated using SHA-256 of the node's address.
-    /// - `self_addr`: The address (`SocketAddr`) where the node is listening.
-    /// - `saved_files`: A collection of files loaded from the storage folder, used for file management.
-    /// - `finger_table`: An empty vector representing the initial finger table for the node (used in distributed systems like Chord).
-    /// - `gossip_interval`: The interval for gossip-based communication, set to 5 seconds.
-
     pub fn new(ip: IpAddr, port: u16) -> Result<Self, io::Error> {
-        let (handler, listener) = node::split();
-        let self_addr = SocketAddr::new(ip, port);
-        let id = Sha256::digest(self_addr.to_string().as_bytes()).to_vec();
+        Self::builder(SocketAddr::new(ip, port)).build()
+    }
 
-        handler.network().listen(Transport::Ws, self_addr)?;
-        handler.network().listen(Transport::Udp, self_addr)?;
+    /// Starts the configuration of a node listening on `bind_addr`, see [`NodeBuilder`].
+    pub fn builder(bind_addr: SocketAddr) -> NodeBuilder {
+        NodeBuilder::new(bind_addr)
+    }
 
-        if !saved_file_folder_exist(port) {
-            if let Err(x) = create_saved_file_folder(port) {
-                error!("ERROR {:?} trying to create the file for saved_files record", x);
-            }
+    /// Returns a handle that can make the node leave the ring once it is running.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// use std::thread;
+    /// use DHTchord::node_state::NodeState;
+    ///
+    /// let node = NodeState::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080).unwrap();
+    /// let leave_handle = node.leave_handle();
+    /// let running = thread::spawn(move || node.run());
+    ///
+    /// leave_handle.leave();
+    /// running.join().unwrap();
+    /// ```
+    pub fn leave_handle(&self) -> LeaveHandle {
+        LeaveHandle {
+            handler: self.handler.clone(),
         }
-
-        let saved_files = load_from_folder(port).unwrap_or_default();
-
-        let config = NodeConfig {
-            id,
-            self_addr,
-            saved_files,
-            finger_table: vec![],
-            successors_cache: vec![],
-            last_modified: Utc::now(),
-            known_endpoints_ws: Default::default(),
-            known_endpoints_udp: Default::default(),
-            predecessor: None,
-            gossip_interval: Duration::from_secs(5),
-        };
-
-        Ok(Self {
-            handler,
-            listener,
-            config,
-        })
     }
 
     /// Connects to a remote node in the Chord network and starts the main event loop for the node.
@@ -139,7 +188,7 @@ impl NodeState {
     ///
     ///
     /// # Example
-    /// ```rust
+    /// ```rust,no_run
     /// use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     /// use DHTchord::node_state::NodeState;
     ///
@@ -154,21 +203,76 @@ impl NodeState {
     /// - The `finger_table_map` is updated with the remote node's address and endpoint, facilitating routing and communication within the Chord network.
     ///
     pub fn connect_and_run(mut self, socket_addr: SocketAddr) {
+        self.connect(socket_addr);
+        self.run();
+    }
+
+    /// Same as [`NodeState::connect_and_run`], but the event loop runs on a background thread.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    /// use DHTchord::node_state::NodeState;
+    ///
+    /// let node = NodeState::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080).unwrap();
+    /// let remote_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), 8080);
+    /// let handle = node.connect_and_spawn(remote_addr).unwrap();
+    ///
+    /// println!("{:?}", handle.query());
+    /// handle.shutdown();
+    /// handle.join().unwrap();
+    /// ```
+    pub fn connect_and_spawn(mut self, socket_addr: SocketAddr) -> Result<NodeHandle, io::Error> {
+        self.connect(socket_addr);
+        self.spawn()
+    }
+
+    /// Sends the join request to `socket_addr`.
+    fn connect(&mut self, socket_addr: SocketAddr) {
         let message = Message::ChordMessage(ChordMessage::Join(self.config.self_addr));
 
         let serialized = bincode::serialize(&message).unwrap();
 
-        let (endpoint, _) = self.handler.network().connect_sync(Transport::Ws, socket_addr).unwrap();
+        let (endpoint, _) = self
+            .handler
+            .network()
+            .connect_sync(self.config.transport, socket_addr)
+            .unwrap();
 
-        self.config.known_endpoints_ws.insert(socket_addr, endpoint);
+        self.config.known_endpoints.insert(socket_addr, endpoint);
 
         self.config.last_modified = Utc::now();
 
         while self.handler.network().send(endpoint, &serialized) == SendStatus::ResourceNotAvailable {
             trace!("Waiting for response...");
         }
+    }
 
-        self.run();
+    /// Starts the main event loop of the node on a background thread, see [`NodeState::run`].
+    ///
+    /// The returned [`NodeHandle`] can query the running node, make it leave the ring, stop it and wait for it.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// use DHTchord::node_state::NodeState;
+    ///
+    /// let node = NodeState::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080).unwrap();
+    /// let handle = node.spawn().unwrap();
+    ///
+    /// let status = handle.query().unwrap();
+    /// println!("{} stores {} keys", status.address, status.keys.len());
+    ///
+    /// handle.shutdown();
+    /// handle.join().unwrap();
+    /// ```
+    pub fn spawn(self) -> Result<NodeHandle, io::Error> {
+        let handler = self.handler.clone();
+        let address = self.config.self_addr;
+        let thread = thread::Builder::new()
+            .name(format!("chord-node-{address}"))
+            .spawn(move || self.run())?;
+        Ok(NodeHandle::new(handler, thread, address))
     }
 
     /// Starts the main event loop for the node, handling network events and periodic stabilization tasks.
@@ -178,13 +282,13 @@ impl NodeState {
     ///
     /// # Behavior
     /// - **Signal Management:** A stabilization signal is scheduled at regular intervals defined by `self.config.gossip_interval`.
-    /// - **Event Processing:** Listens indefinitely for events, ensuring that the node remains active in the Chord network and responds to network or scheduled events.
+    /// - **Event Processing:** Listens for events until the node leaves the ring through a [`LeaveHandle`], ensuring that the node remains active in the Chord network and responds to network or scheduled events.
     /// - **Handlers:** Delegates event-specific logic to:
     ///   - `handle_net_event` for network-related tasks.
     ///   - `handle_server_signal` for signal-related tasks.
     ///
     /// # Example
-    /// ```rust
+    /// ```rust,no_run
     /// use std::net::{IpAddr, Ipv4Addr};
     /// use DHTchord::node_state::NodeState;
     /// let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
@@ -203,7 +307,19 @@ impl NodeState {
 
         self.handler
             .signals()
-            .send_with_timer(ServerSignals::HeartBeat(), HEART_BEAT);
+            .send_with_timer(ServerSignals::HeartBeat(), self.config.heartbeat_interval);
+
+        self.handler
+            .signals()
+            .send_with_timer(ServerSignals::CheckNeighbours(), self.config.heartbeat_interval);
+
+        self.handler
+            .signals()
+            .send_with_timer(ServerSignals::Scrub(), self.config.scrub_interval);
+
+        self.handler
+            .signals()
+            .send_with_timer(ServerSignals::ExpireKeys(), self.config.expiration_interval);
 
         self.listener.for_each(|event| match event {
             NodeEvent::Network(event) => handle_net_event(&self.handler, &mut self.config, event),
@@ -211,41 +327,64 @@ impl NodeState {
         });
     }
 }
-fn saved_file_folder_exist(port: u16) -> bool {
-    let folder_name = SERVER_FOLDER.to_string() + port.to_string().as_str() + "/" + SAVED_FILES;
-    Path::new(&folder_name).exists()
-}
 
-///function to save the hashmap of key-file name
-fn create_saved_file_folder(port: u16) -> io::Result<()> {
-    if !Path::new(SERVER_FOLDER).exists() {
-        fs::create_dir(SERVER_FOLDER)?;
+impl NodeConfig {
+    pub(crate) fn set_predecessor(&mut self, predecessor: SocketAddr) {
+        if self.predecessor != Some(predecessor) {
+            self.predecessor_ping = None;
+        }
+        self.predecessor = Some(predecessor);
     }
-    let folder_name = SERVER_FOLDER.to_string() + port.to_string().as_str() + "/";
 
-    if !Path::new(&folder_name).exists() {
-        fs::create_dir(&folder_name)?;
+    /// Makes `successor` the immediate successor of the node, both in the finger table and in the successor list.
+    pub(crate) fn set_successor(&mut self, successor: SocketAddr) {
+        self.finger_table.set_successor(successor);
+        self.successor_list.retain(|node| *node != successor);
+        self.successor_list.insert(0, successor);
+        self.successor_list.truncate(self.successor_list_length);
     }
 
-    let file_path = folder_name + SAVED_FILES;
-    let mut file = File::create(&file_path)?;
-    file.flush()?;
-    Ok(())
-}
-
-fn load_from_folder(port: u16) -> io::Result<HashMap<String, String>> {
-    let file_path = SERVER_FOLDER.to_string() + port.to_string().as_str() + "/" + SAVED_FILES;
-    let mut saved_files = HashMap::new();
-
-    let file = File::open(&file_path)?;
-
-    let reader = BufReader::new(file);
-
-    for line in reader.lines() {
-        if let Some((key, value)) = line?.split_once(':') {
-            saved_files.insert(key.to_string(), value.to_string());
+    /// Rebuilds the successor list from the one of the immediate successor: `successor` followed by its own list.
+    pub(crate) fn update_successor_list(&mut self, successor: SocketAddr, successors_list: Vec<SocketAddr>) {
+        let mut successor_list = vec![successor];
+        for node in successors_list {
+            if successor_list.len() == self.successor_list_length {
+                break;
+            }
+            if node == self.self_addr || successor_list.contains(&node) {
+                // The list wrapped around the ring
+                break;
+            }
+            successor_list.push(node);
         }
+        self.successor_list = successor_list;
     }
 
-    Ok(saved_files)
+    /// Drops the failed immediate successor and promotes the first entry of the successor list that is not known to
+    /// be dead, that is which did not leave a ping unanswered for longer than the successor timeout.
+    ///
+    /// Returns the new successor, if any is left.
+    pub(crate) fn promote_next_successor(&mut self) -> Option<SocketAddr> {
+        let failed = self.finger_table.successor()?;
+        self.finger_table.remove_node(&failed);
+        self.successor_list.retain(|node| *node != failed);
+
+        let now = Utc::now();
+        let dead: Vec<SocketAddr> = self
+            .successor_pings
+            .iter()
+            .filter(|(_, sent)| now.signed_duration_since(**sent) > self.successor_timeout)
+            .map(|(node, _)| *node)
+            .collect();
+        for node in dead {
+            trace!("Successor list entry {node} failed");
+            self.finger_table.remove_node(&node);
+            self.successor_list.retain(|entry| *entry != node);
+            self.successor_pings.remove(&node);
+        }
+
+        let next = *self.successor_list.first()?;
+        self.finger_table.set_successor(next);
+        Some(next)
+    }
 }
@@ -1,26 +1,69 @@
 #[cfg(test)]
 mod tests {
-    use crate::common::{ChordMessage, File, Message, ServerSignals, UserMessage, SERVER_FOLDER};
-    use crate::errors::GetError;
+    use crate::async_user::AsyncUser;
+    use crate::chunk::{Manifest, CHUNK_SIZE};
+    use crate::client::{delete_outcome, get_outcome, put_outcome};
+    use crate::common::{
+        ChordId, ChordMessage, File, Message, PutCondition, ServerSignals, ServerToUserMessage, UserMessage, Version,
+        ID_BYTES,
+    };
+    use crate::errors::{DeleteError, GetError, PutError};
+    use crate::node_state::finger_table::FingerTable;
+    use crate::node_state::handlers::event::handle_server_signal;
+    use crate::node_state::handlers::server_message::anti_entropy::REPAIR_BATCH;
+    use crate::node_state::handlers::server_message::handoff::{resume_handoff, HANDOFF_BATCH, HANDOFF_JOURNAL};
     use crate::node_state::handlers::server_message::join::handle_join;
+    use crate::node_state::handlers::server_message::leave::{start_leave, Leave};
+    use crate::node_state::handlers::server_message::replication::{
+        collect_replicas, refresh_replicas, replica_of_failed_successor, replica_set,
+    };
+    use crate::node_state::handlers::server_message::scrub::scrub;
+    use crate::node_state::handlers::server_message::stabilization::{check_predecessor, handle_stabilize_response};
+    use crate::node_state::handlers::server_message::{handle_server_message, move_files};
+    use crate::node_state::handlers::user_message::delete::{
+        delete_from_key, delete_in_server, expire_keys, expire_tombstones, save_tombstone,
+    };
     use crate::node_state::handlers::user_message::get::get_from_key;
-    use crate::node_state::handlers::user_message::put::put_user_file;
-    use crate::node_state::{NodeConfig, NodeState};
+    use crate::node_state::handlers::user_message::put::{
+        put_user_file, save_handed_over, save_in_server, save_replica,
+    };
+    use crate::node_state::{NodeConfig, NodeHandle, NodeState, NodeStatus};
+    use crate::storage::merkle::{leaf_of, object_digest, range_entries, MerkleTree, LEAVES};
+    use crate::storage::{
+        checksum, is_corrupted, FsStorage, LogStorage, MemoryStorage, Metadata, StorageBackend, Tombstone, Usage,
+    };
     use crate::user::User;
-    use digest::Digest;
+    use chrono::{TimeDelta, Utc};
+    use message_io::events::EventReceiver;
     use message_io::network::{NetEvent, SendStatus, Transport};
-    use message_io::node::{NodeEvent, NodeHandler, NodeListener};
-    use sha2::Sha256;
+    use message_io::node::{NodeEvent, NodeHandler, NodeListener, StoredNodeEvent};
+    use std::io::Write;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::ops::Add;
+    use std::path::PathBuf;
     use std::sync::mpsc::{Receiver, Sender};
+    use std::time::Duration;
     use std::{fs, thread};
     const LOCAL_IP_STR: &str = "127.0.0.1";
     const LOCAL_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
 
     // Helper function to create a test node
     fn create_test_node(port: u16) -> NodeState {
-        NodeState::new(IpAddr::from(LOCAL_IP), port).unwrap_or_else(|_| panic!())
+        NodeState::builder(SocketAddr::new(IpAddr::from(LOCAL_IP), port))
+            .data_dir(empty_test_data_dir(port))
+            .build()
+            .unwrap_or_else(|_| panic!())
+    }
+
+    /// Data directory of the test node listening on `port`, outside of the repository.
+    fn test_data_dir(port: u16) -> PathBuf {
+        std::env::temp_dir().join("dhtchord-tests").join(port.to_string())
+    }
+
+    fn empty_test_data_dir(port: u16) -> PathBuf {
+        let data_dir = test_data_dir(port);
+        let _ = fs::remove_dir_all(&data_dir);
+        data_dir
     }
 
     fn create_test_node_and_join(port: u16, port_into_join: u16) -> NodeState {
@@ -43,7 +86,7 @@ mod tests {
         tx: Sender<()>,
         handler_into_join: &NodeHandler<ServerSignals>,
         listener_into_join: NodeListener<ServerSignals>,
-        mut config_into_join: &mut NodeConfig,
+        config_into_join: &mut NodeConfig,
     ) {
         let mut join_counter = 0;
         tx.send(()).unwrap();
@@ -55,13 +98,13 @@ mod tests {
                     join_counter += 1;
                     if join_counter > 1 {
                         assert_ne!(
-                            config_into_join.finger_table.first().unwrap().port(),
+                            config_into_join.finger_table.successor().unwrap().port(),
                             config_into_join.predecessor.unwrap().port()
                         );
                     }
                     if join_counter > 2 {
                         assert_ne!(
-                            config_into_join.finger_table.first().unwrap().port(),
+                            config_into_join.finger_table.successor().unwrap().port(),
                             config_into_join.predecessor.unwrap().port()
                         );
                         handler_into_join.stop();
@@ -74,7 +117,7 @@ mod tests {
         assert!(!config_into_join.finger_table.is_empty());
         assert!(config_into_join.predecessor.is_some());
         assert_ne!(
-            config_into_join.finger_table.first().unwrap().port(),
+            config_into_join.finger_table.successor().unwrap().port(),
             config_into_join.predecessor.unwrap().port()
         );
     }
@@ -92,6 +135,9 @@ mod tests {
         } = node_into_join;
         assert!(config_into_join.finger_table.is_empty());
         assert!(config_into_join.predecessor.is_none());
+        // Joins leave the pointers of the contacted node to stabilization, the node is already part of a ring
+        config_into_join.set_successor(SocketAddr::new(IpAddr::from(LOCAL_IP), 8078));
+        config_into_join.set_predecessor(SocketAddr::new(IpAddr::from(LOCAL_IP), 8094));
         let handle_into_join = thread::spawn(move || {
             bootstrap_node(tx, &handler_into_join, listener_into_join, &mut config_into_join);
         });
@@ -102,6 +148,18 @@ mod tests {
         handle_into_join.join().unwrap();
     }
 
+    fn expect_join_reply(port: u16, port_to_join: u16, check: impl Fn(ChordMessage)) {
+        let joining_node = create_test_node_and_join(port, port_to_join);
+        joining_node.listener.for_each(|event| {
+            if let NodeEvent::Network(NetEvent::Message(_, serialized)) = event {
+                if let Message::ChordMessage(message) = bincode::deserialize(serialized).unwrap() {
+                    check(message);
+                    joining_node.handler.stop();
+                }
+            }
+        });
+    }
+
     fn joining_nodes(rx: Receiver<()>, port_to_join: u16) {
         const FIRST_PORT_JOINING: u16 = 8091;
         const SECOND_PORT_JOINING: u16 = 8081;
@@ -123,14 +181,95 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_join_routing() {
+        const PORT_TO_JOIN: u16 = 8102;
+        // Ring: 8102 -> 8129 -> ... -> 8105 -> 8102
+        const SUCCESSOR: u16 = 8129;
+        const BETWEEN_SELF_AND_SUCCESSOR: u16 = 8127;
+        const BETWEEN_PREDECESSOR_AND_SELF: u16 = 8115;
+        const FURTHER_IN_THE_RING: u16 = 8104;
+        let NodeState {
+            handler,
+            listener,
+            mut config,
+        } = create_test_node(PORT_TO_JOIN);
+        config.set_successor(SocketAddr::new(IpAddr::from(LOCAL_IP), SUCCESSOR));
+        config.set_predecessor(SocketAddr::new(IpAddr::from(LOCAL_IP), 8105));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle_into_join = thread::spawn(move || {
+            let mut join_counter = 0;
+            tx.send(()).unwrap();
+            listener.for_each(|event| {
+                if let NodeEvent::Network(NetEvent::Message(endpoint, serialized)) = event {
+                    if let Message::ChordMessage(ChordMessage::Join(address)) =
+                        bincode::deserialize(serialized).unwrap()
+                    {
+                        handle_join(&handler, &mut config, endpoint, address);
+                        join_counter += 1;
+                        if join_counter > 2 {
+                            handler.stop();
+                        }
+                    }
+                }
+            });
+            // Joins do not touch the pointers of the contacted node, stabilization does
+            assert_eq!(config.finger_table.successor().unwrap().port(), SUCCESSOR);
+            assert_eq!(config.predecessor.unwrap().port(), 8105);
+        });
+
+        rx.recv().unwrap();
+        expect_join_reply(BETWEEN_SELF_AND_SUCCESSOR, PORT_TO_JOIN, |message| {
+            assert!(matches!(message, ChordMessage::AddSuccessor(successor) if successor.port() == SUCCESSOR));
+        });
+        expect_join_reply(BETWEEN_PREDECESSOR_AND_SELF, PORT_TO_JOIN, |message| {
+            assert!(matches!(message, ChordMessage::AddSuccessor(successor) if successor.port() == PORT_TO_JOIN));
+        });
+        expect_join_reply(FURTHER_IN_THE_RING, PORT_TO_JOIN, |message| {
+            assert!(
+                matches!(message, ChordMessage::ForwardJoin(forward_address) if forward_address.port() == SUCCESSOR)
+            );
+        });
+        handle_into_join.join().unwrap();
+    }
+
+    #[test]
+    fn test_stabilize_and_notify() {
+        const NODE_PORT: u16 = 8221;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let NodeState {
+            handler, mut config, ..
+        } = create_test_node(NODE_PORT);
+        let (endpoint, _) = handler.network().connect(Transport::Udp, address(8229)).unwrap();
+
+        // The predecessor of the successor sits between the two nodes
+        config.set_successor(address(8228));
+        handle_stabilize_response(&handler, &mut config, address(8228), Some(address(8235)));
+        assert_eq!(config.finger_table.successor(), Some(address(8235)));
+
+        // The predecessor of the successor is behind this node
+        handle_stabilize_response(&handler, &mut config, address(8235), Some(address(8222)));
+        assert_eq!(config.finger_table.successor(), Some(address(8235)));
+
+        // Stale answer from a node that is no longer the successor
+        handle_stabilize_response(&handler, &mut config, address(8228), Some(address(8231)));
+        assert_eq!(config.finger_table.successor(), Some(address(8235)));
+
+        let mut notify = |address| {
+            handle_server_message(&handler, &mut config, endpoint, ChordMessage::NotifySuccessor(address));
+            config.predecessor
+        };
+        assert_eq!(notify(address(8224)), Some(address(8224)));
+        assert_eq!(notify(address(8231)), Some(address(8231)));
+        assert_eq!(notify(address(8226)), Some(address(8231)));
+        handler.stop();
+    }
+
     #[test]
     fn test_user_put() {
         const PORT_TO_SAVE: u16 = 9001;
         const USER_PORT: u16 = 9000;
-        let path = &(SERVER_FOLDER.to_string().add(&PORT_TO_SAVE.to_string()));
-        let file_path = std::path::Path::new(path);
-        let _ = fs::remove_dir(file_path);
-
         let node_into_join = create_test_node(PORT_TO_SAVE);
         let (tx, rx) = std::sync::mpsc::channel();
         let NodeState {
@@ -143,24 +282,24 @@ mod tests {
             listener_into_join.for_each(|event| {
                 if let NodeEvent::Network(NetEvent::Message(endpoint, serialized)) = event {
                     let message = bincode::deserialize(serialized).unwrap();
-                    if let Message::UserMessage(UserMessage::Put(file, user_address)) = message {
-                        let digested_hex_file_name = hex::encode(Sha256::digest(file.name.as_bytes()));
-                        let path = &(SERVER_FOLDER
-                            .to_string()
-                            .add(&PORT_TO_SAVE.to_string())
-                            .add("/")
-                            .add(&digested_hex_file_name));
-                        let file_path = std::path::Path::new(path);
-                        assert!(!(file_path.exists() && file_path.is_file()));
+                    if let Message::UserMessage(request_id, UserMessage::Put(file, _, user_address)) = message {
+                        let key = ChordId::digest(file.name.as_bytes());
+                        assert!(config_into_join.storage.get(&key).unwrap().is_none());
 
-                        let server_to_user =
-                            put_user_file(&handler_into_join, &mut config_into_join, file, user_address);
-                        let serialized = bincode::serialize(&server_to_user).unwrap();
+                        let server_to_user = put_user_file(
+                            &handler_into_join,
+                            &mut config_into_join,
+                            file,
+                            PutCondition::Always,
+                            None,
+                            user_address,
+                            request_id,
+                        );
+                        let serialized = bincode::serialize(&(request_id, server_to_user)).unwrap();
                         handler_into_join.network().send(endpoint, &serialized);
 
-                        let file_path = std::path::Path::new(path);
-                        assert!(file_path.exists() && file_path.is_file());
-                        let _ = fs::remove_file(file_path);
+                        assert!(config_into_join.storage.get(&key).unwrap().is_some());
+                        let _ = config_into_join.storage.delete(&key);
                         handler_into_join.stop();
                     }
                 }
@@ -206,14 +345,22 @@ mod tests {
             listener_into_join.for_each(|event| {
                 if let NodeEvent::Network(NetEvent::Message(endpoint, serialized)) = event {
                     let message = bincode::deserialize(serialized).unwrap();
-                    if let Message::UserMessage(UserMessage::Put(file, user_address)) = message {
-                        let server_to_user =
-                            put_user_file(&handler_into_join, &mut config_into_join, file, user_address);
-                        let serialized = bincode::serialize(&server_to_user).unwrap();
+                    if let Message::UserMessage(request_id, UserMessage::Put(file, _, user_address)) = message {
+                        let server_to_user = put_user_file(
+                            &handler_into_join,
+                            &mut config_into_join,
+                            file,
+                            PutCondition::Always,
+                            None,
+                            user_address,
+                            request_id,
+                        );
+                        let serialized = bincode::serialize(&(request_id, server_to_user)).unwrap();
                         handler_into_join.network().send(endpoint, &serialized);
-                    } else if let Message::UserMessage(UserMessage::Get(key, user_address)) = message {
-                        let server_to_user = get_from_key(&handler_into_join, &mut config_into_join, user_address, key);
-                        let serialized = bincode::serialize(&server_to_user).unwrap();
+                    } else if let Message::UserMessage(request_id, UserMessage::Get(key, user_address)) = message {
+                        let server_to_user =
+                            get_from_key(&handler_into_join, &mut config_into_join, user_address, request_id, key);
+                        let serialized = bincode::serialize(&(request_id, server_to_user)).unwrap();
                         handler_into_join.network().send(endpoint, &serialized);
                     }
                 }
@@ -275,6 +422,23 @@ mod tests {
         assert_eq!(result.err().unwrap(), GetError::NotFound);
     }
 
+    #[test]
+    fn test_unexpected_answers() {
+        let key = "b133a0c0e9bee3be20163d2ad31d6248db292aa6dcb1ee087a2aa50e0fc75ae2".to_string();
+        assert_eq!(
+            put_outcome(ServerToUserMessage::DeletedKey(key.clone())),
+            Some(Err(PutError::UnexpectedAnswer))
+        );
+        assert!(matches!(
+            get_outcome(ServerToUserMessage::DeletedKey(key.clone())),
+            Some(Err(GetError::UnexpectedAnswer))
+        ));
+        assert_eq!(
+            delete_outcome(ServerToUserMessage::SavedKey(key, version(1))),
+            Some(Err(DeleteError::UnexpectedAnswer))
+        );
+    }
+
     fn existing_key(existing_key: String, port_to_save: u16, user_port: u16) {
         let getting_user = User::new(LOCAL_IP_STR.to_string(), user_port.to_string());
         let result = getting_user.unwrap().get(
@@ -289,4 +453,1887 @@ mod tests {
         assert_eq!(result.as_ref().unwrap().name, "file_name".to_string());
         assert_eq!(result.unwrap().buffer, vec![]);
     }
+
+    fn version(counter: u64) -> Version {
+        Version {
+            counter,
+            writer: id_from_u8(0xee),
+        }
+    }
+
+    fn id_from_u8(last: u8) -> ChordId {
+        let mut bytes = [0; ID_BYTES];
+        bytes[ID_BYTES - 1] = last;
+        ChordId::from_bytes(bytes)
+    }
+
+    #[test]
+    fn test_chord_id_add_power_of_two() {
+        assert_eq!(id_from_u8(0).add_power_of_two(0), id_from_u8(1));
+        assert_eq!(id_from_u8(1).add_power_of_two(3), id_from_u8(9));
+
+        let carried = id_from_u8(0xff).add_power_of_two(0);
+        let mut expected = [0; ID_BYTES];
+        expected[ID_BYTES - 2] = 1;
+        assert_eq!(carried, ChordId::from_bytes(expected));
+
+        let top = ChordId::from_bytes([0xff; ID_BYTES]);
+        assert_eq!(top.add_power_of_two(0), id_from_u8(0));
+
+        let mut highest_bit = [0; ID_BYTES];
+        highest_bit[0] = 0x80;
+        let highest_bit = ChordId::from_bytes(highest_bit);
+        assert_eq!(highest_bit.add_power_of_two(255), id_from_u8(0));
+    }
+
+    #[test]
+    fn test_chord_id_distance() {
+        assert_eq!(id_from_u8(3).distance(&id_from_u8(10)), id_from_u8(7));
+        assert_eq!(id_from_u8(10).distance(&id_from_u8(10)), id_from_u8(0));
+
+        let top = ChordId::from_bytes([0xff; ID_BYTES]);
+        assert_eq!(top.distance(&id_from_u8(1)), id_from_u8(2));
+        let mut almost_top = [0xff; ID_BYTES];
+        almost_top[ID_BYTES - 1] = 0xfe;
+        assert_eq!(id_from_u8(1).distance(&top), ChordId::from_bytes(almost_top));
+    }
+
+    #[test]
+    fn test_chord_id_intervals() {
+        let (low, mid, high) = (id_from_u8(10), id_from_u8(20), id_from_u8(30));
+
+        assert!(mid.in_open_interval(&low, &high));
+        assert!(!low.in_open_interval(&low, &high));
+        assert!(!high.in_open_interval(&low, &high));
+        assert!(high.in_half_open_interval(&low, &high));
+        assert!(!low.in_half_open_interval(&low, &high));
+
+        // Wrapping around the top of the ring
+        let top = ChordId::from_bytes([0xff; ID_BYTES]);
+        assert!(top.in_open_interval(&high, &low));
+        assert!(id_from_u8(0).in_open_interval(&high, &low));
+        assert!(!mid.in_open_interval(&high, &low));
+        assert!(low.in_half_open_interval(&high, &low));
+
+        // A single node ring covers everything
+        assert!(mid.in_half_open_interval(&low, &low));
+        assert!(low.in_half_open_interval(&low, &low));
+        assert!(!low.in_open_interval(&low, &low));
+        assert!(mid.in_open_interval(&low, &low));
+    }
+
+    #[test]
+    fn test_chord_id_hex_round_trip() {
+        let id = ChordId::digest(b"file_name");
+        assert_eq!(ChordId::from_hex(&id.to_hex()), Some(id));
+        assert_eq!(ChordId::from_hex("nothexkey"), None);
+        assert_eq!(ChordId::from_hex("abcd"), None);
+    }
+
+    #[test]
+    fn test_finger_table_layout() {
+        let id = id_from_u8(0);
+        let mut finger_table = FingerTable::new(id);
+        assert!(finger_table.is_empty());
+        assert_eq!(finger_table.start(0), id_from_u8(1));
+        assert_eq!(finger_table.start(4), id_from_u8(16));
+        assert_eq!(finger_table.interval(2), (id_from_u8(4), id_from_u8(8)));
+        assert_eq!(finger_table.interval(255).1, id);
+
+        let successor = SocketAddr::new(IpAddr::from(LOCAL_IP), 8078);
+        finger_table.set_successor(successor);
+        assert_eq!(finger_table.successor(), Some(successor));
+        assert!(finger_table.update_start(&id_from_u8(16), successor));
+        assert!(!finger_table.update_start(&id_from_u8(17), successor));
+        assert_eq!(finger_table.nodes(), vec![successor]);
+
+        finger_table.remove_node(&successor);
+        assert!(finger_table.is_empty());
+        assert!(finger_table.nodes().is_empty());
+    }
+
+    #[test]
+    fn test_closest_preceding_finger() {
+        let near = SocketAddr::new(IpAddr::from(LOCAL_IP), 8078);
+        let far = SocketAddr::new(IpAddr::from(LOCAL_IP), 8072);
+        let (near_id, far_id) = (ChordId::from_addr(&near), ChordId::from_addr(&far));
+        let id = ChordId::from_addr(&SocketAddr::new(IpAddr::from(LOCAL_IP), 8101));
+
+        let mut finger_table = FingerTable::new(id);
+        finger_table.set_successor(near);
+        finger_table.update(200, far);
+
+        let past_far = far_id.add_power_of_two(0);
+        assert_eq!(finger_table.closest_preceding_finger(&past_far), Some(far));
+        assert_eq!(finger_table.closest_preceding_finger(&far_id), Some(near));
+        assert_eq!(finger_table.closest_preceding_finger(&near_id), None);
+    }
+
+    #[test]
+    fn test_successor_list_failover() {
+        const NODE_PORT: u16 = 8201;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(address(NODE_PORT))
+            .data_dir(empty_test_data_dir(NODE_PORT))
+            .successor_list_length(3)
+            .build()
+            .unwrap();
+        handler.stop();
+
+        config.set_successor(address(8202));
+        config.update_successor_list(address(8202), vec![address(8203), address(8204), address(8205)]);
+        assert_eq!(config.successor_list, vec![address(8202), address(8203), address(8204)]);
+
+        // The successor's list wraps around to this node
+        config.update_successor_list(address(8202), vec![address(8203), address(NODE_PORT), address(8202)]);
+        assert_eq!(config.successor_list, vec![address(8202), address(8203)]);
+
+        assert_eq!(config.promote_next_successor(), Some(address(8203)));
+        assert_eq!(config.finger_table.successor(), Some(address(8203)));
+        assert_eq!(config.successor_list, vec![address(8203)]);
+
+        assert_eq!(config.promote_next_successor(), None);
+        assert!(config.finger_table.is_empty());
+
+        // An entry that left a ping unanswered for longer than the successor timeout is skipped
+        config.set_successor(address(8202));
+        config.update_successor_list(address(8202), vec![address(8203), address(8204)]);
+        config.successor_pings.insert(
+            address(8203),
+            Utc::now() - config.successor_timeout - TimeDelta::seconds(1),
+        );
+        config.successor_pings.insert(address(8204), Utc::now());
+        assert_eq!(config.promote_next_successor(), Some(address(8204)));
+        assert_eq!(config.successor_list, vec![address(8204)]);
+    }
+
+    #[test]
+    fn test_check_predecessor_timeout() {
+        const NODE_PORT: u16 = 8211;
+        let predecessor = SocketAddr::new(IpAddr::from(LOCAL_IP), 8212);
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(SocketAddr::new(IpAddr::from(LOCAL_IP), NODE_PORT))
+            .data_dir(empty_test_data_dir(NODE_PORT))
+            .predecessor_timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+
+        config.set_predecessor(predecessor);
+        check_predecessor(&handler, &mut config);
+        assert!(config.predecessor_ping.is_some());
+
+        // Still within the timeout
+        check_predecessor(&handler, &mut config);
+        assert_eq!(config.predecessor, Some(predecessor));
+
+        config.predecessor_ping = Some(Utc::now() - TimeDelta::seconds(3));
+        check_predecessor(&handler, &mut config);
+        assert_eq!(config.predecessor, None);
+        handler.stop();
+    }
+
+    fn neighbour_of_leaving_node(node: NodeState, check: impl Fn(&NodeConfig) -> bool + Send + 'static) {
+        let NodeState {
+            handler,
+            listener,
+            mut config,
+        } = node;
+        listener.for_each(|event| match event {
+            NodeEvent::Network(NetEvent::Message(endpoint, serialized)) => {
+                if let Message::ChordMessage(message) = bincode::deserialize(serialized).unwrap() {
+                    handle_server_message(&handler, &mut config, endpoint, message);
+                    if check(&config) {
+                        handler.stop();
+                    }
+                }
+            }
+            NodeEvent::Signal(signal) => handle_server_signal(&handler, &mut config, signal),
+            NodeEvent::Network(_) => {}
+        });
+    }
+
+    #[test]
+    fn test_graceful_leave() {
+        const LEAVING_PORT: u16 = 8241;
+        const SUCCESSOR_PORT: u16 = 8242;
+        const PREDECESSOR_PORT: u16 = 8243;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+
+        let mut leaving = create_test_node(LEAVING_PORT);
+        leaving.config.set_successor(address(SUCCESSOR_PORT));
+        leaving.config.set_predecessor(address(PREDECESSOR_PORT));
+        let file = File {
+            name: "leaving_file".to_string(),
+            buffer: vec![1, 2, 3],
+        };
+        let (key, _) = save_in_server(file, PutCondition::Always, None, &mut leaving.config).unwrap();
+
+        let mut successor = create_test_node(SUCCESSOR_PORT);
+        successor.config.set_predecessor(address(LEAVING_PORT));
+        let successor_key = ChordId::from_hex(&key).unwrap();
+        let successor = thread::spawn(move || {
+            neighbour_of_leaving_node(successor, move |config| {
+                config.storage.get(&successor_key).unwrap().is_some()
+                    && config.predecessor == Some(address(PREDECESSOR_PORT))
+            })
+        });
+
+        let mut predecessor = create_test_node(PREDECESSOR_PORT);
+        predecessor.config.set_successor(address(LEAVING_PORT));
+        let predecessor = thread::spawn(move || {
+            neighbour_of_leaving_node(predecessor, move |config| {
+                config.finger_table.successor() == Some(address(SUCCESSOR_PORT))
+            })
+        });
+
+        leaving.leave_handle().leave();
+        leaving.run();
+
+        successor.join().unwrap();
+        predecessor.join().unwrap();
+        assert!(!test_data_dir(LEAVING_PORT).join(&key).exists());
+        let leaving_storage = FsStorage::open(test_data_dir(LEAVING_PORT)).unwrap();
+        assert!(leaving_storage.keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_leave_in_batches() {
+        const NODE_PORT: u16 = 8421;
+        const SUCCESSOR_PORT: u16 = 8422;
+        const PREDECESSOR_PORT: u16 = 8423;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let NodeState {
+            handler,
+            mut config,
+            listener,
+        } = create_test_node(NODE_PORT);
+        let (endpoint, _) = handler
+            .network()
+            .connect(Transport::Udp, address(SUCCESSOR_PORT))
+            .unwrap();
+        config.set_successor(address(SUCCESSOR_PORT));
+        config.set_predecessor(address(PREDECESSOR_PORT));
+        let files: Vec<File> = (0..HANDOFF_BATCH + 4)
+            .map(|index| File::blob(vec![index as u8]))
+            .collect();
+        for file in &files {
+            save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        }
+        let keys: Vec<ChordId> = files.iter().map(File::key).collect();
+        let (mut task, mut events) = listener.enqueue();
+        let timeout = Duration::from_millis(200);
+
+        // The successor failing to store a file stops the leave, the node keeps its files
+        start_leave(&handler, &mut config);
+        assert!(matches!(config.leave, Some(Leave::HandingOver(successor)) if successor == address(SUCCESSOR_PORT)));
+        let messages = queued_messages(&mut events, timeout);
+        assert!(matches!(&messages[..], [ChordMessage::HandoffOffer(_, offered)] if offered.len() == files.len()));
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffNack(address(SUCCESSOR_PORT), vec![keys[0]]),
+        );
+        assert!(config.leave.is_none());
+        assert!(config.handoff.is_none());
+        assert_eq!(config.storage.keys().unwrap().len(), files.len());
+
+        // Otherwise the files go in acknowledged batches, and the leave is announced once they are all stored
+        start_leave(&handler, &mut config);
+        queued_messages(&mut events, timeout);
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffAccept(address(SUCCESSOR_PORT), keys.clone()),
+        );
+        let mut sent = vec![];
+        for batch in [HANDOFF_BATCH, 4] {
+            let messages = queued_messages(&mut events, timeout);
+            let [ChordMessage::HandoffTransfer(_, transfer)] = &messages[..] else {
+                panic!("expected a single transfer");
+            };
+            assert_eq!(transfer.len(), batch);
+            let batch_keys: Vec<ChordId> = transfer.iter().map(|(file, _, _)| file.key()).collect();
+            sent.extend(batch_keys.iter().copied());
+            handle_server_message(
+                &handler,
+                &mut config,
+                endpoint,
+                ChordMessage::HandoffAck(address(SUCCESSOR_PORT), batch_keys),
+            );
+        }
+        sent.sort_unstable();
+        let mut expected = keys.clone();
+        expected.sort_unstable();
+        assert_eq!(sent, expected);
+        let messages = queued_messages(&mut events, timeout);
+        assert!(matches!(
+            &messages[..],
+            [ChordMessage::LeaveSuccessor(..), ChordMessage::LeavePredecessor(..)]
+        ));
+        assert!(matches!(&config.leave, Some(Leave::Announced(_, pending)) if pending.len() == 2));
+
+        // The receiver acknowledges the files it stored and negatively the ones it failed to store
+        let mut corrupted = File::blob(vec![1]);
+        corrupted.buffer = vec![2];
+        let transfer = vec![
+            (File::blob(vec![3]), version(1), None),
+            (corrupted.clone(), version(1), None),
+        ];
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffTransfer(address(SUCCESSOR_PORT), transfer),
+        );
+        let messages = queued_messages(&mut events, timeout);
+        assert!(matches!(
+            &messages[..],
+            [ChordMessage::HandoffAck(_, stored), ChordMessage::HandoffNack(_, failed)]
+                if *stored == vec![File::blob(vec![3]).key()] && *failed == vec![corrupted.key()]
+        ));
+        handler.stop();
+        task.wait();
+    }
+
+    fn wait_for_status(handle: &NodeHandle, condition: impl Fn(&NodeStatus) -> bool) -> NodeStatus {
+        for _ in 0..100 {
+            let status = handle.query().unwrap();
+            if condition(&status) {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("node {} never reached the expected state", handle.address());
+    }
+
+    #[test]
+    fn test_node_handle() {
+        const FIRST_PORT: u16 = 8251;
+        const SECOND_PORT: u16 = 8252;
+        let first_address = SocketAddr::new(IpAddr::from(LOCAL_IP), FIRST_PORT);
+        let second_address = SocketAddr::new(IpAddr::from(LOCAL_IP), SECOND_PORT);
+
+        let first = create_test_node(FIRST_PORT).spawn().unwrap();
+        let second = create_test_node(SECOND_PORT).connect_and_spawn(first_address).unwrap();
+        assert_eq!(second.address(), second_address);
+
+        let status = wait_for_status(&second, |status| status.successor == Some(first_address));
+        assert_eq!(status.id, ChordId::from_addr(&second_address));
+        assert_eq!(status.successor_list, vec![first_address]);
+        wait_for_status(&first, |status| status.predecessor == Some(second_address));
+
+        first.shutdown();
+        second.shutdown();
+        assert!(!first.is_running());
+        assert!(first.query().is_none());
+        first.join().unwrap();
+        second.join().unwrap();
+    }
+
+    #[test]
+    fn test_node_builder() {
+        const BIND_PORT: u16 = 8261;
+        const ADVERTISED_PORT: u16 = 8262;
+        let bind_address = SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), BIND_PORT);
+        let advertised_address = SocketAddr::new(IpAddr::from(LOCAL_IP), ADVERTISED_PORT);
+        let data_dir = empty_test_data_dir(BIND_PORT);
+
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(bind_address)
+            .advertised_addr(advertised_address)
+            .data_dir(&data_dir)
+            .heartbeat_interval(Duration::from_millis(100))
+            .successor_timeout(Duration::from_secs(1))
+            .leave_timeout(Duration::from_secs(2))
+            .successor_list_length(0)
+            .transport(Transport::Udp)
+            .build()
+            .unwrap();
+        handler.stop();
+
+        assert_eq!(config.self_addr, advertised_address);
+        assert_eq!(config.id, ChordId::from_addr(&advertised_address));
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(100));
+        assert_eq!(config.successor_timeout, TimeDelta::seconds(1));
+        assert_eq!(config.leave_timeout, Duration::from_secs(2));
+        assert_eq!(config.successor_list_length, 1);
+        assert_eq!(config.transport, Transport::Udp);
+        assert_eq!(config.liveness_transport, Transport::Udp);
+
+        let file = File {
+            name: "builder_file".to_string(),
+            buffer: vec![4, 5, 6],
+        };
+        let (key, _) = save_in_server(file, PutCondition::Always, None, &mut config).unwrap();
+        assert_eq!(fs::read(data_dir.join(key)).unwrap(), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn test_data_dir_validation() {
+        const NODE_PORT: u16 = 8263;
+        let address = SocketAddr::new(IpAddr::from(LOCAL_IP), NODE_PORT);
+        let data_dir = empty_test_data_dir(NODE_PORT);
+
+        // A regular file where the data directory should be
+        fs::create_dir_all(data_dir.parent().unwrap()).unwrap();
+        fs::write(&data_dir, b"not a directory").unwrap();
+        let error = NodeState::builder(address).data_dir(&data_dir).build().err().unwrap();
+        assert!(error.to_string().contains("data directory"));
+        fs::remove_file(&data_dir).unwrap();
+
+        // Files stored by a previous run are loaded back
+        let mut node = NodeState::builder(address).data_dir(&data_dir).build().unwrap();
+        let file = File {
+            name: "persistent_file".to_string(),
+            buffer: vec![7],
+        };
+        let (key, _) = save_in_server(file, PutCondition::Always, None, &mut node.config).unwrap();
+        node.handler.stop();
+        drop(node);
+
+        let node = NodeState::builder(address).data_dir(&data_dir).build().unwrap();
+        node.handler.stop();
+        let metadata = node.config.storage.metadata(&ChordId::from_hex(&key).unwrap()).unwrap();
+        assert_eq!(
+            metadata,
+            Some(Metadata {
+                name: "persistent_file".to_string(),
+                size: 1,
+                version: Version::UNVERSIONED.next(node.config.id),
+                checksum: checksum(&[7]),
+                expires_at: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_storage_backends() {
+        let data_dir = empty_test_data_dir(8264);
+        let backends: Vec<Box<dyn StorageBackend>> = vec![
+            Box::new(MemoryStorage::new()),
+            Box::new(FsStorage::open(data_dir.join("fs")).unwrap()),
+            Box::new(LogStorage::open(data_dir.join("log")).unwrap()),
+        ];
+
+        for mut storage in backends {
+            let keys: Vec<ChordId> = [0x10, 0x80, 0xf0].into_iter().map(id_from_u8).collect();
+            for (index, key) in keys.iter().enumerate() {
+                let file = File {
+                    name: format!("file_{index}"),
+                    buffer: vec![index as u8; index + 1],
+                };
+                storage.put(*key, file, version(index as u64), None).unwrap();
+            }
+
+            let file = storage.get(&keys[1]).unwrap().unwrap();
+            assert_eq!(file.name, "file_1");
+            assert_eq!(file.buffer, vec![1, 1]);
+            assert_eq!(storage.get(&id_from_u8(0x20)).unwrap().map(|file| file.name), None);
+            assert_eq!(
+                storage.metadata(&keys[2]).unwrap(),
+                Some(Metadata {
+                    name: "file_2".to_string(),
+                    size: 3,
+                    version: version(2),
+                    checksum: checksum(&[2, 2, 2]),
+                    expires_at: None,
+                })
+            );
+
+            // Ranges are half-open and wrap around the ring
+            assert_eq!(storage.list_range(&keys[0], &keys[2]).unwrap(), vec![keys[1], keys[2]]);
+            assert_eq!(
+                storage.list_range(&id_from_u8(0xa0), &id_from_u8(0x50)).unwrap(),
+                vec![keys[2], keys[0]]
+            );
+            assert_eq!(storage.keys().unwrap(), keys);
+
+            storage
+                .put(
+                    keys[1],
+                    File {
+                        name: "file_1".to_string(),
+                        buffer: vec![9],
+                    },
+                    version(3),
+                    None,
+                )
+                .unwrap();
+            assert_eq!(storage.get(&keys[1]).unwrap().unwrap().buffer, vec![9]);
+
+            assert!(storage.delete(&keys[0]).unwrap());
+            assert!(!storage.delete(&keys[0]).unwrap());
+            assert_eq!(storage.keys().unwrap(), vec![keys[1], keys[2]]);
+        }
+
+        // Both persistent backends come back with the same content
+        let fs_storage = FsStorage::open(data_dir.join("fs")).unwrap();
+        assert_eq!(fs_storage.keys().unwrap(), vec![id_from_u8(0x80), id_from_u8(0xf0)]);
+        assert_eq!(fs_storage.get(&id_from_u8(0x80)).unwrap().unwrap().buffer, vec![9]);
+
+        // A record cut short by a crash is dropped
+        let log_path = data_dir.join("log");
+        let length = fs::metadata(&log_path).unwrap().len();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all(&[42, 0, 0, 0, 0, 0, 0, 0, 1, 2])
+            .unwrap();
+        let log_storage = LogStorage::open(&log_path).unwrap();
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), length);
+        assert_eq!(log_storage.keys().unwrap(), vec![id_from_u8(0x80), id_from_u8(0xf0)]);
+        assert_eq!(log_storage.get(&id_from_u8(0x80)).unwrap().unwrap().buffer, vec![9]);
+    }
+
+    #[test]
+    fn test_fs_storage_crash_recovery() {
+        let data_dir = empty_test_data_dir(8265);
+        let file = |name: &str| File {
+            name: name.to_string(),
+            buffer: name.as_bytes().to_vec(),
+        };
+
+        let mut storage = FsStorage::open(&data_dir).unwrap();
+        storage.put(id_from_u8(1), file("kept"), version(1), None).unwrap();
+        storage.put(id_from_u8(2), file("deleted"), version(1), None).unwrap();
+        storage.put(id_from_u8(3), file("lost"), version(1), None).unwrap();
+        storage.delete(&id_from_u8(2)).unwrap();
+        drop(storage);
+
+        // A put interrupted before its index record, one interrupted while writing the file, a torn index record
+        // and a file lost behind the back of the node
+        fs::write(data_dir.join(id_from_u8(4).to_hex()), b"unacknowledged").unwrap();
+        fs::write(data_dir.join(id_from_u8(5).to_hex() + ".tmp"), b"half written").unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(data_dir.join("index.wal"))
+            .unwrap()
+            .write_all(&[7, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3])
+            .unwrap();
+        fs::remove_file(data_dir.join(id_from_u8(3).to_hex())).unwrap();
+
+        let mut storage = FsStorage::open(&data_dir).unwrap();
+        assert_eq!(storage.keys().unwrap(), vec![id_from_u8(1)]);
+        assert_eq!(storage.get(&id_from_u8(1)).unwrap().unwrap().buffer, b"kept");
+        let mut stored: Vec<String> = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        stored.sort();
+        assert_eq!(stored, vec![id_from_u8(1).to_hex(), "index.wal".to_string()]);
+
+        // Stale records are compacted away
+        for _ in 0..200 {
+            storage.put(id_from_u8(6), file("churn"), version(1), None).unwrap();
+            storage.delete(&id_from_u8(6)).unwrap();
+        }
+        assert!(fs::metadata(data_dir.join("index.wal")).unwrap().len() < 64 * 100);
+        drop(storage);
+
+        // Records written by older versions are imported
+        let legacy_dir = empty_test_data_dir(8266);
+        fs::create_dir_all(&legacy_dir).unwrap();
+        fs::write(legacy_dir.join(id_from_u8(8).to_hex()), b"legacy").unwrap();
+        fs::write(
+            legacy_dir.join("saved_files.txt"),
+            format!("{}:legacy_file\n", id_from_u8(8).to_hex()),
+        )
+        .unwrap();
+        let storage = FsStorage::open(&legacy_dir).unwrap();
+        assert_eq!(storage.get(&id_from_u8(8)).unwrap().unwrap().name, "legacy_file");
+        assert!(!legacy_dir.join("saved_files.txt").exists());
+    }
+
+    #[test]
+    fn test_fs_storage_interrupted_overwrite() {
+        let data_dir = empty_test_data_dir(8413);
+        let key = id_from_u8(1);
+        let file = |content: &str| File {
+            name: "overwritten".to_string(),
+            buffer: content.as_bytes().to_vec(),
+        };
+
+        let mut storage = FsStorage::open(&data_dir).unwrap();
+        storage.put(key, file("acknowledged"), version(1), None).unwrap();
+        let acknowledged_path = storage.file_path(&key).unwrap();
+        let acknowledged_index = fs::read(data_dir.join("index.wal")).unwrap();
+        storage.put(key, file("interrupted"), version(2), None).unwrap();
+        let interrupted_path = storage.file_path(&key).unwrap();
+        assert_ne!(interrupted_path, acknowledged_path);
+        assert!(!acknowledged_path.exists());
+        drop(storage);
+
+        // A crash once the new content is on disk but before its index record: the index and the previous file are
+        // as they were before the overwrite
+        fs::write(data_dir.join("index.wal"), acknowledged_index).unwrap();
+        fs::write(&acknowledged_path, b"acknowledged").unwrap();
+
+        let mut storage = FsStorage::open(&data_dir).unwrap();
+        assert_eq!(storage.get(&key).unwrap().unwrap().buffer, b"acknowledged");
+        assert_eq!(storage.metadata(&key).unwrap().unwrap().version, version(1));
+        assert!(!interrupted_path.exists());
+
+        // The overwrite goes through once retried, and survives a restart
+        storage.put(key, file("retried"), version(2), None).unwrap();
+        drop(storage);
+        let storage = FsStorage::open(&data_dir).unwrap();
+        assert_eq!(storage.get(&key).unwrap().unwrap().buffer, b"retried");
+        let stored: Vec<_> = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != "index.wal")
+            .collect();
+        assert_eq!(stored, vec![storage.file_path(&key).unwrap()]);
+    }
+
+    #[test]
+    fn test_corrupted_record_in_the_middle() {
+        let data_dir = empty_test_data_dir(8414);
+        let flip_byte = |path: &PathBuf, offset: Option<usize>| {
+            let mut content = fs::read(path).unwrap();
+            let offset = offset.unwrap_or(content.len() - 1);
+            content[offset] ^= 1;
+            fs::write(path, content).unwrap();
+        };
+        let file = |name: &str| File {
+            name: name.to_string(),
+            buffer: name.as_bytes().to_vec(),
+        };
+
+        // A torn last record is dropped alone
+        let mut storage = FsStorage::open(&data_dir).unwrap();
+        for (i, name) in ["first", "second", "torn"].into_iter().enumerate() {
+            storage.put(id_from_u8(i as u8), file(name), version(1), None).unwrap();
+        }
+        drop(storage);
+        let index_path = data_dir.join("index.wal");
+        flip_byte(&index_path, None);
+        let storage = FsStorage::open(&data_dir).unwrap();
+        assert_eq!(storage.keys().unwrap(), vec![id_from_u8(0), id_from_u8(1)]);
+        drop(storage);
+
+        // A corrupted record followed by valid ones fails the open and is left in place
+        flip_byte(&index_path, Some(30));
+        let length = fs::metadata(&index_path).unwrap().len();
+        let error = FsStorage::open(&data_dir).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&index_path).unwrap().len(), length);
+
+        let log_path = data_dir.join("log");
+        let mut log = LogStorage::open(&log_path).unwrap();
+        log.put(id_from_u8(0), file("first"), version(1), None).unwrap();
+        log.put(id_from_u8(1), file("second"), version(1), None).unwrap();
+        drop(log);
+        flip_byte(&log_path, Some(30));
+        let length = fs::metadata(&log_path).unwrap().len();
+        let error = LogStorage::open(&log_path).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), length);
+
+        // A torn last record holding a log of its own is still only a torn record
+        let uploaded_path = data_dir.join("uploaded");
+        let mut uploaded = LogStorage::open(&uploaded_path).unwrap();
+        uploaded.put(id_from_u8(0), file("inner"), version(1), None).unwrap();
+        drop(uploaded);
+        let torn_path = data_dir.join("torn");
+        let mut log = LogStorage::open(&torn_path).unwrap();
+        log.put(id_from_u8(0), file("first"), version(1), None).unwrap();
+        let buffer = fs::read(&uploaded_path).unwrap();
+        log.put(id_from_u8(1), File::blob(buffer), version(1), None).unwrap();
+        drop(log);
+        flip_byte(&torn_path, None);
+        let log = LogStorage::open(&torn_path).unwrap();
+        assert_eq!(log.keys().unwrap(), vec![id_from_u8(0)]);
+    }
+
+    #[test]
+    fn test_user_delete() {
+        const NODE_PORT: u16 = 8267;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let user = |port: u16| User::new(LOCAL_IP_STR.to_string(), port.to_string()).unwrap();
+        let file = File {
+            name: "deleted_file".to_string(),
+            buffer: vec![1],
+        };
+
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+        let key = user(8268).put(&node_address, file.clone()).unwrap();
+        assert_eq!(user(8269).delete(&node_address, key.clone()), Ok(()));
+        assert_eq!(
+            user(8270).get(&node_address, key.clone()).unwrap_err(),
+            GetError::NotFound
+        );
+        assert_eq!(
+            user(8271).delete(&node_address, key.clone()),
+            Err(DeleteError::NotFound)
+        );
+        assert_eq!(
+            user(8272).delete(&node_address, "not hex".to_string()),
+            Err(DeleteError::HexConversion)
+        );
+        assert!(node.query().unwrap().keys.is_empty());
+        node.shutdown();
+        node.join().unwrap();
+
+        // A copy handed over after the delete is dropped, a new put brings the key back
+        let NodeState {
+            handler, mut config, ..
+        } = create_test_node(NODE_PORT);
+        let (endpoint, _) = handler
+            .network()
+            .connect(Transport::Udp, SocketAddr::new(IpAddr::from(LOCAL_IP), 8273))
+            .unwrap();
+        let key = ChordId::from_hex(&key).unwrap();
+        // A delete of a key never stored leaves no tombstone
+        assert!(!delete_in_server(&key, &mut config).unwrap());
+        assert!(config.tombstones.is_empty());
+
+        save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        assert!(delete_in_server(&key, &mut config).unwrap());
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::MoveFile(file.clone(), version(1), None),
+        );
+        assert!(config.storage.get(&key).unwrap().is_none());
+
+        save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        assert!(config.storage.get(&key).unwrap().is_some());
+        assert!(config.tombstones.is_empty());
+
+        // Tombstones are handed over, and expire
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::MoveTombstones(vec![(key, version(1)), (id_from_u8(1), version(1))]),
+        );
+        assert_eq!(config.tombstones.keys().collect::<Vec<_>>(), vec![&id_from_u8(1)]);
+        config.tombstone_lifetime = TimeDelta::zero();
+        config.tombstones.get_mut(&id_from_u8(1)).unwrap().deleted_at = Utc::now() - TimeDelta::seconds(1);
+        expire_tombstones(&mut config);
+        assert!(config.tombstones.is_empty());
+        assert!(config.storage.tombstones().unwrap().is_empty());
+        handler.stop();
+    }
+
+    #[test]
+    fn test_durable_tombstones() {
+        // Restarted on the next port, the previous one is not released right away
+        const NODE_PORT: u16 = 8415;
+        let data_dir = empty_test_data_dir(NODE_PORT);
+        let tombstone = |counter| Tombstone {
+            version: version(counter),
+            deleted_at: Utc::now(),
+        };
+
+        // Persistent backends come back with their tombstones, until the key is stored again or they are removed
+        let paths = [data_dir.join("fs"), data_dir.join("log")];
+        let open = |index: usize| -> Box<dyn StorageBackend> {
+            match index {
+                0 => Box::new(FsStorage::open(&paths[0]).unwrap()),
+                _ => Box::new(LogStorage::open(&paths[1]).unwrap()),
+            }
+        };
+        for index in 0..paths.len() {
+            let mut storage = open(index);
+            let file = File {
+                name: "file".to_string(),
+                buffer: vec![1],
+            };
+            storage.put(id_from_u8(1), file.clone(), version(1), None).unwrap();
+            assert!(storage.delete_with_tombstone(&id_from_u8(1), tombstone(2)).unwrap());
+            assert!(!storage.delete_with_tombstone(&id_from_u8(2), tombstone(1)).unwrap());
+            storage.put(id_from_u8(3), file.clone(), version(1), None).unwrap();
+            storage.delete_with_tombstone(&id_from_u8(3), tombstone(2)).unwrap();
+            storage.remove_tombstone(&id_from_u8(2)).unwrap();
+            storage.put(id_from_u8(3), file, version(3), None).unwrap();
+            drop(storage);
+
+            let storage = open(index);
+            assert_eq!(storage.keys().unwrap(), vec![id_from_u8(3)]);
+            let tombstones = storage.tombstones().unwrap();
+            assert_eq!(tombstones.len(), 1);
+            assert_eq!(tombstones[0].0, id_from_u8(1));
+            assert_eq!(tombstones[0].1.version, version(2));
+        }
+
+        // A node restarted after a delete still drops older copies handed over, and goes on counting versions
+        let node = |port| {
+            NodeState::builder(SocketAddr::new(IpAddr::from(LOCAL_IP), port))
+                .data_dir(data_dir.join("node"))
+                .build()
+                .unwrap()
+        };
+        let file = File {
+            name: "deleted_file".to_string(),
+            buffer: vec![1, 2, 3],
+        };
+        let key = file.key();
+        let NodeState {
+            handler, mut config, ..
+        } = node(NODE_PORT);
+        save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        assert!(delete_in_server(&key, &mut config).unwrap());
+        handler.stop();
+        drop(config);
+
+        let NodeState {
+            handler, mut config, ..
+        } = node(NODE_PORT + 1);
+        assert_eq!(config.tombstones[&key].version.counter, 2);
+        save_handed_over(file.clone(), version(1), None, &mut config).unwrap();
+        assert!(config.storage.get(&key).unwrap().is_none());
+        let (_, stored) = save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        assert_eq!(stored.counter, 3);
+        assert!(config.storage.tombstones().unwrap().is_empty());
+
+        // A delete older than the stored file is ignored, a newer one removes it
+        assert!(!save_tombstone(&key, version(2), &mut config).unwrap());
+        assert!(config.storage.get(&key).unwrap().is_some());
+        assert!(save_tombstone(&key, version(5), &mut config).unwrap());
+        let (_, stored) = save_in_server(file, PutCondition::Always, None, &mut config).unwrap();
+        assert_eq!(stored.counter, 6);
+        handler.stop();
+    }
+
+    #[test]
+    fn test_replication() {
+        const NODE_PORT: u16 = 8274;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(address(NODE_PORT))
+            .data_dir(empty_test_data_dir(NODE_PORT))
+            .replication_factor(3)
+            .build()
+            .unwrap();
+        let (endpoint, _) = handler.network().connect(Transport::Udp, address(8275)).unwrap();
+        let file = File {
+            name: "replicated_file".to_string(),
+            buffer: vec![1, 2, 3],
+        };
+        let key = ChordId::digest(file.name.as_bytes());
+
+        // The replicas are the first replication_factor - 1 successors
+        config.set_successor(address(8276));
+        config.update_successor_list(address(8276), vec![address(8277), address(8278), address(NODE_PORT)]);
+        assert_eq!(replica_set(&config), vec![address(8276), address(8277)]);
+        refresh_replicas(&handler, &mut config);
+        assert_eq!(config.replicas, vec![address(8276), address(8277)]);
+        config.replication_factor = 1;
+        assert!(replica_set(&config).is_empty());
+        config.replication_factor = 3;
+
+        // Copies pushed by the node responsible for a key are stored, and deleted with a tombstone
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::Replicate(file.clone(), version(1), None),
+        );
+        assert_eq!(config.storage.get(&key).unwrap().unwrap().buffer, file.buffer);
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::ReplicateDelete(key, version(2)),
+        );
+        assert!(config.storage.get(&key).unwrap().is_none());
+        assert!(config.tombstones.contains_key(&key));
+
+        // A node handing keys over to its new predecessor keeps them as a replica
+        save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        let predecessor = (8279..)
+            .map(address)
+            .find(|candidate| key.in_half_open_interval(&config.id, &ChordId::from_addr(candidate)))
+            .unwrap();
+        move_files(&handler, &mut config, predecessor);
+        assert!(config.storage.get(&key).unwrap().is_some());
+        config.replication_factor = 1;
+        move_files(&handler, &mut config, predecessor);
+        assert!(config.storage.get(&key).unwrap().is_some());
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffAccept(predecessor, vec![]),
+        );
+        assert!(config.storage.get(&key).unwrap().is_none());
+
+        // Reads skip a silent successor
+        assert_eq!(replica_of_failed_successor(&config), None);
+        config.last_modified = Utc::now() - TimeDelta::seconds(60);
+        assert_eq!(replica_of_failed_successor(&config), Some(address(8277)));
+        handler.stop();
+    }
+
+    #[test]
+    fn test_replica_maintenance() {
+        const NODE_PORT: u16 = 8424;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let NodeState {
+            handler,
+            mut config,
+            listener,
+        } = NodeState::builder(address(NODE_PORT))
+            .data_dir(empty_test_data_dir(NODE_PORT))
+            .replication_factor(3)
+            .build()
+            .unwrap();
+        let (endpoint, _) = handler.network().connect(Transport::Udp, address(8425)).unwrap();
+        let predecessor = address(8426);
+        config.set_predecessor(predecessor);
+        config.set_successor(address(8427));
+        config.update_successor_list(address(8427), vec![address(8428)]);
+        let (mut task, mut events) = listener.enqueue();
+        let timeout = Duration::from_millis(200);
+        let summaries = |messages: Vec<ChordMessage>| {
+            messages
+                .iter()
+                .filter(|message| matches!(message, ChordMessage::MerkleSummary(..)))
+                .count()
+        };
+
+        // Only the replicas new to the set are compared, and the repairs go in batches
+        refresh_replicas(&handler, &mut config);
+        assert_eq!(summaries(queued_messages(&mut events, timeout)), 2);
+        config.update_successor_list(address(8427), vec![address(8429)]);
+        refresh_replicas(&handler, &mut config);
+        assert_eq!(summaries(queued_messages(&mut events, timeout)), 1);
+        refresh_replicas(&handler, &mut config);
+        assert_eq!(summaries(queued_messages(&mut events, timeout)), 0);
+
+        let (start, end) = (ChordId::from_addr(&predecessor), config.id);
+        let own = move |key: &ChordId| key.in_half_open_interval(&start, &end);
+        let files = (0..).map(|index: u32| File::blob(index.to_le_bytes().to_vec()));
+        let owned: Vec<File> = files
+            .clone()
+            .filter(|file| own(&file.key()))
+            .take(REPAIR_BATCH + 4)
+            .collect();
+        for file in &owned {
+            save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        }
+        queued_messages(&mut events, timeout);
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::MerkleDiff(address(8427), start, end, (0..LEAVES).collect(), vec![]),
+        );
+        let messages = queued_messages(&mut events, timeout);
+        let pushed = messages
+            .iter()
+            .filter(|message| matches!(message, ChordMessage::Replicate(..)))
+            .count();
+        assert_eq!(pushed, REPAIR_BATCH);
+        assert!(matches!(messages.last(), Some(ChordMessage::MerkleSummary(..))));
+
+        // Copies outside the range of the node are kept while an owner summarizes their range to it
+        let foreign: Vec<File> = files.filter(|file| !own(&file.key())).take(2).collect();
+        for file in &foreign {
+            save_replica(file.clone(), version(1), None, &mut config).unwrap();
+        }
+        config.maximum_gossip_interval = Duration::from_millis(40);
+        thread::sleep(Duration::from_millis(150));
+        let foreign_key = foreign[0].key();
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::MerkleSummary(address(8430), id_from_u8(0), foreign_key, MerkleTree::new(&[])),
+        );
+        collect_replicas(&mut config);
+        assert!(config.storage.get(&foreign_key).unwrap().is_some());
+        assert!(config.storage.get(&owned[0].key()).unwrap().is_some());
+        let expected = foreign[1].key().in_half_open_interval(&id_from_u8(0), &foreign_key);
+        assert_eq!(config.storage.get(&foreign[1].key()).unwrap().is_some(), expected);
+        thread::sleep(Duration::from_millis(150));
+        collect_replicas(&mut config);
+        assert!(config.storage.get(&foreign_key).unwrap().is_none());
+        assert_eq!(config.storage.keys().unwrap().len(), owned.len());
+
+        // The copies of a silent predecessor are served here instead of forwarded to it
+        save_replica(foreign[0].clone(), version(1), None, &mut config).unwrap();
+        let hex_key = foreign_key.to_hex();
+        assert!(matches!(
+            get_from_key(&handler, &mut config, address(8431), 0, hex_key.clone()),
+            ServerToUserMessage::ForwarderTo(_)
+        ));
+        config.predecessor_ping = Some(Utc::now() - TimeDelta::seconds(60));
+        assert!(matches!(
+            get_from_key(&handler, &mut config, address(8431), 0, hex_key),
+            ServerToUserMessage::RequestedFile(file, _) if file.buffer == foreign[0].buffer
+        ));
+        handler.stop();
+        task.wait();
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        let files: Vec<File> = (0..40)
+            .map(|i| File {
+                name: format!("file_{i}"),
+                buffer: vec![i],
+            })
+            .collect();
+        let mut owner = MemoryStorage::new();
+        let mut replica = MemoryStorage::new();
+        for file in &files {
+            let key = ChordId::digest(file.name.as_bytes());
+            owner.put(key, file.clone(), version(1), None).unwrap();
+            replica.put(key, file.clone(), version(1), None).unwrap();
+        }
+        let (start, end) = (id_from_u8(0), id_from_u8(0));
+        let summary = |storage: &MemoryStorage| MerkleTree::new(&range_entries(storage, &start, &end).unwrap());
+        assert_eq!(summary(&owner), summary(&replica));
+        assert!(summary(&owner).differing_leaves(&summary(&replica)).is_empty());
+
+        // A changed object and a missing one only show up in their own leaves
+        let changed = ChordId::digest(files[3].name.as_bytes());
+        let missing = ChordId::digest(files[7].name.as_bytes());
+        replica
+            .put(
+                changed,
+                File {
+                    name: files[3].name.clone(),
+                    buffer: vec![0xff],
+                },
+                version(1),
+                None,
+            )
+            .unwrap();
+        replica.delete(&missing).unwrap();
+        assert_ne!(summary(&owner).root(), summary(&replica).root());
+        let mut expected = vec![leaf_of(&changed), leaf_of(&missing)];
+        expected.sort_unstable();
+        expected.dedup();
+        assert_eq!(summary(&owner).differing_leaves(&summary(&replica)), expected);
+        assert_ne!(
+            object_digest(&owner.metadata(&changed).unwrap().unwrap()),
+            object_digest(&replica.metadata(&changed).unwrap().unwrap())
+        );
+
+        // Only the keys of the range are summarized
+        let narrow_end = ChordId::digest(files[0].name.as_bytes());
+        let entries = range_entries(&owner, &start, &narrow_end).unwrap();
+        assert!(entries
+            .iter()
+            .all(|(key, _, _)| key.in_half_open_interval(&start, &narrow_end)));
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // The summary is built from the metadata alone, without reading any content
+        let mut storage = FsStorage::open(empty_test_data_dir(8417)).unwrap();
+        storage.put(changed, files[3].clone(), version(1), None).unwrap();
+        fs::remove_file(storage.file_path(&changed).unwrap()).unwrap();
+        assert_eq!(
+            range_entries(&storage, &start, &end).unwrap(),
+            vec![(
+                changed,
+                version(1),
+                object_digest(&owner.metadata(&changed).unwrap().unwrap())
+            )]
+        );
+    }
+
+    /// Chord messages queued for sending by the node of `events`, waiting at most `timeout` for each.
+    fn queued_messages(
+        events: &mut EventReceiver<StoredNodeEvent<ServerSignals>>,
+        timeout: Duration,
+    ) -> Vec<ChordMessage> {
+        let mut messages = vec![];
+        while let Some(event) = events.receive_timeout(timeout) {
+            if let StoredNodeEvent::Signal(ServerSignals::ForwardMessage(_, Message::ChordMessage(message))) = event {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn test_anti_entropy_by_version() {
+        const NODE_PORT: u16 = 8418;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let NodeState {
+            handler,
+            mut config,
+            listener,
+        } = create_test_node(NODE_PORT);
+        let (endpoint, _) = handler.network().connect(Transport::Udp, address(8419)).unwrap();
+        config.predecessor = Some(address(8420));
+        let start = ChordId::from_addr(&address(8420));
+        let files: Vec<File> = (0..)
+            .map(|index| File {
+                name: format!("anti_entropy_{index}"),
+                buffer: vec![index as u8],
+            })
+            .filter(|file| file.key().in_half_open_interval(&start, &config.id))
+            .take(5)
+            .collect();
+        let keys: Vec<ChordId> = files.iter().map(File::key).collect();
+        let unknown = [0; 32];
+
+        // Older copies on the replica are pushed, newer ones requested, deletes only win over older copies
+        config.storage.put(keys[0], files[0].clone(), version(2), None).unwrap();
+        config.storage.put(keys[1], files[1].clone(), version(1), None).unwrap();
+        save_tombstone(&keys[2], version(2), &mut config).unwrap();
+        save_tombstone(&keys[3], version(2), &mut config).unwrap();
+        config.storage.put(keys[4], files[4].clone(), version(1), None).unwrap();
+        let in_sync = object_digest(&config.storage.metadata(&keys[4]).unwrap().unwrap());
+        let replica_entries = vec![
+            (keys[0], version(1), unknown),
+            (keys[1], version(3), unknown),
+            (keys[2], version(1), unknown),
+            (keys[3], version(4), unknown),
+            (keys[4], version(1), in_sync),
+        ];
+
+        let (mut task, mut events) = listener.enqueue();
+        let end = config.id;
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::MerkleDiff(address(8419), start, end, (0..LEAVES).collect(), replica_entries),
+        );
+        let messages = queued_messages(&mut events, Duration::from_millis(200));
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(
+            &messages[0],
+            ChordMessage::Replicate(file, pushed, None) if file.key() == keys[0] && *pushed == version(2)
+        ));
+        assert!(matches!(
+            &messages[1],
+            ChordMessage::ReplicateDelete(key, deleted) if *key == keys[2] && *deleted == version(2)
+        ));
+        assert!(matches!(
+            &messages[2],
+            ChordMessage::RequestKeys(_, requested) if *requested == vec![keys[1], keys[3]]
+        ));
+
+        // A replica keeps a copy more recent than the one pushed, and drops copies of keys deleted since
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::Replicate(files[0].clone(), version(1), None),
+        );
+        assert_eq!(config.storage.metadata(&keys[0]).unwrap().unwrap().version, version(2));
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::Replicate(files[2].clone(), version(1), None),
+        );
+        assert!(config.storage.get(&keys[2]).unwrap().is_none());
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::Replicate(files[2].clone(), version(3), None),
+        );
+        assert!(config.storage.get(&keys[2]).unwrap().is_some());
+        handler.stop();
+        task.wait();
+    }
+
+    #[test]
+    fn test_versioned_put() {
+        const NODE_PORT: u16 = 8280;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let user = |port: u16| User::new(LOCAL_IP_STR.to_string(), port.to_string()).unwrap();
+        let file = |buffer: u8| File {
+            name: "shared_configuration".to_string(),
+            buffer: vec![buffer],
+        };
+
+        // Two writers starting from the same version, the second one is told about the first
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+        let (key, first) = user(8281).compare_and_put(&node_address, file(1), None).unwrap();
+        assert_eq!(first.counter, 1);
+        let (read, read_version) = user(8282).get_versioned(&node_address, key.clone()).unwrap();
+        assert_eq!((read.buffer, read_version), (vec![1], first));
+        let (_, second) = user(8283).compare_and_put(&node_address, file(2), Some(first)).unwrap();
+        assert!(second > first);
+        assert_eq!(
+            user(8284).compare_and_put(&node_address, file(3), Some(first)),
+            Err(PutError::VersionConflict(Some(second)))
+        );
+        assert_eq!(
+            user(8285).compare_and_put(&node_address, file(3), None),
+            Err(PutError::VersionConflict(Some(second)))
+        );
+        user(8286).put(&node_address, file(4)).unwrap();
+        let (read, read_version) = user(8287).get_versioned(&node_address, key).unwrap();
+        assert_eq!((read.buffer, read_version.counter), (vec![4], 3));
+        node.shutdown();
+        node.join().unwrap();
+
+        // Versions survive a restart, and handed over copies only replace older ones
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(SocketAddr::new(IpAddr::from(LOCAL_IP), NODE_PORT))
+            .data_dir(test_data_dir(NODE_PORT))
+            .build()
+            .unwrap();
+        handler.stop();
+        let key = ChordId::digest(file(0).name.as_bytes());
+        let stored = config.storage.metadata(&key).unwrap().unwrap().version;
+        assert_eq!(stored.counter, 3);
+        save_handed_over(file(5), version(2), None, &mut config).unwrap();
+        assert_eq!(config.storage.get(&key).unwrap().unwrap().buffer, vec![4]);
+        save_handed_over(file(6), version(9), None, &mut config).unwrap();
+        let (stored, metadata) = config.storage.get_with_metadata(&key).unwrap().unwrap();
+        let stored_version = metadata.version;
+        assert_eq!((stored.buffer, stored_version), (vec![6], version(9)));
+
+        let log_path = empty_test_data_dir(8288).join("log");
+        let mut log = LogStorage::open(&log_path).unwrap();
+        log.put(key, file(7), version(4), None).unwrap();
+        drop(log);
+        assert_eq!(
+            LogStorage::open(&log_path)
+                .unwrap()
+                .metadata(&key)
+                .unwrap()
+                .unwrap()
+                .version,
+            version(4)
+        );
+    }
+
+    #[test]
+    fn test_streamed_put_and_get() {
+        const NODE_PORT: u16 = 8290;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let user = |port: u16| User::new(LOCAL_IP_STR.to_string(), port.to_string()).unwrap();
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+
+        // Two and a half chunks, each one different
+        let value: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let key = user(8291)
+            .put_stream(&node_address, "large_value".to_string(), value.as_slice())
+            .unwrap();
+        assert_eq!(node.query().unwrap().keys.len(), 4);
+
+        let manifest = Manifest::decode(&user(8292).get(&node_address, key.clone()).unwrap().buffer).unwrap();
+        assert_eq!(manifest.size, value.len() as u64);
+        assert_eq!(
+            manifest.chunks.iter().map(|chunk| chunk.size).collect::<Vec<_>>(),
+            vec![CHUNK_SIZE as u64, CHUNK_SIZE as u64, CHUNK_SIZE as u64 / 2]
+        );
+
+        let mut read = vec![];
+        assert_eq!(
+            user(8293).get_stream(&node_address, key, &mut read).unwrap(),
+            value.len() as u64
+        );
+        assert!(read == value);
+
+        // Values put in one piece are streamed as is
+        let file = File {
+            name: "small_value".to_string(),
+            buffer: vec![1, 2, 3],
+        };
+        let key = user(8294).put(&node_address, file).unwrap();
+        let mut read = vec![];
+        assert_eq!(user(8295).get_stream(&node_address, key, &mut read).unwrap(), 3);
+        assert_eq!(read, vec![1, 2, 3]);
+        node.shutdown();
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn test_content_addressed_put() {
+        const NODE_PORT: u16 = 8296;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let user = |port: u16| User::new(LOCAL_IP_STR.to_string(), port.to_string()).unwrap();
+        let content = b"build artifact".to_vec();
+
+        // The key is the digest of the content, and the same content is stored once
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+        let key = user(8297).put_blob(&node_address, content.clone()).unwrap();
+        assert_eq!(key, ChordId::digest(&content).to_hex());
+        let (_, first) = user(8298).get_versioned(&node_address, key.clone()).unwrap();
+        assert_eq!(user(8299).put_blob(&node_address, content.clone()).unwrap(), key);
+        let (file, second) = user(8300).get_versioned(&node_address, key.clone()).unwrap();
+        assert_eq!((file.buffer, second), (content.clone(), first));
+        assert_eq!(node.query().unwrap().keys, vec![key.clone()]);
+
+        let forged = File {
+            name: File::blob(content.clone()).name,
+            buffer: b"tampered".to_vec(),
+        };
+        assert_eq!(
+            user(8301).put(&node_address, forged.clone()),
+            Err(PutError::ContentMismatch)
+        );
+        node.shutdown();
+        node.join().unwrap();
+
+        // Content altered behind the back of the node is neither served nor accepted from other nodes
+        let NodeState {
+            handler, mut config, ..
+        } = create_test_node(NODE_PORT);
+        let blob_key = ChordId::from_hex(&key).unwrap();
+        config.storage.put(blob_key, forged.clone(), version(1), None).unwrap();
+        let user_address = SocketAddr::new(IpAddr::from(LOCAL_IP), 8302);
+        assert!(matches!(
+            get_from_key(&handler, &mut config, user_address, 0, key),
+            ServerToUserMessage::CorruptedFile(_)
+        ));
+        assert!(config.storage.get(&blob_key).unwrap().is_none());
+        assert!(save_handed_over(forged, version(1), None, &mut config).is_err());
+        assert!(config.storage.get(&blob_key).unwrap().is_none());
+        handler.stop();
+    }
+
+    #[test]
+    fn test_corruption_detection() {
+        const NODE_PORT: u16 = 8303;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let file = |name: &str| File {
+            name: name.to_string(),
+            buffer: name.as_bytes().to_vec(),
+        };
+        let key = |name: &str| ChordId::digest(name.as_bytes());
+
+        // A corrupted log record is reported as such
+        let log_path = empty_test_data_dir(8304).join("log");
+        let mut log = LogStorage::open(&log_path).unwrap();
+        log.put(key("logged"), file("logged"), version(1), None).unwrap();
+        let mut content = fs::read(&log_path).unwrap();
+        *content.last_mut().unwrap() ^= 1;
+        fs::write(&log_path, content).unwrap();
+        assert!(is_corrupted(&log.get(&key("logged")).unwrap_err()));
+
+        // A corrupted file is quarantined on read, and stored again when a copy comes back
+        let data_dir = empty_test_data_dir(NODE_PORT);
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(address(NODE_PORT))
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        config.set_predecessor(address(8305));
+        let (hex_key, stored_version) =
+            save_in_server(file("rotten"), PutCondition::Always, None, &mut config).unwrap();
+        fs::write(data_dir.join(&hex_key), b"bit rot").unwrap();
+        assert!(matches!(
+            get_from_key(&handler, &mut config, address(8306), 0, hex_key.clone()),
+            ServerToUserMessage::CorruptedFile(_)
+        ));
+        assert!(config.storage.metadata(&key("rotten")).unwrap().is_none());
+        assert_eq!(
+            fs::read(data_dir.join("quarantine").join(&hex_key)).unwrap(),
+            b"bit rot"
+        );
+        handle_server_message(
+            &handler,
+            &mut config,
+            handler.network().connect(Transport::Udp, address(8305)).unwrap().0,
+            ChordMessage::MoveFile(file("rotten"), stored_version, None),
+        );
+        assert!(matches!(
+            get_from_key(&handler, &mut config, address(8306), 0, hex_key),
+            ServerToUserMessage::RequestedFile(..)
+        ));
+
+        // The scrubber finds corrupted files nobody reads
+        save_in_server(file("idle"), PutCondition::Always, None, &mut config).unwrap();
+        fs::write(data_dir.join(key("idle").to_hex()), b"bit rot").unwrap();
+        scrub(&handler, &mut config);
+        assert_eq!(config.storage.keys().unwrap(), vec![key("rotten")]);
+        handler.stop();
+
+        // Checksums survive a restart
+        let storage = FsStorage::open(&data_dir).unwrap();
+        let metadata = storage.metadata(&key("rotten")).unwrap().unwrap();
+        assert_eq!(metadata.checksum, checksum(b"rotten"));
+    }
+
+    #[test]
+    fn test_time_to_live() {
+        const NODE_PORT: u16 = 8307;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let user = |port: u16| User::new(LOCAL_IP_STR.to_string(), port.to_string()).unwrap();
+        let file = |name: &str| File {
+            name: name.to_string(),
+            buffer: vec![1, 2, 3],
+        };
+
+        // Expired keys are hidden at once, and removed by the next sweep
+        let node = NodeState::builder(SocketAddr::new(IpAddr::from(LOCAL_IP), NODE_PORT))
+            .data_dir(empty_test_data_dir(NODE_PORT))
+            .expiration_interval(Duration::from_millis(200))
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let session = user(8308)
+            .put_with_ttl(&node_address, file("session"), Duration::from_secs(1))
+            .unwrap();
+        let kept = user(8309).put(&node_address, file("kept")).unwrap();
+        assert!(user(8310).get(&node_address, session.clone()).is_ok());
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(user(8311).get(&node_address, session).err(), Some(GetError::NotFound));
+        wait_for_status(&node, |status| status.keys == vec![kept.clone()]);
+        node.shutdown();
+        node.join().unwrap();
+
+        // Expirations are persisted and handed over, expired copies are not stored again
+        let NodeState {
+            handler, mut config, ..
+        } = create_test_node(NODE_PORT);
+        let expires_at = Utc::now() + TimeDelta::hours(1);
+        let endpoint = handler
+            .network()
+            .connect(Transport::Udp, SocketAddr::new(IpAddr::from(LOCAL_IP), 8312))
+            .unwrap()
+            .0;
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::MoveFile(file("cached"), version(1), Some(expires_at)),
+        );
+        let key = ChordId::digest(b"cached");
+        assert_eq!(
+            config.storage.metadata(&key).unwrap().unwrap().expires_at,
+            Some(expires_at)
+        );
+        let log_path = empty_test_data_dir(8313).join("log");
+        let mut log = LogStorage::open(&log_path).unwrap();
+        log.put(key, file("cached"), version(1), Some(expires_at)).unwrap();
+        drop(log);
+        for storage in [
+            Box::new(FsStorage::open(test_data_dir(NODE_PORT)).unwrap()) as Box<dyn StorageBackend>,
+            Box::new(LogStorage::open(&log_path).unwrap()),
+        ] {
+            assert_eq!(storage.metadata(&key).unwrap().unwrap().expires_at, Some(expires_at));
+        }
+
+        let expired = Some(Utc::now() - TimeDelta::seconds(1));
+        save_handed_over(file("stale"), version(1), expired, &mut config).unwrap();
+        assert!(config.storage.metadata(&ChordId::digest(b"stale")).unwrap().is_none());
+
+        // An expired key counts as absent, but its version goes on
+        let (_, first) = save_in_server(file("lease"), PutCondition::Always, expired, &mut config).unwrap();
+        let (_, second) = save_in_server(file("lease"), PutCondition::IfVersion(None), None, &mut config).unwrap();
+        assert!(second > first);
+        config
+            .storage
+            .put(ChordId::digest(b"lease"), file("lease"), second, expired)
+            .unwrap();
+        expire_keys(&mut config);
+        assert_eq!(config.storage.keys().unwrap(), vec![key]);
+
+        // A compare-and-put keeps the expiration of the file it replaces
+        let user_addr = SocketAddr::new(IpAddr::from(LOCAL_IP), 8315);
+        let mut put = |condition, expires_at, request_id| {
+            let answer = put_user_file(
+                &handler,
+                &mut config,
+                file("renewed"),
+                condition,
+                expires_at,
+                user_addr,
+                request_id,
+            );
+            match answer {
+                ServerToUserMessage::SavedKey(_, version) => version,
+                other => panic!("unexpected answer {:?}", other),
+            }
+        };
+        let first = put(PutCondition::Always, Some(expires_at), 1);
+        put(PutCondition::IfVersion(Some(first)), None, 2);
+        let renewed = config.storage.metadata(&ChordId::digest(b"renewed")).unwrap().unwrap();
+        assert_eq!(renewed.expires_at, Some(expires_at));
+        handler.stop();
+    }
+
+    #[test]
+    fn test_storage_quotas() {
+        const NODE_PORT: u16 = 8314;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let user = |port: u16| User::new(LOCAL_IP_STR.to_string(), port.to_string()).unwrap();
+        let file = |name: &str, size: usize| File {
+            name: name.to_string(),
+            buffer: vec![0; size],
+        };
+
+        let node = NodeState::builder(SocketAddr::new(IpAddr::from(LOCAL_IP), NODE_PORT))
+            .data_dir(empty_test_data_dir(NODE_PORT))
+            .max_bytes(10)
+            .max_objects(2)
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        user(8315).put(&node_address, file("first", 4)).unwrap();
+        user(8316).put(&node_address, file("second", 4)).unwrap();
+        assert_eq!(
+            user(8317).put(&node_address, file("third", 1)),
+            Err(PutError::QuotaExceeded)
+        );
+
+        // Replacing a file only counts the difference
+        user(8318).put(&node_address, file("first", 6)).unwrap();
+        assert_eq!(
+            user(8319).put(&node_address, file("first", 7)),
+            Err(PutError::QuotaExceeded)
+        );
+        let status = node.query().unwrap();
+        assert_eq!(status.usage, Usage { bytes: 10, objects: 2 });
+        node.shutdown();
+        node.join().unwrap();
+
+        // Usage survives a restart
+        let data_dir = empty_test_data_dir(8320);
+        let backends: Vec<Box<dyn StorageBackend>> = vec![
+            Box::new(MemoryStorage::new()),
+            Box::new(FsStorage::open(data_dir.join("fs")).unwrap()),
+            Box::new(LogStorage::open(data_dir.join("log")).unwrap()),
+        ];
+        for mut storage in backends {
+            storage.put(id_from_u8(1), file("a", 3), version(1), None).unwrap();
+            storage.put(id_from_u8(2), file("b", 5), version(1), None).unwrap();
+            storage.put(id_from_u8(1), file("a", 1), version(2), None).unwrap();
+            storage.delete(&id_from_u8(2)).unwrap();
+            assert_eq!(storage.usage().unwrap(), Usage { bytes: 1, objects: 1 });
+        }
+        let expected = Usage { bytes: 1, objects: 1 };
+        assert_eq!(FsStorage::open(data_dir.join("fs")).unwrap().usage().unwrap(), expected);
+        assert_eq!(
+            LogStorage::open(data_dir.join("log")).unwrap().usage().unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_key_handoff() {
+        const NODE_PORT: u16 = 8321;
+        const RECEIVER_PORT: u16 = 8322;
+        let address = |port: u16| SocketAddr::new(IpAddr::from(LOCAL_IP), port);
+        let node = |port: u16, data_dir: PathBuf| {
+            let NodeState { handler, config, .. } = NodeState::builder(address(port))
+                .data_dir(data_dir)
+                .replication_factor(1)
+                .build()
+                .unwrap();
+            (handler, config)
+        };
+        let data_dir = empty_test_data_dir(NODE_PORT);
+        let journal = data_dir.join(HANDOFF_JOURNAL);
+        let (handler, mut config) = node(NODE_PORT, data_dir.clone());
+        let (endpoint, _) = handler.network().connect(Transport::Udp, address(8323)).unwrap();
+        let files: Vec<File> = (0..3)
+            .map(|i| File {
+                name: format!("handed_over_{i}"),
+                buffer: vec![i],
+            })
+            .collect();
+        for file in &files {
+            save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        }
+        let keys: Vec<ChordId> = files.iter().map(File::key).collect();
+        // A predecessor taking over all the keys
+        let predecessor = (8324..)
+            .map(address)
+            .find(|candidate| {
+                let candidate_id = ChordId::from_addr(candidate);
+                keys.iter()
+                    .all(|key| key.in_half_open_interval(&config.id, &candidate_id))
+            })
+            .unwrap();
+
+        // Offered files stay stored, and recorded, until acknowledged
+        move_files(&handler, &mut config, predecessor);
+        assert_eq!(config.handoff.as_ref().unwrap().pending.len(), 3);
+        assert!(journal.exists());
+        handler.stop();
+        drop((handler, config));
+
+        // The handoff resumes after a restart
+        let (handler, mut config) = node(NODE_PORT, data_dir);
+        let handoff = config.handoff.as_ref().unwrap();
+        assert_eq!(handoff.target, predecessor);
+        assert_eq!(handoff.pending, keys.iter().copied().collect());
+        resume_handoff(&handler, &mut config);
+        assert!(config.handoff.is_some());
+
+        // Keys the target already holds are done, the accepted ones are deleted once acknowledged
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffAccept(predecessor, keys[1..].to_vec()),
+        );
+        assert!(config.storage.get(&keys[0]).unwrap().is_none());
+        assert!(config.storage.get(&keys[1]).unwrap().is_some());
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffAck(address(8323), keys[1..].to_vec()),
+        );
+        assert!(config.storage.get(&keys[1]).unwrap().is_some());
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffAck(predecessor, vec![keys[1]]),
+        );
+        assert!(config.storage.get(&keys[1]).unwrap().is_none());
+        assert_eq!(config.handoff.as_ref().unwrap().pending.len(), 1);
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffAck(predecessor, vec![keys[2]]),
+        );
+        assert!(config.handoff.is_none());
+        assert!(!journal.exists());
+        handler.stop();
+
+        // The receiver stores the transferred files, unless deleted in the meantime
+        let (handler, mut config) = node(RECEIVER_PORT, empty_test_data_dir(RECEIVER_PORT));
+        config.tombstones.insert(
+            keys[0],
+            Tombstone {
+                version: version(1),
+                deleted_at: Utc::now(),
+            },
+        );
+        let transfer = files.iter().map(|file| (file.clone(), version(1), None)).collect();
+        handle_server_message(
+            &handler,
+            &mut config,
+            endpoint,
+            ChordMessage::HandoffTransfer(address(NODE_PORT), transfer),
+        );
+        assert!(config.storage.get(&keys[0]).unwrap().is_none());
+        assert_eq!(config.storage.get(&keys[2]).unwrap().unwrap().buffer, files[2].buffer);
+        handler.stop();
+
+        // With another storage backend, the handoff is recorded in the data directory too
+        let data_dir = empty_test_data_dir(8432);
+        let NodeState {
+            handler, mut config, ..
+        } = NodeState::builder(address(8432))
+            .storage(LogStorage::open(data_dir.join("log")).unwrap())
+            .data_dir(&data_dir)
+            .replication_factor(1)
+            .build()
+            .unwrap();
+        for file in &files {
+            save_in_server(file.clone(), PutCondition::Always, None, &mut config).unwrap();
+        }
+        move_files(&handler, &mut config, predecessor);
+        assert!(data_dir.join(HANDOFF_JOURNAL).exists());
+        handler.stop();
+    }
+
+    #[test]
+    fn test_reusable_user() {
+        const NODE_PORT: u16 = 8401;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+        let user = User::new(LOCAL_IP_STR.to_string(), "8402".to_string()).unwrap();
+        let file = |i: u8| File {
+            name: format!("shared_user_{i}"),
+            buffer: vec![i; 1 + i as usize],
+        };
+
+        // Operations one after the other
+        let key = user.put(&node_address, file(0)).unwrap();
+        assert_eq!(user.get(&node_address, key.clone()).unwrap().buffer, file(0).buffer);
+        user.delete(&node_address, key.clone()).unwrap();
+        assert_eq!(user.get(&node_address, key).err(), Some(GetError::NotFound));
+
+        // Concurrent operations each get their own answer
+        thread::scope(|scope| {
+            for i in 1..=8 {
+                let (user, node_address) = (&user, &node_address);
+                scope.spawn(move || {
+                    let key = user.put(node_address, file(i)).unwrap();
+                    assert_eq!(user.get(node_address, key).unwrap().buffer, file(i).buffer);
+                });
+            }
+        });
+        assert_eq!(node.query().unwrap().keys.len(), 8);
+        node.shutdown();
+        node.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_user() {
+        const NODE_PORT: u16 = 8403;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+        let user = AsyncUser::new(LOCAL_IP_STR.to_string(), "8404".to_string()).unwrap();
+        let file = |i: u8| File {
+            name: format!("async_user_{i}"),
+            buffer: vec![i; 1 + i as usize],
+        };
+
+        let key = user.put(&node_address, file(0)).await.unwrap();
+        assert_eq!(
+            user.get(&node_address, key.clone()).await.unwrap().buffer,
+            file(0).buffer
+        );
+        user.delete(&node_address, key.clone()).await.unwrap();
+        assert_eq!(user.get(&node_address, key).await.err(), Some(GetError::NotFound));
+
+        // Concurrent operations each get their own answer
+        let (first, second) = tokio::join!(user.put(&node_address, file(1)), user.put(&node_address, file(2)));
+        let (first, second) = tokio::join!(
+            user.get(&node_address, first.unwrap()),
+            user.get(&node_address, second.unwrap())
+        );
+        assert_eq!(first.unwrap().buffer, file(1).buffer);
+        assert_eq!(second.unwrap().buffer, file(2).buffer);
+
+        // A dropped operation is cancelled, its answer does not reach the next ones
+        let key = user.put(&node_address, file(3)).await.unwrap();
+        tokio::select! {
+            biased;
+            _ = user.get(&node_address, key.clone()) => panic!("the get should not have completed"),
+            _ = std::future::ready(()) => {}
+        }
+        assert_eq!(user.put(&node_address, file(4)).await.unwrap(), file(4).key().to_hex());
+        assert_eq!(user.get(&node_address, key).await.unwrap().buffer, file(3).buffer);
+
+        // A closed user releases its port
+        user.close().await;
+        AsyncUser::new(LOCAL_IP_STR.to_string(), "8404".to_string()).unwrap();
+        node.shutdown();
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn test_client_timeout_and_failover() {
+        // Accepts connections and never answers, like a stuck node
+        let silent = std::net::TcpListener::bind((LOCAL_IP, 8405)).unwrap();
+        let silent_address = silent.local_addr().unwrap().to_string();
+        let refusing_address = format!("{LOCAL_IP_STR}:8406");
+        const NODE_PORT: u16 = 8407;
+        let node_address = format!("{LOCAL_IP_STR}:{NODE_PORT}");
+        let node = create_test_node(NODE_PORT).spawn().unwrap();
+        let file = File {
+            name: "failover".to_string(),
+            buffer: b"still reachable".to_vec(),
+        };
+
+        // Without another server, the operations give up after their retries
+        let user = User::builder(LOCAL_IP_STR.to_string(), "8408".to_string())
+            .timeout(Duration::from_millis(300))
+            .retries(1)
+            .backoff(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let started = std::time::Instant::now();
+        assert_eq!(
+            user.get(&silent_address, file.key().to_hex()).err(),
+            Some(GetError::Timeout)
+        );
+        assert_eq!(user.put(&silent_address, file.clone()).err(), Some(PutError::Timeout));
+        assert_eq!(
+            user.delete(&silent_address, file.key().to_hex()).err(),
+            Some(DeleteError::Timeout)
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            user.get(&refusing_address, file.key().to_hex()).err(),
+            Some(GetError::ErrorRetrievingFile)
+        );
+
+        // The bootstrap servers take over from a stuck or refusing entry node
+        let user = User::builder(LOCAL_IP_STR.to_string(), "8409".to_string())
+            .timeout(Duration::from_millis(300))
+            .backoff(Duration::from_millis(10))
+            .bootstrap_servers([node_address.clone()])
+            .build()
+            .unwrap();
+        let key = user.put(&silent_address, file.clone()).unwrap();
+        assert_eq!(user.get(&refusing_address, key.clone()).unwrap().buffer, file.buffer);
+
+        let async_user = AsyncUser::builder(LOCAL_IP_STR.to_string(), "8410".to_string())
+            .timeout(Duration::from_millis(300))
+            .retries(0)
+            .build_async()
+            .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(
+            runtime.block_on(async_user.get(&silent_address, key.clone())).err(),
+            Some(GetError::Timeout)
+        );
+        assert_eq!(
+            runtime.block_on(async_user.get(&node_address, key)).unwrap().buffer,
+            file.buffer
+        );
+
+        drop(silent);
+        node.shutdown();
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn test_retried_request_answered_once() {
+        let NodeState {
+            handler, mut config, ..
+        } = create_test_node(8411);
+        let user_address = SocketAddr::new(IpAddr::from(LOCAL_IP), 8412);
+        let file = File {
+            name: "retried".to_string(),
+            buffer: b"only once".to_vec(),
+        };
+        let key = file.key();
+        let put = |request_id, config: &mut NodeConfig| {
+            let condition = PutCondition::IfVersion(None);
+            put_user_file(
+                &handler,
+                config,
+                file.clone(),
+                condition,
+                None,
+                user_address,
+                request_id,
+            )
+        };
+
+        let ServerToUserMessage::SavedKey(_, version) = put(7, &mut config) else {
+            panic!("the first attempt should store the file");
+        };
+        // A retry gets the answer of the first attempt instead of conflicting with it
+        assert!(matches!(put(7, &mut config), ServerToUserMessage::SavedKey(_, retried) if retried == version));
+        assert_eq!(config.storage.metadata(&key).unwrap().unwrap().version, version);
+        // Another request with the same condition does conflict
+        assert!(matches!(
+            put(8, &mut config),
+            ServerToUserMessage::VersionConflict(Some(current)) if current == version
+        ));
+
+        assert!(matches!(
+            delete_from_key(&handler, &mut config, user_address, 9, key.to_hex()),
+            ServerToUserMessage::DeletedKey(_)
+        ));
+        assert!(matches!(
+            delete_from_key(&handler, &mut config, user_address, 9, key.to_hex()),
+            ServerToUserMessage::DeletedKey(_)
+        ));
+        assert!(matches!(
+            delete_from_key(&handler, &mut config, user_address, 10, key.to_hex()),
+            ServerToUserMessage::FileNotFound(_)
+        ));
+    }
 }
@@ -0,0 +1,237 @@
+use crate::common::{ChordId, File, Version};
+use crate::storage::{
+    checksum, corrupted, keys_in_range, record, usage_of, verify, Metadata, StorageBackend, Tombstone, Usage,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    ///Put(key, file, version, expires_at)
+    Put(ChordId, File, Version, Option<DateTime<Utc>>),
+    Delete(ChordId),
+    ///Tombstone(key, tombstone), also deletes the file
+    Tombstone(ChordId, Tombstone),
+    RemoveTombstone(ChordId),
+}
+
+/// Where the last `Put` of a key lies in the log.
+struct Entry {
+    /// Offset of the payload of the record.
+    offset: u64,
+    length: u64,
+    metadata: Metadata,
+}
+
+/// Stores every file in a single append-only log: each put or delete appends a checksummed record, and the index
+/// of the live keys and the tombstones are rebuilt by replaying the log when it is opened.
+///
+/// Space taken by overwritten or deleted files is never reclaimed.
+pub struct LogStorage {
+    path: PathBuf,
+    log: fs::File,
+    /// Length of the valid part of the log, where the next record goes.
+    end: u64,
+    index: HashMap<ChordId, Entry>,
+    tombstones: HashMap<ChordId, Tombstone>,
+}
+
+impl LogStorage {
+    /// Opens the log at `path`, creating it if missing.
+    ///
+    /// A record cut short or corrupted by a crash at the end of the log is discarded.
+    ///
+    /// # Returns
+    /// - `Err(io::Error)` of kind `InvalidData`: if a record in the middle of the log is corrupted. The log is left
+    ///   as is, for inspection.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut log = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+
+        let (index, tombstones, end) =
+            replay(&mut log).map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        if end < log.metadata()?.len() {
+            warn!("Discarding the incomplete record at the end of {}", path.display());
+            log.set_len(end)?;
+        }
+
+        Ok(Self {
+            path,
+            log,
+            end,
+            index,
+            tombstones,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&mut self, operation: &Record) -> io::Result<(u64, u64)> {
+        let serialized = bincode::serialize(operation).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let length = serialized.len() as u64;
+
+        let written = self
+            .log
+            .write_all(&record::encode(&serialized))
+            .and_then(|_| self.log.sync_data());
+        if let Err(e) = written {
+            if let Err(truncated) = record::truncate(&mut self.log, self.end) {
+                error!(
+                    "ERROR {:?} cutting off a failed append to {}",
+                    truncated,
+                    self.path.display()
+                );
+            }
+            return Err(e);
+        }
+
+        let offset = self.end + record::HEADER_BYTES;
+        self.end = offset + length;
+        Ok((offset, length))
+    }
+}
+
+impl StorageBackend for LogStorage {
+    fn put(&mut self, key: ChordId, file: File, version: Version, expires_at: Option<DateTime<Utc>>) -> io::Result<()> {
+        let metadata = Metadata {
+            name: file.name.clone(),
+            size: file.buffer.len() as u64,
+            version,
+            checksum: checksum(&file.buffer),
+            expires_at,
+        };
+        let (offset, length) = self.append(&Record::Put(key, file, version, expires_at))?;
+        self.index.insert(
+            key,
+            Entry {
+                offset,
+                length,
+                metadata,
+            },
+        );
+        self.tombstones.remove(&key);
+        Ok(())
+    }
+
+    fn get(&self, key: &ChordId) -> io::Result<Option<File>> {
+        let Some(entry) = self.index.get(key) else {
+            return Ok(None);
+        };
+
+        let mut log = fs::File::open(&self.path)?;
+        let serialized = record::read_at(&mut log, entry.offset, entry.length).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => corrupted(*key),
+            _ => e,
+        })?;
+
+        match bincode::deserialize(&serialized) {
+            Ok(Record::Put(_, file, _, _)) => {
+                verify(key, &file.buffer, &entry.metadata.checksum)?;
+                Ok(Some(file))
+            }
+            _ => Err(corrupted(*key)),
+        }
+    }
+
+    fn delete(&mut self, key: &ChordId) -> io::Result<bool> {
+        if !self.index.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(&Record::Delete(*key))?;
+        self.index.remove(key);
+        Ok(true)
+    }
+
+    fn delete_with_tombstone(&mut self, key: &ChordId, tombstone: Tombstone) -> io::Result<bool> {
+        self.append(&Record::Tombstone(*key, tombstone))?;
+        self.tombstones.insert(*key, tombstone);
+        Ok(self.index.remove(key).is_some())
+    }
+
+    fn remove_tombstone(&mut self, key: &ChordId) -> io::Result<()> {
+        if self.tombstones.contains_key(key) {
+            self.append(&Record::RemoveTombstone(*key))?;
+            self.tombstones.remove(key);
+        }
+        Ok(())
+    }
+
+    fn tombstones(&self) -> io::Result<Vec<(ChordId, Tombstone)>> {
+        Ok(self
+            .tombstones
+            .iter()
+            .map(|(key, tombstone)| (*key, *tombstone))
+            .collect())
+    }
+
+    fn list_range(&self, start: &ChordId, end: &ChordId) -> io::Result<Vec<ChordId>> {
+        Ok(keys_in_range(self.index.keys(), start, end))
+    }
+
+    fn metadata(&self, key: &ChordId) -> io::Result<Option<Metadata>> {
+        Ok(self.index.get(key).map(|entry| entry.metadata.clone()))
+    }
+
+    fn usage(&self) -> io::Result<Usage> {
+        Ok(usage_of(self.index.values().map(|entry| entry.metadata.size)))
+    }
+}
+
+/// Index of the live keys, tombstones and length of the valid part of a replayed log.
+type Replayed = (HashMap<ChordId, Entry>, HashMap<ChordId, Tombstone>, u64);
+
+/// Rebuilds the index and the tombstones from the log.
+fn replay(log: &mut fs::File) -> io::Result<Replayed> {
+    let mut index = HashMap::new();
+    let mut tombstones = HashMap::new();
+    let end = record::read_all(log, |offset, serialized| {
+        let (key, file, version, expires_at) = match bincode::deserialize(&serialized) {
+            Ok(Record::Put(key, file, version, expires_at)) => (key, file, version, expires_at),
+            Ok(Record::Delete(key)) => {
+                index.remove(&key);
+                return;
+            }
+            Ok(Record::Tombstone(key, tombstone)) => {
+                index.remove(&key);
+                tombstones.insert(key, tombstone);
+                return;
+            }
+            Ok(Record::RemoveTombstone(key)) => {
+                tombstones.remove(&key);
+                return;
+            }
+            Err(e) => {
+                warn!("Skipping an unreadable record at offset {offset}: {e}");
+                return;
+            }
+        };
+        let metadata = Metadata {
+            size: file.buffer.len() as u64,
+            checksum: checksum(&file.buffer),
+            name: file.name,
+            version,
+            expires_at,
+        };
+        let length = serialized.len() as u64;
+        tombstones.remove(&key);
+        index.insert(
+            key,
+            Entry {
+                offset,
+                length,
+                metadata,
+            },
+        );
+    })?;
+    Ok((index, tombstones, end))
+}
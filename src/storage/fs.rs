@@ -0,0 +1,468 @@
+use crate::common::{ChordId, File, Version};
+use crate::storage::{
+    checksum, keys_in_range, record, usage_of, verify, Checksum, Metadata, StorageBackend, Tombstone, Usage,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, trace, warn};
+
+/// Write-ahead log of the stored keys with their metadata.
+const INDEX: &str = "index.wal";
+
+/// `hex key:name` record of the stored files written by older versions, imported on open.
+const LEGACY_SAVED_FILES: &str = "saved_files.txt";
+
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// Subdirectory the files failing their checksum are moved to.
+const QUARANTINE: &str = "quarantine";
+
+/// Minimum number of records in the index before it is compacted.
+const COMPACTION_THRESHOLD: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+enum IndexRecord {
+    ///Put(key, name, version, checksum, expires_at, generation)
+    Put(ChordId, String, Version, Checksum, Option<DateTime<Utc>>, u64),
+    Delete(ChordId),
+    ///Tombstone(key, tombstone), also deletes the file
+    Tombstone(ChordId, Tombstone),
+    RemoveTombstone(ChordId),
+}
+
+#[derive(Clone, PartialEq)]
+struct Entry {
+    name: String,
+    version: Version,
+    checksum: Checksum,
+    expires_at: Option<DateTime<Utc>>,
+    /// Generation of the file holding the content, increased by every overwrite of the key.
+    generation: u64,
+    /// Size of the file, not recorded in the index but read from the file on open.
+    size: u64,
+}
+
+/// Stores each file as `<dir>/<hex key>`, suffixed with `.<generation>` once overwritten, with the metadata of every
+/// stored file and the tombstones of deleted keys kept in a write-ahead log, `<dir>/index.wal`. Files failing their checksum are quarantined to
+/// `<dir>/quarantine/`.
+///
+/// A put is acknowledged only once its file and its index record are on disk, so after a crash the storage comes
+/// back with exactly the acknowledged keys:
+/// - the content of a put goes to a file of its own, and the file of the content it replaces is removed only once
+///   the index record naming the new file is on disk, so an overwrite interrupted by a crash leaves the previous
+///   content in place,
+/// - every index record carries a checksum, a record torn by a crash is dropped on open,
+/// - on open, files without an index record and records without a file are discarded.
+///
+/// The index is compacted on open and whenever most of its records are stale.
+pub struct FsStorage {
+    dir: PathBuf,
+    /// Maps each stored key to the metadata of its file, except for its size.
+    entries: HashMap<ChordId, Entry>,
+    tombstones: HashMap<ChordId, Tombstone>,
+    index: fs::File,
+    /// Number of records in the index, live or not.
+    index_records: usize,
+}
+
+impl FsStorage {
+    /// Opens the storage in `dir`, creating it if missing, replays the index and reconciles it with the files
+    /// actually stored.
+    ///
+    /// # Returns
+    /// - `Err(io::Error)`: if `dir` cannot be created, or the index cannot be read or written.
+    /// - `Err(io::Error)` of kind `InvalidData`: if a record in the middle of the index is corrupted. The index is
+    ///   left as is, for inspection.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        let index = open_index(&dir)
+            .map_err(|e| io::Error::new(e.kind(), format!("data directory {} is not usable: {e}", dir.display())))?;
+
+        let mut storage = Self {
+            dir,
+            entries: HashMap::new(),
+            tombstones: HashMap::new(),
+            index,
+            index_records: 0,
+        };
+        storage.replay_index()?;
+        let imported = storage.import_legacy_saved_files()?;
+        storage.reconcile()?;
+        storage.checksum_legacy_files(imported)?;
+        storage.compact()?;
+        Ok(storage)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the file holding the content stored under `key`, if any.
+    pub fn file_path(&self, key: &ChordId) -> Option<PathBuf> {
+        let entry = self.entries.get(key)?;
+        Some(self.content_path(key, entry.generation))
+    }
+
+    /// Path of the file holding generation `generation` of the content of `key`. The first generation is named
+    /// after the key alone, like the files of older versions.
+    fn content_path(&self, key: &ChordId, generation: u64) -> PathBuf {
+        match generation {
+            0 => self.dir.join(key.to_hex()),
+            generation => self.dir.join(format!("{}.{generation}", key.to_hex())),
+        }
+    }
+
+    /// Replays the index.
+    fn replay_index(&mut self) -> io::Result<()> {
+        let entries = &mut self.entries;
+        let tombstones = &mut self.tombstones;
+        let mut records = 0;
+        let end = record::read_all(&mut self.index, |offset, payload| {
+            records += 1;
+            let (key, name, version, checksum, expires_at, generation) = match bincode::deserialize(&payload) {
+                Ok(IndexRecord::Put(key, name, version, checksum, expires_at, generation)) => {
+                    (key, name, version, checksum, expires_at, generation)
+                }
+                Ok(IndexRecord::Delete(key)) => {
+                    entries.remove(&key);
+                    return;
+                }
+                Ok(IndexRecord::Tombstone(key, tombstone)) => {
+                    entries.remove(&key);
+                    tombstones.insert(key, tombstone);
+                    return;
+                }
+                Ok(IndexRecord::RemoveTombstone(key)) => {
+                    tombstones.remove(&key);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Skipping an unreadable index record at offset {offset}: {e}");
+                    return;
+                }
+            };
+            let entry = Entry {
+                name,
+                version,
+                checksum,
+                expires_at,
+                generation,
+                size: 0,
+            };
+            tombstones.remove(&key);
+            entries.insert(key, entry);
+        })
+        .map_err(|e| io::Error::new(e.kind(), format!("index of {}: {e}", self.dir.display())))?;
+        self.index_records = records;
+
+        if end < self.index.metadata()?.len() {
+            warn!(
+                "Discarding the incomplete record at the end of the index in {}",
+                self.dir.display()
+            );
+            self.index.set_len(end)?;
+        }
+        Ok(())
+    }
+
+    /// Imports the `saved_files.txt` of older versions. Returns the keys imported.
+    fn import_legacy_saved_files(&mut self) -> io::Result<Vec<ChordId>> {
+        let path = self.dir.join(LEGACY_SAVED_FILES);
+        let mut imported = vec![];
+        if !path.exists() {
+            return Ok(imported);
+        }
+
+        let reader = BufReader::new(fs::File::open(&path)?);
+        for line in reader.lines() {
+            if let Some((key, name)) = line?.split_once(':') {
+                let Some(key) = ChordId::from_hex(key) else {
+                    continue;
+                };
+                if let hash_map::Entry::Vacant(vacant) = self.entries.entry(key) {
+                    vacant.insert(Entry {
+                        name: name.to_string(),
+                        version: Version::UNVERSIONED,
+                        checksum: Checksum::default(),
+                        expires_at: None,
+                        generation: 0,
+                        size: 0,
+                    });
+                    imported.push(key);
+                }
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Computes the checksum of the files imported from `saved_files.txt`, trusting their content.
+    fn checksum_legacy_files(&mut self, keys: impl IntoIterator<Item = ChordId>) -> io::Result<()> {
+        for key in keys {
+            let Some(path) = self.file_path(&key) else {
+                continue;
+            };
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.checksum = checksum(&fs::read(path)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the index entries whose file is missing, and removes the files no index entry points to: they were
+    /// never acknowledged, were replaced, or their removal was interrupted. Reads the size of the files kept.
+    fn reconcile(&mut self) -> io::Result<()> {
+        let paths: HashMap<ChordId, PathBuf> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (*key, self.content_path(key, entry.generation)))
+            .collect();
+        self.entries.retain(|key, entry| match fs::metadata(&paths[key]) {
+            Ok(metadata) if metadata.is_file() => {
+                entry.size = metadata.len();
+                true
+            }
+            _ => {
+                warn!("Dropping {key} ({}) from the index, its file is missing", entry.name);
+                false
+            }
+        });
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let Some(file_name) = path.file_name().and_then(|file_name| file_name.to_str()) else {
+                continue;
+            };
+            let (stem, generation) = match file_name.split_once('.') {
+                Some((stem, generation)) => (stem, generation.parse::<u64>().ok()),
+                None => (file_name, Some(0)),
+            };
+            let orphan = match (ChordId::from_hex(stem), generation) {
+                (Some(key), Some(generation)) => {
+                    self.entries.get(&key).map(|entry| entry.generation) != Some(generation)
+                }
+                _ => path
+                    .extension()
+                    .is_some_and(|extension| extension == TEMPORARY_EXTENSION),
+            };
+            if orphan {
+                trace!("Removing unacknowledged file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the index with a single record per stored key and per tombstone.
+    fn compact(&mut self) -> io::Result<()> {
+        let temporary = self.dir.join(format!("{INDEX}.{TEMPORARY_EXTENSION}"));
+        let mut compacted = fs::File::create(&temporary)?;
+        for (key, entry) in &self.entries {
+            compacted.write_all(&encode(&IndexRecord::Put(
+                *key,
+                entry.name.clone(),
+                entry.version,
+                entry.checksum,
+                entry.expires_at,
+                entry.generation,
+            ))?)?;
+        }
+        for (key, tombstone) in &self.tombstones {
+            compacted.write_all(&encode(&IndexRecord::Tombstone(*key, *tombstone))?)?;
+        }
+        compacted.sync_all()?;
+        fs::rename(&temporary, self.dir.join(INDEX))?;
+
+        let legacy = self.dir.join(LEGACY_SAVED_FILES);
+        if legacy.exists() {
+            fs::remove_file(legacy)?;
+        }
+        record::sync_dir(&self.dir)?;
+
+        self.index = open_index(&self.dir)?;
+        self.index_records = self.live_records();
+        Ok(())
+    }
+
+    fn append(&mut self, operation: &IndexRecord) -> io::Result<()> {
+        let encoded = encode(operation)?;
+        let end = self.index.metadata()?.len();
+        let written = self.index.write_all(&encoded).and_then(|_| self.index.sync_data());
+        if let Err(e) = written {
+            if let Err(truncated) = record::truncate(&mut self.index, end) {
+                error!("ERROR {:?} cutting off a failed append to the index", truncated);
+            }
+            return Err(e);
+        }
+        self.index_records += 1;
+        Ok(())
+    }
+
+    /// Number of records left in the index once compacted.
+    fn live_records(&self) -> usize {
+        self.entries.len() + self.tombstones.len()
+    }
+
+    fn compact_if_stale(&mut self) -> io::Result<()> {
+        if self.index_records > COMPACTION_THRESHOLD && self.index_records > 2 * self.live_records() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for FsStorage {
+    fn put(&mut self, key: ChordId, file: File, version: Version, expires_at: Option<DateTime<Utc>>) -> io::Result<()> {
+        let File { name, buffer } = file;
+        let checksum = checksum(&buffer);
+        let previous = self.entries.get(&key);
+        let generation = previous.map_or(0, |previous| previous.generation + 1);
+
+        // The previous content stays in place until the index names the new one
+        let mut stored = fs::File::create(self.content_path(&key, generation))?;
+        stored.write_all(&buffer)?;
+        stored.sync_all()?;
+        record::sync_dir(&self.dir)?;
+        self.append(&IndexRecord::Put(
+            key,
+            name.clone(),
+            version,
+            checksum,
+            expires_at,
+            generation,
+        ))?;
+
+        let entry = Entry {
+            name,
+            version,
+            checksum,
+            expires_at,
+            generation,
+            size: buffer.len() as u64,
+        };
+        self.tombstones.remove(&key);
+        if let Some(previous) = self.entries.insert(key, entry) {
+            remove_content(&self.content_path(&key, previous.generation))?;
+        }
+        self.compact_if_stale()?;
+        trace!("File stored successfully");
+        Ok(())
+    }
+
+    fn get(&self, key: &ChordId) -> io::Result<Option<File>> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        let buffer = fs::read(self.content_path(key, entry.generation))?;
+        verify(key, &buffer, &entry.checksum)?;
+        Ok(Some(File {
+            name: entry.name.clone(),
+            buffer,
+        }))
+    }
+
+    fn delete(&mut self, key: &ChordId) -> io::Result<bool> {
+        let Some(path) = self.file_path(key) else {
+            return Ok(false);
+        };
+        self.append(&IndexRecord::Delete(*key))?;
+        self.entries.remove(key);
+        remove_content(&path)?;
+        self.compact_if_stale()?;
+        Ok(true)
+    }
+
+    fn delete_with_tombstone(&mut self, key: &ChordId, tombstone: Tombstone) -> io::Result<bool> {
+        let path = self.file_path(key);
+        self.append(&IndexRecord::Tombstone(*key, tombstone))?;
+        self.tombstones.insert(*key, tombstone);
+        self.entries.remove(key);
+        if let Some(path) = &path {
+            remove_content(path)?;
+        }
+        self.compact_if_stale()?;
+        Ok(path.is_some())
+    }
+
+    fn remove_tombstone(&mut self, key: &ChordId) -> io::Result<()> {
+        if self.tombstones.contains_key(key) {
+            self.append(&IndexRecord::RemoveTombstone(*key))?;
+            self.tombstones.remove(key);
+            self.compact_if_stale()?;
+        }
+        Ok(())
+    }
+
+    fn tombstones(&self) -> io::Result<Vec<(ChordId, Tombstone)>> {
+        Ok(self
+            .tombstones
+            .iter()
+            .map(|(key, tombstone)| (*key, *tombstone))
+            .collect())
+    }
+
+    fn quarantine(&mut self, key: &ChordId) -> io::Result<bool> {
+        let Some(path) = self.file_path(key) else {
+            return Ok(false);
+        };
+        let quarantine = self.dir.join(QUARANTINE);
+        fs::create_dir_all(&quarantine)?;
+        match fs::rename(path, quarantine.join(key.to_hex())) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        warn!("Quarantined {key} to {}", quarantine.display());
+        self.delete(key)
+    }
+
+    fn list_range(&self, start: &ChordId, end: &ChordId) -> io::Result<Vec<ChordId>> {
+        Ok(keys_in_range(self.entries.keys(), start, end))
+    }
+
+    fn metadata(&self, key: &ChordId) -> io::Result<Option<Metadata>> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        Ok(Some(Metadata {
+            name: entry.name.clone(),
+            size: entry.size,
+            version: entry.version,
+            checksum: entry.checksum,
+            expires_at: entry.expires_at,
+        }))
+    }
+
+    fn usage(&self) -> io::Result<Usage> {
+        Ok(usage_of(self.entries.values().map(|entry| entry.size)))
+    }
+}
+
+/// Creates `dir` and its index if missing, and opens the index for appending.
+fn open_index(dir: &Path) -> io::Result<fs::File> {
+    fs::create_dir_all(dir)?;
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(dir.join(INDEX))
+}
+
+/// Removes a file holding content no index entry points to any more. A removal interrupted by a crash is finished
+/// when the storage is opened again.
+fn remove_content(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn encode(operation: &IndexRecord) -> io::Result<Vec<u8>> {
+    let payload = bincode::serialize(operation).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(record::encode(&payload))
+}
@@ -0,0 +1,75 @@
+use crate::common::{ChordId, File, Version};
+use crate::storage::{checksum, keys_in_range, usage_of, Metadata, StorageBackend, Tombstone, Usage};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::io;
+
+/// Keeps the files in memory, e.g. for tests or nodes that do not need to survive a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: HashMap<ChordId, (File, Metadata)>,
+    tombstones: HashMap<ChordId, Tombstone>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn put(&mut self, key: ChordId, file: File, version: Version, expires_at: Option<DateTime<Utc>>) -> io::Result<()> {
+        let metadata = Metadata {
+            name: file.name.clone(),
+            size: file.buffer.len() as u64,
+            version,
+            checksum: checksum(&file.buffer),
+            expires_at,
+        };
+        self.files.insert(key, (file, metadata));
+        self.tombstones.remove(&key);
+        Ok(())
+    }
+
+    fn get(&self, key: &ChordId) -> io::Result<Option<File>> {
+        Ok(self.files.get(key).map(|(file, _)| file.clone()))
+    }
+
+    fn get_with_metadata(&self, key: &ChordId) -> io::Result<Option<(File, Metadata)>> {
+        Ok(self.files.get(key).cloned())
+    }
+
+    fn delete(&mut self, key: &ChordId) -> io::Result<bool> {
+        Ok(self.files.remove(key).is_some())
+    }
+
+    fn delete_with_tombstone(&mut self, key: &ChordId, tombstone: Tombstone) -> io::Result<bool> {
+        self.tombstones.insert(*key, tombstone);
+        self.delete(key)
+    }
+
+    fn remove_tombstone(&mut self, key: &ChordId) -> io::Result<()> {
+        self.tombstones.remove(key);
+        Ok(())
+    }
+
+    fn tombstones(&self) -> io::Result<Vec<(ChordId, Tombstone)>> {
+        Ok(self
+            .tombstones
+            .iter()
+            .map(|(key, tombstone)| (*key, *tombstone))
+            .collect())
+    }
+
+    fn list_range(&self, start: &ChordId, end: &ChordId) -> io::Result<Vec<ChordId>> {
+        Ok(keys_in_range(self.files.keys(), start, end))
+    }
+
+    fn metadata(&self, key: &ChordId) -> io::Result<Option<Metadata>> {
+        Ok(self.files.get(key).map(|(_, metadata)| metadata.clone()))
+    }
+
+    fn usage(&self) -> io::Result<Usage> {
+        Ok(usage_of(self.files.values().map(|(_, metadata)| metadata.size)))
+    }
+}
@@ -0,0 +1,106 @@
+use crate::common::{ChordId, Version, ID_BYTES};
+use crate::storage::{Metadata, StorageBackend};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io;
+
+/// Number of leaves of a [`MerkleTree`], a power of two.
+pub(crate) const LEAVES: usize = 64;
+
+/// SHA-256 digest of a stored object or of a node of a [`MerkleTree`].
+pub(crate) type ObjectDigest = [u8; 32];
+
+/// Key of a stored object with its version and its digest, see [`range_entries`].
+pub(crate) type RangeEntry = (ChordId, Version, ObjectDigest);
+
+/// Summary of the objects stored in a key range, compared between replicas to find the keys that differ.
+///
+/// Keys are spread over the leaves by their last byte, which is uniform since keys are digests. A leaf hashes the
+/// keys it covers together with the digest of their object, every other node hashes its two children. The nodes
+/// are kept in heap order: the root first, the leaves last.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MerkleTree {
+    nodes: Vec<ObjectDigest>,
+}
+
+impl MerkleTree {
+    /// Builds the tree of `entries`, which must be sorted by key.
+    pub(crate) fn new(entries: &[RangeEntry]) -> Self {
+        let mut leaves = vec![Sha256::new(); LEAVES];
+        for (key, _, digest) in entries {
+            let leaf = &mut leaves[leaf_of(key)];
+            leaf.update(key.as_bytes());
+            leaf.update(digest);
+        }
+
+        let mut nodes = vec![[0; 32]; LEAVES - 1];
+        nodes.extend(leaves.into_iter().map(|leaf| ObjectDigest::from(leaf.finalize())));
+        for index in (0..LEAVES - 1).rev() {
+            let mut hasher = Sha256::new();
+            hasher.update(nodes[2 * index + 1]);
+            hasher.update(nodes[2 * index + 2]);
+            nodes[index] = hasher.finalize().into();
+        }
+        Self { nodes }
+    }
+
+    pub(crate) fn root(&self) -> ObjectDigest {
+        self.nodes[0]
+    }
+
+    /// Leaves whose hash differs from `other`, found by descending only into the subtrees that differ.
+    pub(crate) fn differing_leaves(&self, other: &MerkleTree) -> Vec<usize> {
+        if other.nodes.len() != self.nodes.len() {
+            return (0..LEAVES).collect();
+        }
+        let mut differing = vec![];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if self.nodes[index] == other.nodes[index] {
+                continue;
+            }
+            if index >= LEAVES - 1 {
+                differing.push(index - (LEAVES - 1));
+            } else {
+                pending.extend([2 * index + 2, 2 * index + 1]);
+            }
+        }
+        differing.sort_unstable();
+        differing
+    }
+}
+
+/// Leaf of a [`MerkleTree`] covering `key`.
+pub(crate) fn leaf_of(key: &ChordId) -> usize {
+    key.as_bytes()[ID_BYTES - 1] as usize % LEAVES
+}
+
+/// Digest of the name, the version and the checksum of the content of a stored object, taken from its metadata
+/// so that no content is read.
+pub(crate) fn object_digest(metadata: &Metadata) -> ObjectDigest {
+    let mut hasher = Sha256::new();
+    hasher.update(metadata.version.counter.to_le_bytes());
+    hasher.update(metadata.version.writer.as_bytes());
+    hasher.update((metadata.name.len() as u64).to_le_bytes());
+    hasher.update(metadata.name.as_bytes());
+    hasher.update(metadata.checksum);
+    hasher.finalize().into()
+}
+
+/// Keys stored in `(start, end]` with the version and the digest of their object, sorted by key. A corrupted object still matches
+/// its checksum here: it shows up as missing once the scrubber quarantined it.
+pub(crate) fn range_entries(
+    storage: &dyn StorageBackend,
+    start: &ChordId,
+    end: &ChordId,
+) -> io::Result<Vec<RangeEntry>> {
+    let mut entries = vec![];
+    for key in storage.list_range(start, end)? {
+        if let Some(metadata) = storage.metadata(&key)? {
+            entries.push((key, metadata.version, object_digest(&metadata)));
+        }
+    }
+    entries.sort_unstable_by_key(|(key, _, _)| *key);
+    Ok(entries)
+}
@@ -0,0 +1,182 @@
+//! Storage of the files a node is responsible for.
+//!
+//! Nodes only talk to their storage through [`StorageBackend`], so the backend can be picked with
+//! `NodeBuilder::storage` without touching the handlers:
+//! - [`FsStorage`]: one file per key plus a crash-safe index of their names, the default.
+//! - [`MemoryStorage`]: everything in memory, lost when the node stops.
+//! - [`LogStorage`]: a single append-only log file.
+
+mod append_log;
+mod fs;
+mod memory;
+pub(crate) mod merkle;
+mod record;
+
+pub use append_log::LogStorage;
+pub use fs::FsStorage;
+pub use memory::MemoryStorage;
+pub(crate) use record::sync_dir;
+
+use crate::common::{ChordId, File, Version, ID_BYTES};
+use chrono::{DateTime, Utc};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+/// SHA-256 of the content of a stored file.
+pub type Checksum = [u8; 32];
+
+pub fn checksum(buffer: &[u8]) -> Checksum {
+    Sha256::digest(buffer).into()
+}
+
+/// Information about a stored file that does not require reading it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// Name the file was put with.
+    pub name: String,
+    /// Size of the content, in bytes.
+    pub size: u64,
+    pub version: Version,
+    /// Checksum of the content when it was stored, checked on every read.
+    pub checksum: Checksum,
+    /// When the file stops being served and gets removed, `None` if it never expires.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Metadata {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Record of a deleted key, kept so that older copies of its file handed over later do not bring it back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Version of the delete, following the one of the deleted file: puts of the key go on counting from it.
+    pub version: Version,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// Space taken by the stored files, reported in `NodeStatus` and checked against the quotas of the node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Total size of the content of the files, in bytes.
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+/// Error of a read whose content no longer matches the checksum it was stored with, carried by an `io::Error` of
+/// kind `InvalidData`, see [`is_corrupted`].
+#[derive(Debug)]
+pub struct Corrupted {
+    pub key: ChordId,
+}
+
+impl Display for Corrupted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "content of {} does not match its checksum", self.key)
+    }
+}
+
+impl Error for Corrupted {}
+
+/// Whether `error` was returned for a corrupted file, which should be quarantined.
+pub fn is_corrupted(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Corrupted>())
+}
+
+/// Key-value store of the files of a node, keyed by their position on the ring.
+pub trait StorageBackend: Send {
+    /// Stores `file` under `key` with `version`, replacing whatever was stored there, tombstone included. The file
+    /// expires at `expires_at`, if any, but stays stored until deleted.
+    fn put(&mut self, key: ChordId, file: File, version: Version, expires_at: Option<DateTime<Utc>>) -> io::Result<()>;
+
+    /// Returns the file stored under `key`, if any.
+    ///
+    /// The content is checked against its checksum, see [`is_corrupted`] for the error returned on a mismatch.
+    fn get(&self, key: &ChordId) -> io::Result<Option<File>>;
+
+    /// Returns the file stored under `key` with its metadata, if any.
+    fn get_with_metadata(&self, key: &ChordId) -> io::Result<Option<(File, Metadata)>> {
+        let Some(metadata) = self.metadata(key)? else {
+            return Ok(None);
+        };
+        Ok(self.get(key)?.map(|file| (file, metadata)))
+    }
+
+    /// Removes the file stored under `key`. Returns false if there was none.
+    fn delete(&mut self, key: &ChordId) -> io::Result<bool>;
+
+    /// Removes the file stored under `key` and records `tombstone` in its place, both at once so that a crash
+    /// cannot lose the tombstone of a removed file. Returns false if there was no file.
+    fn delete_with_tombstone(&mut self, key: &ChordId, tombstone: Tombstone) -> io::Result<bool>;
+
+    /// Forgets the tombstone of `key`, if any.
+    fn remove_tombstone(&mut self, key: &ChordId) -> io::Result<()>;
+
+    /// Every tombstone recorded and neither removed nor replaced by a put since.
+    fn tombstones(&self) -> io::Result<Vec<(ChordId, Tombstone)>>;
+
+    /// Moves the file stored under `key` out of the storage after it failed its checksum, keeping it aside for
+    /// inspection where the backend can. Returns false if there was none.
+    fn quarantine(&mut self, key: &ChordId) -> io::Result<bool> {
+        self.delete(key)
+    }
+
+    /// Stored keys in `(start, end]`, in ring order starting from `start`. When `start == end` every key is listed.
+    fn list_range(&self, start: &ChordId, end: &ChordId) -> io::Result<Vec<ChordId>>;
+
+    fn metadata(&self, key: &ChordId) -> io::Result<Option<Metadata>>;
+
+    /// Space taken by the stored files.
+    fn usage(&self) -> io::Result<Usage> {
+        let mut usage = Usage::default();
+        for key in self.keys()? {
+            if let Some(metadata) = self.metadata(&key)? {
+                usage.bytes += metadata.size;
+                usage.objects += 1;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Every stored key, in ring order.
+    fn keys(&self) -> io::Result<Vec<ChordId>> {
+        let origin = ChordId::from_bytes([0; ID_BYTES]);
+        self.list_range(&origin, &origin)
+    }
+}
+
+fn corrupted(key: ChordId) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Corrupted { key })
+}
+
+/// Checks `buffer`, read back from `key`, against the checksum it was stored with.
+fn verify(key: &ChordId, buffer: &[u8], expected: &Checksum) -> io::Result<()> {
+    if checksum(buffer) != *expected {
+        return Err(corrupted(*key));
+    }
+    Ok(())
+}
+
+/// Adds up the sizes of the stored files.
+fn usage_of(sizes: impl Iterator<Item = u64>) -> Usage {
+    sizes.fold(Usage::default(), |usage, size| Usage {
+        bytes: usage.bytes + size,
+        objects: usage.objects + 1,
+    })
+}
+
+/// Keeps the keys in `(start, end]` and sorts them in ring order from `start`.
+fn keys_in_range<'a>(keys: impl Iterator<Item = &'a ChordId>, start: &ChordId, end: &ChordId) -> Vec<ChordId> {
+    let mut keys: Vec<ChordId> = keys
+        .filter(|key| key.in_half_open_interval(start, end))
+        .copied()
+        .collect();
+    keys.sort_by_key(|key| start.distance(key));
+    keys
+}
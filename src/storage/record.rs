@@ -0,0 +1,136 @@
+//! Framing of the records of the append-only files of the storage backends.
+//!
+//! Every record is `[payload length: u64 LE][length checksum: 4 bytes][checksum: 8 bytes][payload]`, the checksums
+//! being the first bytes of the SHA-256 of the length and of the payload. A record cut short or corrupted by a crash
+//! fails a checksum. Only the last record of a file can be torn by a crash: a bad record whose length is intact and
+//! which is followed by a valid record is corruption, and is reported rather than cut off with everything after it.
+//! The next record is only looked for where the length of the bad one says it starts, never inside its payload,
+//! which may hold any bytes, records included.
+
+use digest::Digest;
+use sha2::Sha256;
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const LENGTH_BYTES: usize = 8;
+const LENGTH_CHECKSUM_BYTES: usize = 4;
+const CHECKSUM_BYTES: usize = 8;
+pub(crate) const HEADER_BYTES: u64 = (LENGTH_BYTES + LENGTH_CHECKSUM_BYTES + CHECKSUM_BYTES) as u64;
+
+fn checksum<const BYTES: usize>(payload: &[u8]) -> [u8; BYTES] {
+    let digest = Sha256::digest(payload);
+    let mut checksum = [0; BYTES];
+    checksum.copy_from_slice(&digest[..BYTES]);
+    checksum
+}
+
+/// Length of the payload of the record starting with `header`, `None` if the header is corrupted.
+fn payload_length(header: &[u8]) -> Option<u64> {
+    let (length, rest) = header.split_at(LENGTH_BYTES);
+    (checksum::<LENGTH_CHECKSUM_BYTES>(length) == rest[..LENGTH_CHECKSUM_BYTES])
+        .then(|| u64::from_le_bytes(length.try_into().unwrap()))
+}
+
+/// Frames `payload` as a record, ready to be appended.
+pub(crate) fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_BYTES as usize + payload.len());
+    let length = (payload.len() as u64).to_le_bytes();
+    record.extend_from_slice(&length);
+    record.extend_from_slice(&checksum::<LENGTH_CHECKSUM_BYTES>(&length));
+    record.extend_from_slice(&checksum::<CHECKSUM_BYTES>(payload));
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Calls `apply` with the offset and the payload of every record of `file`, from the start.
+///
+/// Stops at the first truncated or corrupted record and returns the length of the valid part of the file: what
+/// follows is a record torn by a crash, which the caller can cut off.
+///
+/// # Returns
+/// - `Err(io::Error)` of kind `InvalidData`: if the bad record is followed by a valid one, which cutting it off would
+///   lose.
+pub(crate) fn read_all(file: &mut fs::File, mut apply: impl FnMut(u64, Vec<u8>)) -> io::Result<u64> {
+    let total = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(&mut *file);
+
+    let mut end = 0;
+    // Where the record after the bad one starts, if the length of the bad one can be trusted
+    let mut next = None;
+    while total - end >= HEADER_BYTES {
+        let mut header = [0; HEADER_BYTES as usize];
+        reader.read_exact(&mut header)?;
+        let offset = end + HEADER_BYTES;
+        let Some(length) = payload_length(&header).filter(|length| total - offset >= *length) else {
+            break;
+        };
+
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        if checksum::<CHECKSUM_BYTES>(&payload) != header[LENGTH_BYTES + LENGTH_CHECKSUM_BYTES..] {
+            next = Some(offset + length);
+            break;
+        }
+        apply(offset, payload);
+        end = offset + length;
+    }
+
+    if let Some(next) = next {
+        if valid_record_at(file, next, total)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted record at offset {end} followed by valid records"),
+            ));
+        }
+    }
+    Ok(end)
+}
+
+/// Whether a valid record starts at `start` in `file`, of length `total`.
+fn valid_record_at(file: &mut fs::File, start: u64, total: u64) -> io::Result<bool> {
+    if total - start < HEADER_BYTES {
+        return Ok(false);
+    }
+    let mut header = [0; HEADER_BYTES as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut header)?;
+    let offset = start + HEADER_BYTES;
+    Ok(payload_length(&header)
+        .filter(|length| total - offset >= *length)
+        .is_some_and(|length| read_at(file, offset, length).is_ok()))
+}
+
+/// Cuts `file` back to `end`, dropping what a failed append left after the last whole record.
+pub(crate) fn truncate(file: &mut fs::File, end: u64) -> io::Result<()> {
+    file.set_len(end)?;
+    file.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Reads back the payload of `length` bytes at `offset`, as passed to `read_all`, checking it against its checksum.
+pub(crate) fn read_at(file: &mut fs::File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset - CHECKSUM_BYTES as u64))?;
+    let mut expected = [0; CHECKSUM_BYTES];
+    file.read_exact(&mut expected)?;
+    let mut payload = vec![0; length as usize];
+    file.read_exact(&mut payload)?;
+
+    if checksum::<CHECKSUM_BYTES>(&payload) != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupted record at offset {offset}"),
+        ));
+    }
+    Ok(payload)
+}
+
+/// Makes the creation, renaming and removal of the entries of `dir` durable.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
@@ -1,178 +1,503 @@
-use crate::common::UserMessage::{Get, Put};
-use crate::common::{File, Message, ServerToUserMessage};
-use crate::errors::GetError::{ErrorRetrievingFile, HexConversion, NotFound};
-use crate::errors::PutError::ErrorStoringFile;
-use crate::errors::{GetError, PutError};
-use message_io::network::SendStatus::ResourceNotAvailable;
-use message_io::network::{NetEvent, Transport};
-use message_io::node;
-use message_io::node::{NodeHandler, NodeListener};
-use std::io;
+use crate::async_user::AsyncUser;
+use crate::chunk::{ChunkRef, Manifest, CHUNK_SIZE, PARALLEL_TRANSFERS};
+use crate::client::{
+    delete_failure, delete_outcome, get_failure, get_outcome, put_failure, put_outcome, Client, Failure, Notifier,
+    Request, RetryPolicy,
+};
+use crate::common::UserMessage::{CompareAndPut, Delete, Get, Put};
+use crate::common::{File, ServerToUserMessage, UserMessage, Version};
+use crate::errors::GetError::Corrupted;
+use crate::errors::{DeleteError, GetError, PutError};
+use message_io::node::NodeHandler;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
-use tracing::trace;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, trace, warn};
 
+/// Builder for a [`User`] or an [`AsyncUser`], covering the deadline of the requests, their retries and the bootstrap
+/// servers to fail over to.
+///
+/// Every option has a default, [`User::new`] is a shortcut for `User::builder(ip_addr, port).build()`.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use crate::DHTchord::user::User;
+///
+/// let user = User::builder("127.0.0.1".to_string(), "8700".to_string())
+///     .timeout(Duration::from_secs(2))
+///     .retries(4)
+///     .backoff(Duration::from_millis(100))
+///     .bootstrap_servers(["127.0.0.1:7778", "127.0.0.1:7779"])
+///     .build()
+///     .unwrap();
+///
+/// match user.get("127.0.0.1:7777", "string_key".to_string()) {
+///     Ok(file) => println!("File retrieved successfully: {:?}", file),
+///     Err(err) => println!("Failed to retrieve file: {:?}", err),
+/// }
+/// ```
+pub struct UserBuilder {
+    ip_addr: String,
+    port: String,
+    policy: RetryPolicy,
+}
+
+impl UserBuilder {
+    /// Starts the configuration of a user listening on `ip_addr:port`, `0` picking a free port.
+    pub fn new(ip_addr: String, port: String) -> Self {
+        Self {
+            ip_addr,
+            port,
+            policy: RetryPolicy::default(),
+        }
+    }
+
+    /// How long each attempt of a request waits for its connection and its answer, forwarding through the ring
+    /// included. Defaults to 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.policy.timeout = timeout;
+        self
+    }
+
+    /// Number of times a request left without an answer is sent again, each time to the next server. Defaults to 2.
+    ///
+    /// The retries keep the request id: the node responsible for the key answers a put or a delete it already
+    /// carried out with its first answer, so a retried [`User::compare_and_put`] does not conflict with itself.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.policy.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled for each following one. Defaults to 200 milliseconds.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.policy.backoff = backoff;
+        self
+    }
+
+    /// Servers a request is retried on in turn, after the one given to the operation. Defaults to none: the
+    /// retries go to the same server.
+    pub fn bootstrap_servers(mut self, servers: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.policy.bootstrap_servers = servers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Listens and starts the thread receiving the answers.
+    pub fn build(self) -> io::Result<User> {
+        let client = Client::new(self.ip_addr, self.port, self.policy)?;
+        Ok(User {
+            handler: client.handler.clone(),
+            listening_addr: client.listening_addr,
+            client,
+        })
+    }
+
+    /// Like [`UserBuilder::build`], for tokio applications.
+    pub fn build_async(self) -> io::Result<AsyncUser> {
+        let client = Client::new(self.ip_addr, self.port, self.policy)?;
+        Ok(AsyncUser::from_client(client))
+    }
+}
+
+/// Client of the ring, meant to be kept for the lifetime of the application.
+///
+/// A single thread receives the answers of the nodes and hands each of them to the operation waiting for it, by
+/// request id, and connections to the nodes are reused across operations. The operations take `&self`: they can run
+/// one after the other or concurrently from several threads sharing the user. A server that does not answer in time
+/// fails the operation with a `Timeout` error once the retries configured with [`UserBuilder`] are spent. See
+/// [`AsyncUser`](crate::async_user::AsyncUser) for tokio applications.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::thread;
+/// use crate::DHTchord::user::User;
+///
+/// let user = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+/// let keys = vec!["first_key".to_string(), "second_key".to_string()];
+///
+/// thread::scope(|scope| {
+///     for key in keys {
+///         let user = &user;
+///         scope.spawn(move || match user.get("127.0.0.1:7777", key) {
+///             Ok(file) => println!("File retrieved successfully: {:?}", file),
+///             Err(err) => println!("Failed to retrieve file: {:?}", err),
+///         });
+///     }
+/// });
+/// ```
 pub struct User {
     pub handler: NodeHandler<()>,
-    listener: NodeListener<()>,
     pub listening_addr: SocketAddr,
+    client: Client,
 }
 
 impl User {
+    /// Listens on `ip_addr:port`, `0` picking a free port, and starts the thread receiving the answers. The
+    /// requests use the default deadline and retries, see [`UserBuilder`].
     pub fn new(ip_addr: String, port: String) -> Result<Self, io::Error> {
-        let (handler, listener) = node::split();
-        let (_, listen_socket) = handler.network().listen(Transport::Ws, ip_addr + ":" + &port)?;
-        let listening_addr = listen_socket;
-
-        Ok(Self {
-            handler,
-            listener,
-            listening_addr,
-        })
+        Self::builder(ip_addr, port).build()
+    }
+
+    /// Starts the configuration of a user listening on `ip_addr:port`.
+    pub fn builder(ip_addr: String, port: String) -> UserBuilder {
+        UserBuilder::new(ip_addr, port)
     }
 
-    /// Sends a file to a remote server, handles the server's response, and communicates the result back via a channel.
+    /// Sends a file to a remote server and waits for the server's response.
     ///
     ///
     /// # Parameters
-    /// - `self`: Consumes the instance of the struct to perform the operation. This implies that the instance cannot be reused after this function call.
     /// - `server_address`: A string slice (`&str`) representing the address of the server to which the file will be sent.
-    /// - `sender`: A channel sender (`Sender<Result<String, PutError>>`) used to send the result of the operation back to the caller.
-    ///   - On success, the sender transmits an `Ok(String)` containing the key returned by the server.
-    ///   - On failure, the sender transmits an `Err(PutError)`.
     /// - `file`: A `File` instance representing the file to be sent to the server.
     ///
+    /// # Returns
+    /// - `Ok(String)`: the key returned by the server.
+    /// - `Err(PutError::Timeout)`: if no server answered in time, retries included.
+    /// - `Err(PutError)`: if the server did not store the file.
+    ///
     /// # Example
-    /// ```rust
-    /// use std::sync::mpsc::channel;
+    /// ```rust,no_run
     /// use crate::DHTchord::user::User;
     /// use crate::DHTchord::common::File;
     ///
-    /// let (tx, rx) = oneshot::channel();
     /// let file = File{name: "".to_string(),buffer: vec![]};
-    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap(); // Replace with the actual struct implementing this method.
-    ///
-    /// instance.put("127.0.0.1:7777", file);
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
     ///
-    /// match rx.recv().unwrap() {
+    /// match instance.put("127.0.0.1:7777", file) {
     ///     Ok(key) => println!("File stored successfully, key: {}", key),
     ///     Err(err) => println!("Failed to store file: {:?}", err),
     /// }
     /// ```
+    pub fn put(&self, server_address: &str, file: File) -> Result<String, PutError> {
+        let message = Put(file, None, self.listening_addr);
+        self.send_put(server_address, message).map(|(key, _)| key)
+    }
 
-    pub fn put(self, server_address: &str, file: File) -> Result<String, PutError> {
-        let (endpoint, _) = self.handler.network().connect(Transport::Ws, server_address).unwrap();
-
-        let message = Message::UserMessage(Put(file, self.listening_addr));
-        let serialized = bincode::serialize(&message).unwrap();
-        while self.handler.network().send(endpoint, &serialized) == ResourceNotAvailable {
-            trace!("waiting for server");
-        }
+    /// Like [`User::put`], the file expiring `time_to_live` after the node receives it: gets no longer find it, and
+    /// the nodes storing it remove it at their next sweep. Putting the file again replaces the time-to-live.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use crate::DHTchord::user::User;
+    /// use crate::DHTchord::common::File;
+    ///
+    /// let session = File{name: "session_42".to_string(), buffer: b"user=alice".to_vec()};
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+    ///
+    /// match instance.put_with_ttl("127.0.0.1:7777", session, Duration::from_secs(15 * 60)) {
+    ///     Ok(key) => println!("Session stored for 15 minutes, key: {}", key),
+    ///     Err(err) => println!("Failed to store session: {:?}", err),
+    /// }
+    /// ```
+    pub fn put_with_ttl(&self, server_address: &str, file: File, time_to_live: Duration) -> Result<String, PutError> {
+        let message = Put(file, Some(time_to_live), self.listening_addr);
+        self.send_put(server_address, message).map(|(key, _)| key)
+    }
 
-        let mut response = Err(ErrorStoringFile);
+    /// Stores `file` only if the version currently stored under its key is `expected`, or if nothing is stored
+    /// under it when `expected` is `None`. Returns the key and the new version.
+    ///
+    /// The version to expect is the one returned by [`User::get_versioned`] or by a previous put, so that concurrent
+    /// writers do not overwrite each other's changes without knowing it.
+    /// The new content keeps the expiration, if any, of the file it replaces.
+    ///
+    /// # Returns
+    /// - `Err(PutError::VersionConflict(current))`: if another put changed the file in the meantime, `current` being
+    ///   the version now stored.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use crate::DHTchord::user::User;
+    ///
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+    /// let (mut file, version) = instance.get_versioned("127.0.0.1:7777", "string_key".to_string()).unwrap();
+    /// file.buffer.push(b'!');
+    ///
+    /// match instance.compare_and_put("127.0.0.1:7777", file, Some(version)) {
+    ///     Ok((_, version)) => println!("File updated to version {version}"),
+    ///     Err(err) => println!("Failed to update file: {:?}", err),
+    /// }
+    /// ```
+    pub fn compare_and_put(
+        &self,
+        server_address: &str,
+        file: File,
+        expected: Option<Version>,
+    ) -> Result<(String, Version), PutError> {
+        let message = CompareAndPut(file, expected, self.listening_addr);
+        self.send_put(server_address, message)
+    }
 
-        self.listener.for_each(|event| {
-            if let NetEvent::Message(_, bytes) = event.network() {
-                let server_to_user_message: ServerToUserMessage = bincode::deserialize(bytes).unwrap();
-                match server_to_user_message {
-                    ServerToUserMessage::SavedKey(key) => {
-                        trace!("Ok response from server, stopping myself");
-                        response = Ok(key);
-                        self.handler.stop();
-                    }
-                    ServerToUserMessage::ForwarderTo(_) => {
-                        trace!("forwarder");
-                        //todo extend eventually a timer of the request
-                    }
-                    ServerToUserMessage::InternalServerError => {
-                        trace!("Error returned from serve");
-                        response = Err(ErrorStoringFile);
-                        self.handler.stop();
-                    }
-                    other => panic!("received unexpected message: {:?}", other),
-                }
-            }
-        });
+    /// Stores `buffer` as an immutable, content-addressed blob: its key is the SHA-256 of the content, checked by
+    /// the node on receipt and on every read, and putting the same content again stores nothing new.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use crate::DHTchord::user::User;
+    ///
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+    ///
+    /// match instance.put_blob("127.0.0.1:7777", b"build artifact".to_vec()) {
+    ///     Ok(key) => println!("Blob stored, key: {}", key),
+    ///     Err(err) => println!("Failed to store blob: {:?}", err),
+    /// }
+    /// ```
+    pub fn put_blob(&self, server_address: &str, buffer: Vec<u8>) -> Result<String, PutError> {
+        self.put(server_address, File::blob(buffer))
+    }
 
-        response
+    fn send_put(&self, server_address: &str, message: UserMessage) -> Result<(String, Version), PutError> {
+        self.request(server_address, message, put_failure, put_outcome)
     }
 
-    /// Retrieves a file from a remote server using a key and communicates the result back via a channel.
+    /// Retrieves a file from a remote server using a key.
     ///
     /// This function performs the following operations:
-    /// 1. Establishes a connection with the server using a WebSocket transport.
+    /// 1. Takes an idle WebSocket connection to the server, or establishes a new one.
     /// 2. Sends a request to the server to retrieve the file corresponding to the provided key.
-    /// 3. Listens for events and processes the server's response to determine the outcome (success or failure).
-    /// 4. Sends the result (the retrieved file or an error) back to the caller via the provided `Sender`.
+    /// 3. Waits for the server's response to this request, routed by the listener thread of the user, to determine
+    ///    the outcome (success or failure).
+    /// 4. Sends the request again, to the next bootstrap server if any, when no response came before the deadline.
     ///
     /// # Parameters
-    /// - `self`: Consumes the instance of the struct to perform the operation. This implies that the instance cannot be reused after this function call.
     /// - `server_address`: A string slice (`&str`) representing the address of the server to which the file retrieval request is sent.
-    /// - `sender`: A channel sender (`Sender<Result<File, GetError>>`) used to send the result of the operation back to the caller.
-    ///   - On success, the sender transmits an `Ok(File)` containing the retrieved file.
-    ///   - On failure, the sender transmits an `Err(GetError)`, specifying the type of error encountered.
     /// - `key`: A `String` representing the unique identifier (key) for the file to be retrieved.
     ///
+    /// # Returns
+    /// - `Ok(File)`: the retrieved file.
+    /// - `Err(GetError::Timeout)`: if no server answered in time, retries included.
+    /// - `Err(GetError::UnexpectedAnswer)`: if the server answered with a message that is not an answer to a get.
+    /// - `Err(GetError)`: specifying the type of error encountered.
+    ///
     /// # Panics
-    /// - The function will panic if:
-    ///   - Serialization of the message fails.
-    ///   - An unexpected server message is received.
-    ///   - The `sender.send` call fails.
+    /// - The function will panic if serialization of the message fails.
     ///
     /// # Example
-    /// ```rust
-    /// use std::sync::mpsc::channel;
+    /// ```rust,no_run
     /// use crate::DHTchord::user::User;
     ///
-    /// let (tx, rx) = oneshot::channel();
     /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
     ///
-    /// instance.get("127.0.0.1:7777", "string_key".to_string());
-    /// match rx.recv().unwrap() {
+    /// match instance.get("127.0.0.1:7777", "string_key".to_string()) {
     ///     Ok(file) => println!("File retrieved successfully: {:?}", file),
     ///     Err(err) => println!("Failed to retrieve file: {:?}", err),
     /// }
     /// ```
+    pub fn get(&self, server_address: &str, key: String) -> Result<File, GetError> {
+        self.get_versioned(server_address, key).map(|(file, _)| file)
+    }
 
-    pub fn get(self, server_address: &str, key: String) -> Result<File, GetError> {
-        let (endpoint, _) = self.handler.network().connect(Transport::Ws, server_address).unwrap();
+    /// Like [`User::get`], also returning the version of the file, to pass to [`User::compare_and_put`].
+    pub fn get_versioned(&self, server_address: &str, key: String) -> Result<(File, Version), GetError> {
+        let message = Get(key, self.listening_addr);
+        self.request(server_address, message, get_failure, get_outcome)
+    }
 
-        let message = Message::UserMessage(Get(key, self.listening_addr));
-        let serialized = bincode::serialize(&message).unwrap();
-        while self.handler.network().send(endpoint, &serialized) == ResourceNotAvailable {}
+    /// Deletes the file stored under `key` from the ring.
+    ///
+    /// The request is routed like a get, and the node responsible for the key removes the file and keeps a
+    /// tombstone so that copies handed over later do not bring it back.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use crate::DHTchord::user::User;
+    ///
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+    ///
+    /// match instance.delete("127.0.0.1:7777", "string_key".to_string()) {
+    ///     Ok(()) => println!("File deleted"),
+    ///     Err(err) => println!("Failed to delete file: {:?}", err),
+    /// }
+    /// ```
+    pub fn delete(&self, server_address: &str, key: String) -> Result<(), DeleteError> {
+        let message = Delete(key, self.listening_addr);
+        self.request(server_address, message, delete_failure, delete_outcome)
+    }
 
-        let mut response = Err(ErrorRetrievingFile);
+    /// Stores a value of any size read from `reader` under `name`, without holding it in memory.
+    ///
+    /// The value is split into chunks of [`CHUNK_SIZE`] bytes, each put under its own key by up to
+    /// [`PARALLEL_TRANSFERS`] concurrent requests, and a manifest listing the chunks is put under `name`. Returns the
+    /// key of the manifest, to pass to [`User::get_stream`].
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use crate::DHTchord::user::User;
+    ///
+    /// let video = std::fs::File::open("video.mp4").unwrap();
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+    ///
+    /// match instance.put_stream("127.0.0.1:7777", "video.mp4".to_string(), video) {
+    ///     Ok(key) => println!("Video stored, key: {}", key),
+    ///     Err(err) => println!("Failed to store video: {:?}", err),
+    /// }
+    /// ```
+    pub fn put_stream(&self, server_address: &str, name: String, mut reader: impl Read) -> Result<String, PutError> {
+        let mut manifest = Manifest {
+            size: 0,
+            chunks: vec![],
+        };
 
-        self.listener.for_each(|event| {
-            if let NetEvent::Message(_, bytes) = event.network() {
-                let server_to_user_message: ServerToUserMessage = bincode::deserialize(bytes).unwrap();
-                match server_to_user_message {
-                    ServerToUserMessage::RequestedFile(file) => {
-                        trace!("File received");
-                        response = Ok(file);
-                        self.handler.stop();
-                    }
-                    ServerToUserMessage::ForwarderTo(_) => {
-                        trace!("Forwarded")
-                    }
-                    ServerToUserMessage::FileNotFound(_hex) => {
-                        trace!("Not found");
-                        response = Err(NotFound);
-                        self.handler.stop();
-                    }
-                    ServerToUserMessage::HexConversionNotValid(_) => {
-                        trace!("hex conversion error");
-                        response = Err(HexConversion);
-                        self.handler.stop();
-                    }
-                    ServerToUserMessage::InternalServerError => {
-                        trace!("Internal error while saving file");
-                        response = Err(ErrorRetrievingFile);
-                        self.handler.stop();
-                    }
-                    other => panic!("received unexpected message: {:?}", other),
+        let mut finished = false;
+        while !finished {
+            let mut batch = Vec::with_capacity(PARALLEL_TRANSFERS);
+            while batch.len() < PARALLEL_TRANSFERS && !finished {
+                let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+                reader
+                    .by_ref()
+                    .take(CHUNK_SIZE as u64)
+                    .read_to_end(&mut buffer)
+                    .map_err(|e| PutError::ReadingInput(e.kind()))?;
+                finished = buffer.len() < CHUNK_SIZE;
+                if !buffer.is_empty() {
+                    batch.push(buffer);
                 }
             }
-        });
-        response
+
+            let chunks = thread::scope(|scope| {
+                let transfers: Vec<_> = batch
+                    .into_iter()
+                    .map(|buffer| scope.spawn(|| self.put_chunk(server_address, buffer)))
+                    .collect();
+                transfers
+                    .into_iter()
+                    .map(|transfer| transfer.join().unwrap())
+                    .collect::<Result<Vec<_>, _>>()
+            })?;
+            manifest.size += chunks.iter().map(|chunk| chunk.size).sum::<u64>();
+            manifest.chunks.extend(chunks);
+        }
+
+        trace!("Stored {} chunks, storing the manifest", manifest.chunks.len());
+        let buffer = manifest.encode();
+        self.put(server_address, File { name, buffer })
+    }
+
+    /// Writes the value stored under `key` to `writer`, fetching up to [`PARALLEL_TRANSFERS`] chunks at a time and
+    /// checking each of them against the manifest. Values not stored with [`User::put_stream`] are written as is.
+    /// Returns the number of bytes written.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use crate::DHTchord::user::User;
+    ///
+    /// let video = std::fs::File::create("video.mp4").unwrap();
+    /// let instance = User::new("127.0.0.1".to_string(), "8700".to_string()).unwrap();
+    ///
+    /// match instance.get_stream("127.0.0.1:7777", "string_key".to_string(), video) {
+    ///     Ok(size) => println!("Video retrieved, {size} bytes"),
+    ///     Err(err) => println!("Failed to retrieve video: {:?}", err),
+    /// }
+    /// ```
+    pub fn get_stream(&self, server_address: &str, key: String, mut writer: impl Write) -> Result<u64, GetError> {
+        let file = self.get(server_address, key)?;
+        let write_error = |e: io::Error| GetError::WritingOutput(e.kind());
+
+        let Some(manifest) = Manifest::decode(&file.buffer) else {
+            writer.write_all(&file.buffer).map_err(write_error)?;
+            writer.flush().map_err(write_error)?;
+            return Ok(file.buffer.len() as u64);
+        };
+
+        for batch in manifest.chunks.chunks(PARALLEL_TRANSFERS) {
+            let buffers = thread::scope(|scope| {
+                let transfers: Vec<_> = batch
+                    .iter()
+                    .map(|chunk| scope.spawn(|| self.get_chunk(server_address, chunk)))
+                    .collect();
+                transfers
+                    .into_iter()
+                    .map(|transfer| transfer.join().unwrap())
+                    .collect::<Result<Vec<_>, _>>()
+            })?;
+            for buffer in buffers {
+                writer.write_all(&buffer).map_err(write_error)?;
+            }
+        }
+        writer.flush().map_err(write_error)?;
+        Ok(manifest.size)
+    }
+
+    /// Puts a chunk as a content-addressed blob.
+    fn put_chunk(&self, server_address: &str, buffer: Vec<u8>) -> Result<ChunkRef, PutError> {
+        let chunk = ChunkRef::new(&buffer);
+        self.put_blob(server_address, buffer)?;
+        Ok(chunk)
+    }
+
+    /// Gets a chunk and checks its content.
+    fn get_chunk(&self, server_address: &str, chunk: &ChunkRef) -> Result<Vec<u8>, GetError> {
+        let file = self.get(server_address, chunk.key.clone())?;
+        if !chunk.matches(&file.buffer) {
+            error!("Chunk {} does not match its manifest", chunk.key);
+            return Err(Corrupted);
+        }
+        Ok(file.buffer)
+    }
+
+    /// Sends `message` to `server_address` under a new request id and passes the answers to `outcome` until it
+    /// returns the outcome, skipping the intermediate ones. An attempt left without an outcome at its deadline is
+    /// retried on the next server, the answers of every attempt being accepted. Returns the error `failed` makes of
+    /// the last failure once the attempts are spent.
+    fn request<T, E>(
+        &self,
+        server_address: &str,
+        message: UserMessage,
+        failed: fn(Failure) -> E,
+        mut outcome: impl FnMut(ServerToUserMessage) -> Option<Result<T, E>>,
+    ) -> Result<T, E> {
+        let (answers, receiver) = mpsc::channel();
+        let mut request = self.client.start(message, Notifier::Blocking(answers));
+        let mut failure = Failure::Unreachable;
+        for (attempt, server) in self.client.attempts(server_address) {
+            thread::sleep(self.client.backoff(attempt));
+            let deadline = Instant::now() + self.client.policy.timeout;
+            failure = match send(&mut request, server, deadline) {
+                Ok(()) => loop {
+                    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(answer) => {
+                            if let Some(outcome) = outcome(answer) {
+                                return outcome;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => break Failure::Timeout,
+                        Err(RecvTimeoutError::Disconnected) => break Failure::Unreachable,
+                    }
+                },
+                Err(failure) => failure,
+            };
+            warn!(
+                "Attempt {} of request {} to {server}: {:?}",
+                attempt + 1,
+                request.id,
+                failure
+            );
+            request.abandon();
+        }
+        Err(failed(failure))
+    }
+}
+
+/// Sends `request` to `server` on an idle connection, or on a new one once established before `deadline`.
+fn send(request: &mut Request, server: &str, deadline: Instant) -> Result<(), Failure> {
+    request.target(server);
+    if request.send_on_idle() {
+        return Ok(());
+    }
+    let established = request.connect().map_err(|e| {
+        error!("ERROR {:?} connecting to {server}", e);
+        Failure::Unreachable
+    })?;
+    match established.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(true) => {}
+        Err(oneshot::RecvTimeoutError::Timeout) => return Err(Failure::Timeout),
+        Ok(false) | Err(oneshot::RecvTimeoutError::Disconnected) => return Err(Failure::Unreachable),
     }
+    request.send_on_new().map_err(|e| {
+        error!("ERROR {:?} sending request {}", e, request.id);
+        Failure::Unreachable
+    })
 }